@@ -0,0 +1,438 @@
+//
+// Minimal bencode decoder, for .torrent files
+//
+
+use std::collections::BTreeMap;
+
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// Decoded bencode value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    /// Entry of a dictionary
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// Decode a whole bencoded buffer, trailing data is an error
+pub(crate) fn decode(data: &[u8]) -> Result<Value, String> {
+    let (value, rest) = decode_value(data)?;
+    if !rest.is_empty() {
+        return Err(format!("{} bytes of trailing data", rest.len()));
+    }
+    Ok(value)
+}
+
+/// Hex encoded SHA-1 of the pieces, from the info dictionary of a v1 .torrent file
+pub(crate) fn v1_pieces_hashes(torrent_file: &[u8]) -> Result<Vec<String>, String> {
+    let torrent = decode(torrent_file)?;
+    v1_pieces(torrent.get("info").ok_or("no info dictionary")?)
+}
+
+fn v1_pieces(info: &Value) -> Result<Vec<String>, String> {
+    let pieces = info
+        .get("pieces")
+        .and_then(Value::as_bytes)
+        .ok_or("no v1 pieces in the info dictionary")?;
+    if pieces.len() % 20 != 0 {
+        return Err("pieces are not a list of SHA-1".to_owned());
+    }
+
+    Ok(pieces.chunks(20).map(hex::encode).collect())
+}
+
+/// File of a .torrent file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MetaFile {
+    /// Path, starting with the name of the torrent in multi file torrents, like qBittorrent's
+    pub(crate) path: String,
+    pub(crate) size: u64,
+    /// Root of the SHA-256 merkle tree of the file, in v2 and hybrid torrents
+    pub(crate) pieces_root: Option<[u8; 32]>,
+    /// Padding file (BEP 47), flagged by its `attr` or named like one
+    pub(crate) padding: bool,
+}
+
+/// What a .torrent file says about its data
+#[derive(Debug)]
+pub(crate) struct MetaInfo {
+    /// Hex encoded SHA-1 of the info dictionary, or truncated SHA-256 for pure v2 torrents, like qBittorrent's
+    pub(crate) info_hash: String,
+    pub(crate) piece_size: u64,
+    /// In torrent order
+    pub(crate) files: Vec<MetaFile>,
+    /// Hex encoded SHA-1 of v1 and hybrid torrents, SHA-256 of the piece layers of pure v2 torrents
+    pub(crate) pieces_hashes: Vec<String>,
+}
+
+impl MetaInfo {
+    pub(crate) fn parse(torrent_file: &[u8]) -> Result<Self, String> {
+        let info_bytes = info_bytes(torrent_file)?;
+        let torrent = decode(torrent_file)?;
+        let info = torrent.get("info").ok_or("no info dictionary")?;
+
+        let name = text(info.get("name"), "name")?;
+        let piece_size = int(info.get("piece length"), "piece length")?;
+        if piece_size == 0 {
+            return Err("invalid piece length".to_owned());
+        }
+
+        // v2 file tree, with the same paths as v1 files
+        let mut tree_files = Vec::new();
+        if let Some(tree) = info.get("file tree") {
+            file_tree(tree, &mut Vec::new(), &mut tree_files)?;
+            if let [file] = tree_files.as_mut_slice() {
+                // single file torrent, the file is named after the torrent
+                file.path = name.clone();
+            } else {
+                for file in &mut tree_files {
+                    file.path = format!("{}/{}", name, file.path);
+                }
+            }
+        }
+
+        if info.get("pieces").is_some() {
+            let files = match info.get("files") {
+                Some(Value::List(files)) => files
+                    .iter()
+                    .map(|file| {
+                        let path = match file.get("path") {
+                            Some(Value::List(parts)) => parts
+                                .iter()
+                                .map(|part| text(Some(part), "file path"))
+                                .collect::<Result<Vec<_>, _>>()?,
+                            _ => return Err("invalid file path".to_owned()),
+                        };
+                        let path = format!("{}/{}", name, path.join("/"));
+                        let padding = file
+                            .get("attr")
+                            .and_then(Value::as_bytes)
+                            .is_some_and(|attr| attr.contains(&b'p'))
+                            || crate::is_padding_file(&path);
+                        Ok((path, int(file.get("length"), "file length")?, padding))
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => vec![(name, int(info.get("length"), "length")?, false)],
+            };
+            // hybrid torrents have both, the roots are found by path
+            let files = files
+                .into_iter()
+                .map(|(path, size, padding)| MetaFile {
+                    pieces_root: tree_files
+                        .iter()
+                        .find(|file| file.path == path)
+                        .and_then(|file| file.pieces_root),
+                    path,
+                    size,
+                    padding,
+                })
+                .collect();
+
+            return Ok(MetaInfo {
+                info_hash: hex::encode(Sha1::digest(info_bytes)),
+                piece_size,
+                files,
+                pieces_hashes: v1_pieces(info)?,
+            });
+        }
+
+        if tree_files.is_empty() {
+            return Err("no v1 pieces nor v2 file tree in the info dictionary".to_owned());
+        }
+
+        // pure v2: files start on a piece boundary, the layer of each file has its pieces hashes. Files of a
+        // single piece have no layer, their root is the hash of the piece
+        let layers = torrent.get("piece layers");
+        let mut pieces_hashes = Vec::new();
+        for file in &tree_files {
+            match file.pieces_root {
+                Some(root) if file.size > piece_size => {
+                    let layer = layers
+                        .and_then(|layers| match layers {
+                            Value::Dict(layers) => layers.get(&root[..]),
+                            _ => None,
+                        })
+                        .and_then(Value::as_bytes)
+                        .ok_or_else(|| format!("no piece layer for {}", file.path))?;
+                    if layer.len() % 32 != 0 {
+                        return Err(format!("invalid piece layer for {}", file.path));
+                    }
+                    pieces_hashes.extend(layer.chunks(32).map(hex::encode));
+                }
+                Some(root) => pieces_hashes.push(hex::encode(root)),
+                None => {}
+            }
+        }
+
+        // the alignment of the files is virtual padding, as if the torrent had BEP 47 padding files
+        let last = tree_files.len().saturating_sub(1);
+        let mut files = Vec::with_capacity(tree_files.len());
+        for (idx, file) in tree_files.into_iter().enumerate() {
+            let padding = (piece_size - file.size % piece_size) % piece_size;
+            files.push(file);
+            if padding > 0 && idx < last {
+                files.push(MetaFile {
+                    path: format!("{}/.pad/{}", name, padding),
+                    size: padding,
+                    pieces_root: None,
+                    padding: true,
+                });
+            }
+        }
+
+        Ok(MetaInfo {
+            info_hash: hex::encode(&Sha256::digest(info_bytes)[..20]),
+            piece_size,
+            files,
+            pieces_hashes,
+        })
+    }
+}
+
+fn text(value: Option<&Value>, what: &str) -> Result<String, String> {
+    value
+        .and_then(Value::as_bytes)
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        .ok_or_else(|| format!("invalid {}", what))
+}
+
+fn int(value: Option<&Value>, what: &str) -> Result<u64, String> {
+    match value {
+        Some(&Value::Int(number)) if number >= 0 => Ok(number as u64),
+        _ => Err(format!("invalid {}", what)),
+    }
+}
+
+/// Files of a v2 file tree, in torrent order. Paths are relative to the root of the tree
+fn file_tree(
+    node: &Value,
+    path: &mut Vec<String>,
+    files: &mut Vec<MetaFile>,
+) -> Result<(), String> {
+    let Value::Dict(entries) = node else {
+        return Err("invalid file tree".to_owned());
+    };
+    for (key, value) in entries {
+        if key.is_empty() {
+            // the empty key holds the properties of the file named by the path
+            files.push(MetaFile {
+                path: path.join("/"),
+                size: int(value.get("length"), "file length")?,
+                pieces_root: value
+                    .get("pieces root")
+                    .and_then(Value::as_bytes)
+                    .and_then(|root| root.try_into().ok()),
+                // pure v2 torrents align files without padding files
+                padding: false,
+            });
+        } else {
+            path.push(String::from_utf8_lossy(key).into_owned());
+            file_tree(value, path, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+/// Bencoded info dictionary, as it is in the file: the info hash is computed on these bytes
+fn info_bytes(torrent_file: &[u8]) -> Result<&[u8], String> {
+    let mut rest = torrent_file.strip_prefix(b"d").ok_or("not a dictionary")?;
+    while rest.first() != Some(&b'e') {
+        let (key, value) = decode_bytes(rest)?;
+        let (_, next) = decode_value(value)?;
+        if key == b"info" {
+            return Ok(&value[..value.len() - next.len()]);
+        }
+        rest = next;
+    }
+    Err("no info dictionary".to_owned())
+}
+
+fn decode_value(data: &[u8]) -> Result<(Value, &[u8]), String> {
+    match data.first() {
+        Some(b'i') => {
+            let (number, rest) = split_at_byte(&data[1..], b'e')?;
+            let number = std::str::from_utf8(number)
+                .ok()
+                .and_then(|number| number.parse().ok())
+                .ok_or("invalid integer")?;
+            Ok((Value::Int(number), rest))
+        }
+        Some(b'l') => {
+            let mut list = Vec::new();
+            let mut rest = &data[1..];
+            while rest.first() != Some(&b'e') {
+                let (value, next) = decode_value(rest)?;
+                list.push(value);
+                rest = next;
+            }
+            Ok((Value::List(list), &rest[1..]))
+        }
+        Some(b'd') => {
+            let mut dict = BTreeMap::new();
+            let mut rest = &data[1..];
+            while rest.first() != Some(&b'e') {
+                let (key, next) = decode_bytes(rest)?;
+                let (value, next) = decode_value(next)?;
+                dict.insert(key, value);
+                rest = next;
+            }
+            Ok((Value::Dict(dict), &rest[1..]))
+        }
+        Some(b'0'..=b'9') => {
+            let (bytes, rest) = decode_bytes(data)?;
+            Ok((Value::Bytes(bytes), rest))
+        }
+        Some(c) => Err(format!("unexpected byte {:?}", *c as char)),
+        None => Err("unexpected end of data".to_owned()),
+    }
+}
+
+/// `<length>:<bytes>`
+fn decode_bytes(data: &[u8]) -> Result<(Vec<u8>, &[u8]), String> {
+    let (length, rest) = split_at_byte(data, b':')?;
+    let length: usize = std::str::from_utf8(length)
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .ok_or("invalid string length")?;
+    if rest.len() < length {
+        return Err("unexpected end of data".to_owned());
+    }
+    Ok((rest[..length].to_vec(), &rest[length..]))
+}
+
+fn split_at_byte(data: &[u8], byte: u8) -> Result<(&[u8], &[u8]), String> {
+    let end = data
+        .iter()
+        .position(|&b| b == byte)
+        .ok_or("unexpected end of data")?;
+    Ok((&data[..end], &data[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torrent_like_dict() {
+        let value = decode(b"d4:infod6:lengthi40e4:name1:a6:pieces3:abce3:urll1:xi-1eee").unwrap();
+        let info = value.get("info").unwrap();
+
+        assert_eq!(info.get("length"), Some(&Value::Int(40)));
+        assert_eq!(info.get("pieces").unwrap().as_bytes(), Some(&b"abc"[..]));
+        assert_eq!(
+            value.get("url"),
+            Some(&Value::List(vec![
+                Value::Bytes(b"x".to_vec()),
+                Value::Int(-1)
+            ]))
+        );
+        assert!(decode(b"d4:info").is_err());
+        assert!(decode(b"i1ei2e").is_err());
+    }
+
+    #[test]
+    fn pieces_of_torrent_file() {
+        let mut torrent_file =
+            b"d4:infod6:lengthi40e4:name1:a12:piece lengthi16e6:pieces60:".to_vec();
+        torrent_file.extend([1; 20].iter().chain(&[2; 20]).chain(&[3; 20]));
+        torrent_file.extend(b"ee");
+
+        let hashes = v1_pieces_hashes(&torrent_file).unwrap();
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(hashes[1], "02".repeat(20));
+
+        let meta_info = MetaInfo::parse(&torrent_file).unwrap();
+        assert_eq!(
+            meta_info.files,
+            vec![MetaFile {
+                path: "a".to_owned(),
+                size: 40,
+                pieces_root: None,
+                padding: false,
+            }]
+        );
+        assert_eq!(meta_info.piece_size, 16);
+        assert_eq!(meta_info.pieces_hashes, hashes);
+        assert_eq!(
+            meta_info.info_hash,
+            hex::encode(Sha1::digest(&torrent_file[7..torrent_file.len() - 1]))
+        );
+    }
+
+    #[test]
+    fn v2_file_tree_and_piece_layers() {
+        // "b" spans 2 pieces and has a layer, "a" fits in one piece, whose hash is its root
+        let mut torrent_file = b"d4:infod9:file treed1:ad0:d6:lengthi10e11:pieces root32:".to_vec();
+        torrent_file.extend([1; 32]);
+        torrent_file.extend(b"ee1:bd0:d6:lengthi20e11:pieces root32:");
+        torrent_file.extend([2; 32]);
+        torrent_file
+            .extend(b"eee12:meta versioni2e4:name4:root12:piece lengthi16ee12:piece layersd32:");
+        torrent_file.extend([2; 32]);
+        torrent_file.extend(b"64:");
+        torrent_file.extend([3; 64]);
+        torrent_file.extend(b"ee");
+
+        let meta_info = MetaInfo::parse(&torrent_file).unwrap();
+        let files: Vec<(&str, u64, Option<[u8; 32]>)> = meta_info
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.size, file.pieces_root))
+            .collect();
+        // b starts on a piece boundary
+        assert_eq!(
+            files,
+            [
+                ("root/a", 10, Some([1; 32])),
+                ("root/.pad/6", 6, None),
+                ("root/b", 20, Some([2; 32]))
+            ]
+        );
+        assert!(meta_info.files[1].padding);
+        assert_eq!(
+            meta_info.pieces_hashes,
+            ["01".repeat(32), "03".repeat(32), "03".repeat(32)]
+        );
+        assert_eq!(meta_info.info_hash.len(), 40);
+    }
+
+    #[test]
+    fn padding_files() {
+        let mut torrent_file = b"d4:infod5:filesld6:lengthi20e4:pathl1:aeed4:attr1:p6:lengthi12e\
+              4:pathl4:.pad2:12eed6:lengthi16e4:pathl1:beee4:name4:root12:piece lengthi16e6:pieces60:"
+            .to_vec();
+        torrent_file.extend([1; 60]);
+        torrent_file.extend(b"ee");
+
+        let meta_info = MetaInfo::parse(&torrent_file).unwrap();
+        let files: Vec<(&str, bool)> = meta_info
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.padding))
+            .collect();
+        assert_eq!(
+            files,
+            [("root/a", false), ("root/.pad/12", true), ("root/b", false)]
+        );
+    }
+}
@@ -0,0 +1,636 @@
+//
+// Torrent clients holding the torrents to merge
+//
+
+use async_trait::async_trait;
+use log::warn;
+use qbit_rs::model::{
+    Credential, GetTorrentListArg, PieceState, Priority, Torrent as TorrentInfo, TorrentContent,
+    TorrentProperty,
+};
+use qbit_rs::Qbit;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Torrents that changed since a previous `TorrentClient::sync`
+#[derive(Debug, Clone, Default)]
+pub struct SyncUpdate {
+    /// Id of this update, to give to the next `sync`
+    pub rid: i64,
+    /// `changed` lists all the torrents, those that are not in it were removed
+    pub full_update: bool,
+    /// Torrents added or changed, lowercase hashes
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// Extension qBittorrent appends to incomplete files, when enabled in its preferences
+pub(crate) const INCOMPLETE_FILE_EXT: &str = ".!qB";
+
+/// Settings of a client deciding where the files of a torrent are
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Preferences {
+    pub save_path: Option<String>,
+    /// Whether incomplete torrents are in `temp_path` rather than in their save path
+    pub temp_path_enabled: Option<bool>,
+    pub temp_path: Option<String>,
+    /// Appended to the names of incomplete files, `.!qB` for qBittorrent, `.part` for Transmission
+    pub incomplete_files_ext: Option<String>,
+}
+
+impl From<qbit_rs::model::Preferences> for Preferences {
+    fn from(preferences: qbit_rs::model::Preferences) -> Self {
+        Preferences {
+            save_path: preferences.save_path,
+            temp_path_enabled: preferences.temp_path_enabled,
+            temp_path: preferences.temp_path,
+            incomplete_files_ext: (preferences.incomplete_files_ext == Some(true))
+                .then(|| INCOMPLETE_FILE_EXT.to_owned()),
+        }
+    }
+}
+
+/// Client holding the torrents: where they are, what they have, and how to pause or recheck them
+///
+/// Data uses the types of the qBittorrent API, other clients convert theirs
+#[async_trait]
+pub trait TorrentClient: Send + Sync {
+    /// Version of the client, for logs
+    async fn version(&self) -> Result<String>;
+
+    /// Torrents of `hashes`, or all the torrents
+    async fn torrents(&self, hashes: Option<&[String]>) -> Result<Vec<TorrentInfo>>;
+
+    async fn properties(&self, hash: &str) -> Result<TorrentProperty>;
+
+    async fn contents(&self, hash: &str) -> Result<Vec<TorrentContent>>;
+
+    async fn pieces_states(&self, hash: &str) -> Result<Vec<PieceState>>;
+
+    /// Hashes of the pieces, hex encoded
+    async fn pieces_hashes(&self, hash: &str) -> Result<Vec<String>>;
+
+    /// Settings deciding where the files of `hash` are, e.g. the temp path of incomplete torrents
+    async fn preferences(&self, hash: &str) -> Result<Preferences>;
+
+    async fn pause(&self, hashes: &[String]) -> Result<()>;
+
+    async fn resume(&self, hashes: &[String]) -> Result<()>;
+
+    /// Start checking the data of torrents, without waiting for the end
+    async fn recheck(&self, hashes: &[String]) -> Result<()>;
+
+    async fn set_file_priority(
+        &self,
+        hash: &str,
+        indexes: &[i64],
+        priority: Priority,
+    ) -> Result<()>;
+
+    /// Content of the .torrent file of `hash`, for what the API doesn't tell, e.g. v2 piece layers. None when the
+    /// client can't give it
+    async fn torrent_file(&self, _hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Torrents that changed since the update `rid`, 0 for all of them
+    ///
+    /// The default lists all the torrents every time
+    async fn sync(&self, rid: i64) -> Result<SyncUpdate> {
+        Ok(SyncUpdate {
+            rid: rid + 1,
+            full_update: true,
+            changed: self
+                .torrents(None)
+                .await?
+                .into_iter()
+                .filter_map(|t| Some(t.hash?.to_lowercase()))
+                .collect(),
+            removed: Vec::new(),
+        })
+    }
+}
+
+/// qBittorrent WebUI, through qbit-rs, which lacks the export of .torrent files
+pub struct QBittorrent {
+    api: Qbit,
+    http: reqwest::Client,
+    url: reqwest::Url,
+}
+
+impl QBittorrent {
+    /// `url` of the WebUI, with a trailing slash
+    pub fn new(url: &str, credential: Credential) -> Result<Self> {
+        Ok(QBittorrent {
+            api: Qbit::new(url, credential),
+            http: reqwest::Client::new(),
+            url: reqwest::Url::parse(url)?,
+        })
+    }
+
+    /// Call an endpoint of the API directly, for what qbit-rs doesn't expose
+    async fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<reqwest::Response> {
+        let mut request = self.http.get(self.url.join(path)?).query(query);
+        self.api.login(false).await?;
+        if let Some(cookie) = self.api.get_cookie().await {
+            request = request.header(reqwest::header::COOKIE, cookie);
+        }
+
+        Ok(request.send().await?.error_for_status()?)
+    }
+}
+
+/// `preferences` for the torrent `info` (an entry of torrents/info): incomplete torrents are in its own download
+/// path, set by its category or when it was added, rather than in the global temp path. An empty one means the
+/// torrent doesn't use one. Older versions don't report it, the global temp path applies
+fn with_download_path(preferences: Preferences, info: &serde_json::Value) -> Preferences {
+    match info["download_path"].as_str() {
+        Some("") => Preferences {
+            temp_path_enabled: Some(false),
+            ..preferences
+        },
+        Some(download_path) => Preferences {
+            temp_path_enabled: Some(true),
+            temp_path: Some(download_path.to_owned()),
+            ..preferences
+        },
+        None => preferences,
+    }
+}
+
+/// States of `pieces_num` pieces from a bitfield, the first piece in the most significant bit of the first byte as in
+/// the BitTorrent protocol. Pieces past the end of a shorter bitfield are not downloaded
+pub(crate) fn bitfield_states(bytes: &[u8], pieces_num: usize) -> Vec<PieceState> {
+    (0..pieces_num)
+        .map(|idx| {
+            match bytes
+                .get(idx / 8)
+                .is_some_and(|byte| byte & (0x80 >> (idx % 8)) != 0)
+            {
+                true => PieceState::Downloaded,
+                false => PieceState::NotDownloaded,
+            }
+        })
+        .collect()
+}
+
+/// Make an API call, logging in again and retrying it once if the session expired
+///
+/// qbit-rs already logs in again when a request is refused, this covers sessions that expire while it does so,
+/// e.g. after hours of merging, so that the run doesn't fail at the final recheck
+async fn retry_on_expired_session<T, F, Fut>(api: &Qbit, call: F) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<T, qbit_rs::Error>>,
+{
+    match call().await {
+        Err(qbit_rs::Error::ApiError(qbit_rs::ApiError::NotLoggedIn)) => {
+            warn!("qBittorrent session expired, logging in again");
+            api.login(true).await?;
+            Ok(call().await?)
+        }
+        result => Ok(result?),
+    }
+}
+
+#[async_trait]
+impl TorrentClient for QBittorrent {
+    async fn version(&self) -> Result<String> {
+        retry_on_expired_session(&self.api, || self.api.get_version()).await
+    }
+
+    async fn torrents(&self, hashes: Option<&[String]>) -> Result<Vec<TorrentInfo>> {
+        let arg = match hashes {
+            Some(hashes) => GetTorrentListArg::builder()
+                .hashes(hashes.join("|"))
+                .build(),
+            None => GetTorrentListArg::builder().build(),
+        };
+        retry_on_expired_session(&self.api, || self.api.get_torrent_list(arg.clone())).await
+    }
+
+    async fn properties(&self, hash: &str) -> Result<TorrentProperty> {
+        retry_on_expired_session(&self.api, || self.api.get_torrent_properties(hash)).await
+    }
+
+    async fn contents(&self, hash: &str) -> Result<Vec<TorrentContent>> {
+        retry_on_expired_session(&self.api, || self.api.get_torrent_contents(hash, None)).await
+    }
+
+    async fn pieces_states(&self, hash: &str) -> Result<Vec<PieceState>> {
+        retry_on_expired_session(&self.api, || self.api.get_torrent_pieces_states(hash)).await
+    }
+
+    async fn pieces_hashes(&self, hash: &str) -> Result<Vec<String>> {
+        retry_on_expired_session(&self.api, || self.api.get_torrent_pieces_hashes(hash)).await
+    }
+
+    /// Global preferences, with the download path of the torrent (qBittorrent 4.4 or later) as temp path
+    async fn preferences(&self, hash: &str) -> Result<Preferences> {
+        let preferences =
+            retry_on_expired_session(&self.api, || self.api.get_preferences()).await?;
+        let response = self
+            .get("api/v2/torrents/info", &[("hashes", hash)])
+            .await?;
+        let info: serde_json::Value = response.json().await?;
+        Ok(with_download_path(preferences.into(), &info[0]))
+    }
+
+    async fn pause(&self, hashes: &[String]) -> Result<()> {
+        retry_on_expired_session(&self.api, || self.api.pause_torrents(hashes.to_vec())).await
+    }
+
+    async fn resume(&self, hashes: &[String]) -> Result<()> {
+        retry_on_expired_session(&self.api, || self.api.resume_torrents(hashes.to_vec())).await
+    }
+
+    async fn recheck(&self, hashes: &[String]) -> Result<()> {
+        retry_on_expired_session(&self.api, || self.api.recheck_torrents(hashes.to_vec())).await
+    }
+
+    async fn set_file_priority(
+        &self,
+        hash: &str,
+        indexes: &[i64],
+        priority: Priority,
+    ) -> Result<()> {
+        retry_on_expired_session(&self.api, || {
+            self.api.set_file_priority(hash, indexes.to_vec(), priority)
+        })
+        .await
+    }
+
+    /// Needs qBittorrent 4.5 or later
+    async fn torrent_file(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let response = self
+            .get("api/v2/torrents/export", &[("hash", hash)])
+            .await?;
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+
+    /// Through sync/maindata, which only sends what changed since `rid`
+    async fn sync(&self, rid: i64) -> Result<SyncUpdate> {
+        let data = retry_on_expired_session(&self.api, || self.api.sync(rid)).await?;
+        let lowercase = |hashes: Vec<String>| hashes.iter().map(|h| h.to_lowercase()).collect();
+        Ok(SyncUpdate {
+            rid: data.rid,
+            full_update: data.full_update,
+            changed: lowercase(data.torrents.unwrap_or_default().into_keys().collect()),
+            removed: lowercase(data.torrents_removed.unwrap_or_default()),
+        })
+    }
+}
+
+/// Several clients seen as one, each torrent is handled by the client that has it
+pub struct MultiClient {
+    clients: Vec<Box<dyn TorrentClient>>,
+    /// Index of the client of each torrent that was looked up
+    owners: Mutex<HashMap<String, usize>>,
+}
+
+impl MultiClient {
+    pub fn new(clients: Vec<Box<dyn TorrentClient>>) -> Self {
+        MultiClient {
+            clients,
+            owners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Client that has the torrent `hash`
+    async fn owner(&self, hash: &str) -> Result<&dyn TorrentClient> {
+        if let Some(&idx) = self.owners.lock().unwrap().get(hash) {
+            return Ok(self.clients[idx].as_ref());
+        }
+
+        for (idx, client) in self.clients.iter().enumerate() {
+            if !client.torrents(Some(&[hash.to_owned()])).await?.is_empty() {
+                self.owners.lock().unwrap().insert(hash.to_owned(), idx);
+                return Ok(client.as_ref());
+            }
+        }
+
+        Err(format!("Torrent not found: {}", hash).into())
+    }
+
+    /// `hashes` split by client, for calls on several torrents
+    async fn by_owner(&self, hashes: &[String]) -> Result<Vec<(&dyn TorrentClient, Vec<String>)>> {
+        let mut groups: Vec<(&dyn TorrentClient, Vec<String>)> = Vec::new();
+        for hash in hashes {
+            let owner = self.owner(hash).await?;
+            match groups
+                .iter_mut()
+                .find(|(client, _)| std::ptr::addr_eq(*client, owner))
+            {
+                Some((_, group)) => group.push(hash.clone()),
+                None => groups.push((owner, vec![hash.clone()])),
+            }
+        }
+        Ok(groups)
+    }
+}
+
+#[async_trait]
+impl TorrentClient for MultiClient {
+    async fn version(&self) -> Result<String> {
+        let mut versions = Vec::new();
+        for client in &self.clients {
+            versions.push(client.version().await?);
+        }
+        Ok(versions.join(", "))
+    }
+
+    async fn torrents(&self, hashes: Option<&[String]>) -> Result<Vec<TorrentInfo>> {
+        let mut torrents = Vec::new();
+        for client in &self.clients {
+            torrents.extend(client.torrents(hashes).await?);
+        }
+        Ok(torrents)
+    }
+
+    async fn properties(&self, hash: &str) -> Result<TorrentProperty> {
+        let client = self.owner(hash).await?;
+        client.properties(hash).await
+    }
+
+    async fn contents(&self, hash: &str) -> Result<Vec<TorrentContent>> {
+        let client = self.owner(hash).await?;
+        client.contents(hash).await
+    }
+
+    async fn pieces_states(&self, hash: &str) -> Result<Vec<PieceState>> {
+        let client = self.owner(hash).await?;
+        client.pieces_states(hash).await
+    }
+
+    async fn pieces_hashes(&self, hash: &str) -> Result<Vec<String>> {
+        let client = self.owner(hash).await?;
+        client.pieces_hashes(hash).await
+    }
+
+    async fn preferences(&self, hash: &str) -> Result<Preferences> {
+        let client = self.owner(hash).await?;
+        client.preferences(hash).await
+    }
+
+    async fn pause(&self, hashes: &[String]) -> Result<()> {
+        let groups = self.by_owner(hashes).await?;
+        for (client, hashes) in groups {
+            client.pause(&hashes).await?;
+        }
+        Ok(())
+    }
+
+    async fn resume(&self, hashes: &[String]) -> Result<()> {
+        let groups = self.by_owner(hashes).await?;
+        for (client, hashes) in groups {
+            client.resume(&hashes).await?;
+        }
+        Ok(())
+    }
+
+    async fn recheck(&self, hashes: &[String]) -> Result<()> {
+        let groups = self.by_owner(hashes).await?;
+        for (client, hashes) in groups {
+            client.recheck(&hashes).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_file_priority(
+        &self,
+        hash: &str,
+        indexes: &[i64],
+        priority: Priority,
+    ) -> Result<()> {
+        let client = self.owner(hash).await?;
+        client.set_file_priority(hash, indexes, priority).await
+    }
+
+    async fn torrent_file(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let client = self.owner(hash).await?;
+        client.torrent_file(hash).await
+    }
+}
+
+/// Names of the clients, for `PathMapping::client`
+pub const CLIENT_NAMES: [&str; 4] = ["qbittorrent", "transmission", "deluge", "rtorrent"];
+
+/// A directory as the client sees it, and as this tool sees it, e.g. `/downloads` in a qBittorrent container is
+/// `/mnt/tank/downloads` on the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathMapping {
+    /// Only for the torrents of this client, one of `CLIENT_NAMES`. All clients when not set
+    pub client: Option<String>,
+    pub from: String,
+    pub to: String,
+}
+
+impl std::str::FromStr for PathMapping {
+    type Err = String;
+
+    /// `[CLIENT:]FROM=TO`, e.g. `transmission:/downloads=/mnt/tank/downloads`
+    fn from_str(mapping: &str) -> std::result::Result<Self, String> {
+        let (client, paths) = match mapping.split_once(':') {
+            Some((client, paths)) if CLIENT_NAMES.contains(&client) => {
+                (Some(client.to_owned()), paths)
+            }
+            _ => (None, mapping),
+        };
+        let (from, to) = paths
+            .split_once('=')
+            .ok_or_else(|| format!("Expected [CLIENT:]FROM=TO, got {:?}", mapping))?;
+        if from.is_empty() || to.is_empty() {
+            return Err(format!("Empty path in {:?}", mapping));
+        }
+        let trim = |path: &str| match path.trim_end_matches(['/', '\\']) {
+            // the root
+            "" => path[..1].to_owned(),
+            trimmed => trimmed.to_owned(),
+        };
+
+        Ok(PathMapping {
+            client,
+            from: trim(from),
+            to: trim(to),
+        })
+    }
+}
+
+impl std::fmt::Display for PathMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(client) = &self.client {
+            write!(f, "{}:", client)?;
+        }
+        write!(f, "{}={}", self.from, self.to)
+    }
+}
+
+impl PathMapping {
+    /// `path` moved from `from` to `to`, `None` when it is not in `from`. Whole path components are matched:
+    /// `/downloads` doesn't map `/downloads2`. The rest of the path gets the separators of this system, e.g. a Windows
+    /// client path mapped to a Linux mount
+    pub(crate) fn apply(&self, path: &str) -> Option<String> {
+        // Windows clients use both separators: `D:\\Downloads` maps `D:/Downloads/a`
+        let path = path.replace('\\', "/");
+        let from = self.from.replace('\\', "/");
+        let rest = path.strip_prefix(&from)?;
+        let rest = match rest.strip_prefix('/') {
+            Some(rest) => rest,
+            None if rest.is_empty() || from.ends_with('/') => rest,
+            None => return None,
+        };
+        Some(match rest.is_empty() {
+            true => self.to.clone(),
+            false => format!(
+                "{}{}{}",
+                self.to.trim_end_matches(['/', '\\']),
+                std::path::MAIN_SEPARATOR,
+                rest.replace('/', std::path::MAIN_SEPARATOR_STR)
+            ),
+        })
+    }
+}
+
+/// A client, with the paths it reports rewritten by the `PathMapping`s that apply to it
+pub struct MappedPaths {
+    inner: Box<dyn TorrentClient>,
+    /// Longest prefixes first, so that the most precise mapping wins
+    mappings: Vec<PathMapping>,
+}
+
+impl MappedPaths {
+    /// `inner` is the client `name`, one of `CLIENT_NAMES`
+    pub fn new(inner: Box<dyn TorrentClient>, name: &str, mappings: &[PathMapping]) -> Self {
+        let mut mappings: Vec<PathMapping> = mappings
+            .iter()
+            .filter(|m| m.client.as_deref().is_none_or(|client| client == name))
+            .cloned()
+            .collect();
+        mappings.sort_by_key(|m| std::cmp::Reverse(m.from.len()));
+        MappedPaths { inner, mappings }
+    }
+
+    fn map(&self, path: &mut Option<String>) {
+        if let Some(mapped) = path
+            .as_deref()
+            .and_then(|path| self.mappings.iter().find_map(|m| m.apply(path)))
+        {
+            *path = Some(mapped);
+        }
+    }
+}
+
+#[async_trait]
+impl TorrentClient for MappedPaths {
+    async fn version(&self) -> Result<String> {
+        self.inner.version().await
+    }
+
+    async fn torrents(&self, hashes: Option<&[String]>) -> Result<Vec<TorrentInfo>> {
+        let mut torrents = self.inner.torrents(hashes).await?;
+        for torrent in &mut torrents {
+            self.map(&mut torrent.save_path);
+            self.map(&mut torrent.content_path);
+        }
+        Ok(torrents)
+    }
+
+    async fn properties(&self, hash: &str) -> Result<TorrentProperty> {
+        let mut properties = self.inner.properties(hash).await?;
+        self.map(&mut properties.save_path);
+        Ok(properties)
+    }
+
+    async fn contents(&self, hash: &str) -> Result<Vec<TorrentContent>> {
+        self.inner.contents(hash).await
+    }
+
+    async fn pieces_states(&self, hash: &str) -> Result<Vec<PieceState>> {
+        self.inner.pieces_states(hash).await
+    }
+
+    async fn pieces_hashes(&self, hash: &str) -> Result<Vec<String>> {
+        self.inner.pieces_hashes(hash).await
+    }
+
+    async fn preferences(&self, hash: &str) -> Result<Preferences> {
+        let mut preferences = self.inner.preferences(hash).await?;
+        self.map(&mut preferences.save_path);
+        self.map(&mut preferences.temp_path);
+        Ok(preferences)
+    }
+
+    async fn pause(&self, hashes: &[String]) -> Result<()> {
+        self.inner.pause(hashes).await
+    }
+
+    async fn resume(&self, hashes: &[String]) -> Result<()> {
+        self.inner.resume(hashes).await
+    }
+
+    async fn recheck(&self, hashes: &[String]) -> Result<()> {
+        self.inner.recheck(hashes).await
+    }
+
+    async fn set_file_priority(
+        &self,
+        hash: &str,
+        indexes: &[i64],
+        priority: Priority,
+    ) -> Result<()> {
+        self.inner.set_file_priority(hash, indexes, priority).await
+    }
+
+    async fn torrent_file(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.torrent_file(hash).await
+    }
+
+    async fn sync(&self, rid: i64) -> Result<SyncUpdate> {
+        self.inner.sync(rid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_path_of_the_torrent() {
+        let global = Preferences {
+            temp_path_enabled: Some(true),
+            temp_path: Some("/temp".to_owned()),
+            ..Default::default()
+        };
+
+        let info = serde_json::json!({ "download_path": "/incomplete/tv" });
+        let preferences = with_download_path(global.clone(), &info);
+        assert_eq!(preferences.temp_path.as_deref(), Some("/incomplete/tv"));
+        assert_eq!(preferences.temp_path_enabled, Some(true));
+
+        let info = serde_json::json!({ "download_path": "" });
+        let preferences = with_download_path(global.clone(), &info);
+        assert_eq!(preferences.temp_path_enabled, Some(false));
+
+        // before qBittorrent 4.4
+        let preferences = with_download_path(global, &serde_json::json!({}));
+        assert_eq!(preferences.temp_path.as_deref(), Some("/temp"));
+    }
+
+    #[test]
+    fn states_from_bitfield() {
+        use PieceState::{Downloaded, NotDownloaded};
+        let states = bitfield_states(&[0b1010_0000, 0b0000_0001], 16);
+        let downloaded: Vec<usize> = (0..16).filter(|&idx| states[idx] == Downloaded).collect();
+        assert_eq!(downloaded, [0, 2, 15]);
+        // bits past the last piece are padding
+        assert_eq!(bitfield_states(&[0xff], 3), [Downloaded; 3]);
+        // shorter than the number of pieces
+        assert_eq!(
+            bitfield_states(&[0xff], 10)[7..],
+            [Downloaded, NotDownloaded, NotDownloaded]
+        );
+        assert_eq!(bitfield_states(&[], 2), [NotDownloaded; 2]);
+    }
+}
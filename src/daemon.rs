@@ -0,0 +1,290 @@
+//
+// Watch the torrent client, and merge new torrents from the complete ones
+//
+
+use futures_util::StreamExt;
+use log::{debug, error, info};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+
+use crate::client::SyncUpdate;
+use crate::{
+    check_writable, connect, forget_paused_torrents, is_padding_file, wait_for_check,
+    write_paused_torrents, GroupBy, MergeEngine, Options, Torrent, TorrentClient, WriteBackend,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Torrents of the client, as seen by successive syncs
+#[derive(Debug, Default)]
+struct Watcher {
+    /// Id of the last update, 0 before the first sync
+    rid: i64,
+    known: HashSet<String>,
+    /// Torrents added since the daemon started, that were not looked at yet
+    pending: BTreeSet<String>,
+}
+
+impl Watcher {
+    /// Torrents that are there at the first sync are not new, they are left alone
+    fn update(&mut self, update: SyncUpdate) {
+        let first = self.rid == 0;
+        if update.full_update {
+            let current: HashSet<&String> = update.changed.iter().collect();
+            self.known.retain(|hash| current.contains(hash));
+            self.pending.retain(|hash| current.contains(hash));
+        }
+        for hash in &update.removed {
+            self.known.remove(hash);
+            self.pending.remove(hash);
+        }
+        for hash in update.changed {
+            if self.known.insert(hash.clone()) && !first {
+                info!("New torrent: {}", hash);
+                self.pending.insert(hash);
+            }
+        }
+        self.rid = update.rid;
+    }
+}
+
+/// Sizes of the files that can be matched, see `find_same_size_files`
+fn matchable_sizes<'a>(
+    options: &Options,
+    files: impl IntoIterator<Item = (&'a str, u64)>,
+) -> HashSet<u64> {
+    files
+        .into_iter()
+        .filter(|(name, _)| !is_padding_file(name))
+        .map(|(_, size)| size)
+        .filter(|&size| {
+            options.min_file_size.is_none_or(|min| size >= min)
+                && options.max_file_size.is_none_or(|max| size <= max)
+        })
+        .collect()
+}
+
+/// Watch the client every `poll_interval` seconds, and merge each new incomplete torrent from the complete torrents
+/// that have files of the same size. Runs until Ctrl-C, merges in progress stop after their current piece, and their
+/// torrents are rechecked and resumed
+pub async fn run_daemon(options: &Options) -> Result<()> {
+    let client = connect(options)?;
+    let api = client.as_ref();
+    info!("Client version: {}", api.version().await?);
+
+    let engine = MergeEngine::new(api, options)?;
+    let mut watcher = Watcher::default();
+    // sizes of the files of the complete torrents, their content doesn't change
+    let mut donor_sizes = HashMap::new();
+
+    // merges can take longer than the interval, polls are not made up for
+    let mut ticker = tokio::time::interval(Duration::from_secs(options.poll_interval));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    info!(
+        "Watching for new torrents every {} seconds",
+        options.poll_interval
+    );
+    engine.interrupt_on_ctrl_c();
+    while !engine.is_interrupted() {
+        tokio::select! {
+            _ = ticker.tick() => (),
+            _ = engine.interrupted() => break,
+        }
+        // the client may be restarting, try again at the next poll
+        if let Err(e) = poll(api, &engine, options, &mut watcher, &mut donor_sizes).await {
+            error!("{}", e);
+        }
+    }
+    info!("Interrupted, stopping");
+
+    Ok(())
+}
+
+/// Look at the new torrents, and merge those that have donors, up to `concurrent_merges` at a time
+async fn poll(
+    api: &dyn TorrentClient,
+    engine: &MergeEngine<'_>,
+    options: &Options,
+    watcher: &mut Watcher,
+    donor_sizes: &mut HashMap<String, HashSet<u64>>,
+) -> Result<()> {
+    watcher.update(api.sync(watcher.rid).await?);
+    if watcher.pending.is_empty() {
+        return Ok(());
+    }
+
+    let torrents = api.torrents(None).await?;
+    let hash_of = |t: &qbit_rs::model::Torrent| t.hash.as_deref().map(str::to_lowercase);
+    let complete: Vec<_> = torrents
+        .iter()
+        .filter(|t| t.progress.unwrap_or_default() >= 1.)
+        .collect();
+    for t in &complete {
+        // new torrents that are already complete need nothing, they are donors from now on
+        if let Some(hash) = hash_of(t) {
+            watcher.pending.remove(&hash);
+        }
+    }
+
+    let info = |hash: &String| torrents.iter().find(|t| hash_of(t).as_ref() == Some(hash));
+    let mut merges = Vec::new();
+    for dst_hash in watcher.pending.clone() {
+        if !info(&dst_hash).is_some_and(|t| options.dst_filter.matches(t)) {
+            debug!("{}: not a destination, filtered out", dst_hash);
+            watcher.pending.remove(&dst_hash);
+            continue;
+        }
+
+        // e.g. magnet links without metadata yet, tried again at the next poll
+        let dst = match Torrent::fetch(api, &dst_hash, false).await {
+            Ok(dst) => dst,
+            Err(e) => {
+                debug!("{}: not ready yet: {}", dst_hash, e);
+                continue;
+            }
+        };
+        watcher.pending.remove(&dst_hash);
+
+        let dst_category = info(&dst_hash)
+            .and_then(|t| t.category.clone())
+            .unwrap_or_default();
+        let dst_sizes = matchable_sizes(
+            options,
+            dst.content.iter().map(|f| (f.name.as_str(), f.size)),
+        );
+        let mut donors = Vec::new();
+        for t in &complete {
+            let Some(src_hash) = hash_of(t) else {
+                continue;
+            };
+            if !options.src_filter.matches(t)
+                || options.group_by == Some(GroupBy::Category)
+                    && t.category.clone().unwrap_or_default() != dst_category
+            {
+                continue;
+            }
+            if !donor_sizes.contains_key(&src_hash) {
+                let content = api.contents(&src_hash).await?;
+                let sizes =
+                    matchable_sizes(options, content.iter().map(|f| (f.name.as_str(), f.size)));
+                donor_sizes.insert(src_hash.clone(), sizes);
+            }
+            if !donor_sizes[&src_hash].is_disjoint(&dst_sizes) {
+                donors.push(src_hash);
+            }
+        }
+        if donors.is_empty() {
+            info!(
+                "{}: no complete torrent has files of the same size",
+                dst_hash
+            );
+            continue;
+        }
+
+        // same order as `donor_pairs`
+        donors.sort();
+        #[cfg(feature = "script")]
+        if let Some(script) = &options.script {
+            donors.sort_by_cached_key(|src_hash| script.donor_rank(src_hash, &dst_hash));
+        }
+        merges.push((dst_hash, donors));
+    }
+
+    futures_util::stream::iter(merges)
+        .map(|(dst_hash, donors)| async move {
+            if let Err(e) = merge_new_torrent(api, engine, options, &dst_hash, &donors).await {
+                error!("{}: {}", dst_hash, e);
+            }
+        })
+        .buffer_unordered(options.concurrent_merges)
+        .collect::<Vec<()>>()
+        .await;
+
+    Ok(())
+}
+
+/// Merge the donors into a torrent, paused meanwhile, then recheck it if it got data, and resume it unless `keep_paused`
+async fn merge_new_torrent(
+    api: &dyn TorrentClient,
+    engine: &MergeEngine<'_>,
+    options: &Options,
+    dst_hash: &str,
+    donors: &[String],
+) -> Result<()> {
+    let dst = [dst_hash.to_owned()];
+    if !options.is_read_only() {
+        // the daemon backend can't write yet, fail before pausing anything
+        if options.check_writable || options.write_backend == WriteBackend::Daemon {
+            check_writable(api, engine.store.as_ref(), dst_hash).await?;
+        }
+        write_paused_torrents(&dst)?;
+        api.pause(&dst).await?;
+    }
+
+    let (mut restored_pieces, mut resumed_pieces) = (0, 0);
+    for src_hash in donors {
+        match engine.merge(src_hash, dst_hash).await {
+            Ok(report) => {
+                restored_pieces += report.restored_pieces;
+                resumed_pieces += report.resumed_pieces;
+            }
+            Err(e) => error!("{} -> {}: {}", src_hash, dst_hash, e),
+        }
+    }
+    info!(
+        "{}: {} pieces restored from {} donors",
+        dst_hash,
+        restored_pieces,
+        donors.len()
+    );
+    // rechecked below, or never written
+    engine.forget_restored(dst_hash);
+    if options.is_read_only() {
+        return Ok(());
+    }
+
+    // nobody is there to answer, modified torrents are always rechecked, also those restored before a restart
+    if restored_pieces > 0 || resumed_pieces > 0 {
+        api.recheck(&dst).await?;
+        wait_for_check(api, dst_hash).await?;
+    }
+    if !options.keep_paused {
+        api.resume(&dst).await?;
+    }
+    forget_paused_torrents(&dst)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(rid: i64, full_update: bool, changed: &[&str], removed: &[&str]) -> SyncUpdate {
+        SyncUpdate {
+            rid,
+            full_update,
+            changed: changed.iter().map(|h| h.to_string()).collect(),
+            removed: removed.iter().map(|h| h.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn only_torrents_added_later_are_new() {
+        let mut watcher = Watcher::default();
+        watcher.update(update(1, true, &["a", "b"], &[]));
+        assert!(watcher.pending.is_empty());
+
+        // changes of known torrents are not new torrents
+        watcher.update(update(2, false, &["a", "c", "d"], &[]));
+        assert_eq!(watcher.pending, BTreeSet::from(["c".into(), "d".into()]));
+
+        watcher.update(update(3, false, &[], &["c"]));
+        assert_eq!(watcher.pending, BTreeSet::from(["d".into()]));
+
+        // a full update drops what is not there anymore
+        watcher.update(update(4, true, &["a", "b", "e"], &[]));
+        assert_eq!(watcher.pending, BTreeSet::from(["e".into()]));
+        assert_eq!(watcher.rid, 4);
+    }
+}
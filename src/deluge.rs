@@ -0,0 +1,361 @@
+//
+// Deluge client, through the JSON-RPC of its web UI
+//
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+use qbit_rs::model::{
+    PieceState, Priority, Torrent as TorrentInfo, TorrentContent, TorrentProperty,
+};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::bencode;
+use crate::client::{Preferences, TorrentClient};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Torrents of a deluged daemon, through the JSON-RPC of the web UI
+///
+/// Piece hashes are not in the RPC, they are read from `<hash>.torrent` in the state directory of deluged, which
+/// must be readable locally
+pub struct Deluge {
+    http: reqwest::Client,
+    url: reqwest::Url,
+    state_dir: Option<PathBuf>,
+    request_id: AtomicU64,
+    /// Session cookie, set by `auth.login`
+    cookie: std::sync::Mutex<Option<String>>,
+    /// Logged in, and the web UI is connected to a daemon
+    ready: Mutex<bool>,
+}
+
+impl Deluge {
+    /// `url` of the JSON-RPC, with the web UI password, e.g. `http://:password@seedbox:8112/json`
+    pub fn new(url: &str, state_dir: Option<&str>) -> Result<Self> {
+        Ok(Deluge {
+            http: reqwest::Client::new(),
+            url: reqwest::Url::parse(url)?,
+            state_dir: state_dir.map(PathBuf::from),
+            request_id: AtomicU64::new(0),
+            cookie: std::sync::Mutex::new(None),
+            ready: Mutex::new(false),
+        })
+    }
+
+    async fn raw_call(&self, method: &str, params: Value) -> Result<Value> {
+        let body = json!({
+            "method": method,
+            "params": params,
+            "id": self.request_id.fetch_add(1, Ordering::Relaxed),
+        });
+        let mut request = self.http.post(self.url.clone()).json(&body);
+        if let Some(cookie) = self.cookie.lock().unwrap().as_ref() {
+            request = request.header(reqwest::header::COOKIE, cookie);
+        }
+
+        let response = request.send().await?.error_for_status()?;
+        if let Some(cookie) = response
+            .headers()
+            .get(reqwest::header::SET_COOKIE)
+            .and_then(|cookie| cookie.to_str().ok())
+            .and_then(|cookie| cookie.split(';').next())
+        {
+            *self.cookie.lock().unwrap() = Some(cookie.to_owned());
+        }
+        let response: Value = response.json().await?;
+
+        match &response["error"] {
+            Value::Null => Ok(response["result"].clone()),
+            error => Err(format!("Deluge {} failed: {}", method, error["message"]).into()),
+        }
+    }
+
+    /// Log in, and connect the web UI to the first daemon if it is not connected yet
+    async fn ensure_ready(&self) -> Result<()> {
+        let mut ready = self.ready.lock().await;
+        if *ready {
+            return Ok(());
+        }
+
+        let password = self.url.password().unwrap_or_default();
+        if self.raw_call("auth.login", json!([password])).await? != Value::Bool(true) {
+            return Err("Deluge refused the password".into());
+        }
+        if self.raw_call("web.connected", json!([])).await? != Value::Bool(true) {
+            let hosts = self.raw_call("web.get_hosts", json!([])).await?;
+            let host_id = hosts[0][0]
+                .as_str()
+                .ok_or("The Deluge web UI knows no daemon")?
+                .to_owned();
+            self.raw_call("web.connect", json!([host_id])).await?;
+        }
+
+        *ready = true;
+        Ok(())
+    }
+
+    /// Call a method of the daemon
+    async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        self.ensure_ready().await?;
+        self.raw_call(method, params).await
+    }
+
+    /// `<hash>.torrent` of the state directory, piece hashes are not in the RPC
+    fn read_torrent_file(&self, hash: &str) -> Result<Vec<u8>> {
+        let state_dir = self.state_dir.as_ref().ok_or_else(|| {
+            format!(
+                "{}: Deluge doesn't report piece hashes, its state directory is needed",
+                hash
+            )
+        })?;
+        let path = state_dir.join(format!("{}.torrent", hash));
+        Ok(std::fs::read(&path).map_err(|e| format!("{}: can't read {:?}: {}", hash, path, e))?)
+    }
+
+    /// `keys` of the status of a torrent
+    async fn status(&self, hash: &str, keys: &[&str]) -> Result<Value> {
+        let status = self
+            .call("core.get_torrent_status", json!([hash, keys]))
+            .await?;
+        match status.as_object() {
+            Some(fields) if !fields.is_empty() => Ok(status),
+            _ => Err(format!("Torrent not found: {}", hash).into()),
+        }
+    }
+}
+
+/// qBittorrent state of a torrent, from Deluge's. Only checking states matter to the merge
+fn torrent_state(state: &str, complete: bool) -> &'static str {
+    match (state, complete) {
+        ("Checking", true) => "checkingUP",
+        ("Checking", false) => "checkingDL",
+        ("Paused", true) => "pausedUP",
+        ("Paused", false) => "pausedDL",
+        ("Queued", true) => "queuedUP",
+        ("Queued", false) => "queuedDL",
+        ("Error", _) => "error",
+        ("Allocating", _) => "allocating",
+        ("Moving", _) => "moving",
+        (_, true) => "uploading",
+        (_, false) => "downloading",
+    }
+}
+
+/// Deluge piece states: 0 missing, 1 not downloaded, 2 downloading, 3 completed
+fn piece_state(state: u64) -> PieceState {
+    match state {
+        3 => PieceState::Downloaded,
+        2 => PieceState::Downloading,
+        _ => PieceState::NotDownloaded,
+    }
+}
+
+/// qBittorrent priority of a file, from Deluge's 0 (skip) to 7 (high)
+fn file_priority(priority: u64) -> Priority {
+    match priority {
+        0 => Priority::DoNotDownload,
+        1..=4 => Priority::Normal,
+        _ => Priority::High,
+    }
+}
+
+/// Deluge priority of a file
+fn deluge_priority(priority: Priority) -> u64 {
+    match priority {
+        Priority::DoNotDownload => 0,
+        Priority::High | Priority::Maximal => 7,
+        _ => 4,
+    }
+}
+
+#[async_trait]
+impl TorrentClient for Deluge {
+    async fn version(&self) -> Result<String> {
+        let version = self.call("daemon.info", json!([])).await?;
+        Ok(format!("Deluge {}", version.as_str().unwrap_or_default()))
+    }
+
+    async fn torrents(&self, hashes: Option<&[String]>) -> Result<Vec<TorrentInfo>> {
+        let filter = match hashes {
+            Some(hashes) => json!({ "id": hashes }),
+            None => json!({}),
+        };
+        let torrents = self
+            .call(
+                "core.get_torrents_status",
+                json!([filter, ["name", "total_size", "progress", "state", "label"]]),
+            )
+            .await?;
+
+        torrents
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(hash, torrent)| {
+                let progress = torrent["progress"].as_f64().unwrap_or_default() / 100.;
+                Ok(serde_json::from_value(json!({
+                    "hash": hash,
+                    "name": torrent["name"],
+                    "size": torrent["total_size"],
+                    "progress": progress,
+                    "state": torrent_state(
+                        torrent["state"].as_str().unwrap_or_default(),
+                        progress >= 1.
+                    ),
+                    "category": torrent["label"],
+                }))?)
+            })
+            .collect()
+    }
+
+    async fn properties(&self, hash: &str) -> Result<TorrentProperty> {
+        let torrent = self
+            .status(
+                hash,
+                &[
+                    "download_location",
+                    "piece_length",
+                    "num_pieces",
+                    "total_size",
+                ],
+            )
+            .await?;
+        let pieces_have = self
+            .pieces_states(hash)
+            .await?
+            .iter()
+            .filter(|&state| state == &PieceState::Downloaded)
+            .count();
+
+        Ok(serde_json::from_value(json!({
+            "save_path": torrent["download_location"],
+            "piece_size": torrent["piece_length"],
+            "pieces_num": torrent["num_pieces"],
+            "pieces_have": pieces_have,
+            "total_size": torrent["total_size"],
+        }))?)
+    }
+
+    async fn contents(&self, hash: &str) -> Result<Vec<TorrentContent>> {
+        let torrent = self
+            .status(hash, &["files", "file_progress", "file_priorities"])
+            .await?;
+        let files = torrent["files"].as_array().ok_or("no files")?;
+
+        Ok(files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| TorrentContent {
+                index: index as u64,
+                name: file["path"].as_str().unwrap_or_default().to_owned(),
+                size: file["size"].as_u64().unwrap_or_default(),
+                progress: torrent["file_progress"][index].as_f64().unwrap_or_default(),
+                priority: file_priority(torrent["file_priorities"][index].as_u64().unwrap_or(4)),
+                is_seed: None,
+                piece_range: vec![],
+                availability: 0.,
+            })
+            .collect())
+    }
+
+    async fn pieces_states(&self, hash: &str) -> Result<Vec<PieceState>> {
+        let torrent = self.status(hash, &["pieces", "num_pieces"]).await?;
+        match torrent["pieces"].as_array() {
+            Some(pieces) => Ok(pieces
+                .iter()
+                .map(|state| piece_state(state.as_u64().unwrap_or_default()))
+                .collect()),
+            // not reported once the torrent is complete
+            None => Ok(vec![
+                PieceState::Downloaded;
+                torrent["num_pieces"].as_u64().unwrap_or_default()
+                    as usize
+            ]),
+        }
+    }
+
+    async fn pieces_hashes(&self, hash: &str) -> Result<Vec<String>> {
+        let torrent_file = self.read_torrent_file(hash)?;
+        Ok(bencode::v1_pieces_hashes(&torrent_file)
+            .map_err(|e| format!("{}: invalid .torrent file: {}", hash, e))?)
+    }
+
+    async fn preferences(&self, _hash: &str) -> Result<Preferences> {
+        // the download location of a torrent is where its files are, even when they are moved once completed
+        Ok(Preferences {
+            temp_path_enabled: Some(false),
+            ..Default::default()
+        })
+    }
+
+    async fn pause(&self, hashes: &[String]) -> Result<()> {
+        self.call("core.pause_torrents", json!([hashes]))
+            .await
+            .map(|_| ())
+    }
+
+    async fn resume(&self, hashes: &[String]) -> Result<()> {
+        self.call("core.resume_torrents", json!([hashes]))
+            .await
+            .map(|_| ())
+    }
+
+    async fn recheck(&self, hashes: &[String]) -> Result<()> {
+        self.call("core.force_recheck", json!([hashes]))
+            .await
+            .map(|_| ())
+    }
+
+    async fn set_file_priority(
+        &self,
+        hash: &str,
+        indexes: &[i64],
+        priority: Priority,
+    ) -> Result<()> {
+        // priorities are set for all the files at once
+        let torrent = self.status(hash, &["file_priorities"]).await?;
+        let mut priorities: Vec<u64> = torrent["file_priorities"]
+            .as_array()
+            .ok_or("no file priorities")?
+            .iter()
+            .map(|priority| priority.as_u64().unwrap_or(4))
+            .collect();
+        for &index in indexes {
+            if let Some(file_priority) = priorities.get_mut(index as usize) {
+                *file_priority = deluge_priority(priority);
+            }
+        }
+
+        self.call(
+            "core.set_torrent_options",
+            json!([[hash], { "file_priorities": priorities }]),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn torrent_file(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(Some(self.read_torrent_file(hash)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_and_priorities() {
+        assert_eq!(torrent_state("Checking", false), "checkingDL");
+        assert_eq!(torrent_state("Seeding", true), "uploading");
+        assert_eq!(piece_state(2), PieceState::Downloading);
+        assert_eq!(piece_state(1), PieceState::NotDownloaded);
+        assert_eq!(file_priority(0), Priority::DoNotDownload);
+        assert_eq!(
+            file_priority(deluge_priority(Priority::Maximal)),
+            Priority::High
+        );
+    }
+}
@@ -0,0 +1,9625 @@
+//
+// Merge identical files from different torrents via qBittorrent API
+//
+// The `merge` tool is a thin command line wrapper around `MergeEngine`
+//
+
+use base64::Engine;
+use futures_util::{StreamExt, TryStreamExt};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use itertools::Itertools;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryInto;
+use std::fs::OpenOptions;
+use std::io::{prelude::*, BufWriter, IsTerminal};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{collections::HashMap, fs::File};
+
+use log::{debug, error, info, warn};
+use qbit_rs::model::Torrent as TorrentInfo;
+use qbit_rs::model::{Credential, PieceState};
+use qbit_rs::model::{Priority, State, TorrentContent, TorrentProperty};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+mod bencode;
+mod client;
+mod daemon;
+mod deluge;
+mod rtorrent;
+mod transmission;
+mod xmlrpc;
+
+pub use client::{
+    MappedPaths, MultiClient, PathMapping, Preferences, QBittorrent, SyncUpdate, TorrentClient,
+};
+pub use daemon::run_daemon;
+pub use deluge::Deluge;
+pub use rtorrent::RTorrent;
+pub use transmission::Transmission;
+
+#[allow(dead_code)]
+struct Torrent {
+    hash: String,
+    properties: TorrentProperty,
+    piece_size: u64,
+    content: Vec<TorrentContent>,
+    pieces_states: Vec<PieceState>,
+    pieces_hashes: Vec<PieceHash>,
+    /// Roots of the merkle trees of the files, by name, when the torrent is v2 or hybrid and its .torrent file is known
+    pieces_roots: HashMap<String, [u8; 32]>,
+}
+
+impl Torrent {
+    async fn new(api: &dyn TorrentClient, hash: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::fetch(api, hash, true).await
+    }
+
+    /// Without `with_hashes`, pieces hashes are not fetched, which saves a big request on torrents with many pieces
+    async fn fetch(
+        api: &dyn TorrentClient,
+        hash: &str,
+        with_hashes: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let pieces_states = api.pieces_states(hash).await?;
+        let properties = api.properties(hash).await?;
+        let mut content = api.contents(hash).await?;
+
+        // where the files really are, when the client tells
+        let info = api.torrents(Some(&[hash.to_owned()])).await?;
+        if let Some(content_path) = info.first().and_then(|t| t.content_path.as_deref()) {
+            let preferences = api.preferences(hash).await?;
+            let dir = content_dir(&preferences, &properties, &content);
+            content = with_content_layout(hash, content, dir, content_path);
+        }
+
+        // the .torrent file has what the API doesn't: the piece layers of pure v2 torrents and the roots of files
+        let meta_info = match with_hashes {
+            true => match api.torrent_file(hash).await {
+                Ok(Some(torrent_file)) => bencode::MetaInfo::parse(&torrent_file)
+                    .map_err(|e| warn!("{}: invalid .torrent file: {}", hash, e))
+                    .ok(),
+                Ok(None) => None,
+                Err(e) => {
+                    debug!("{}: no .torrent file: {}", hash, e);
+                    None
+                }
+            },
+            false => None,
+        };
+        let pieces_hashes = match &meta_info {
+            Some(meta_info)
+                if Some(meta_info.pieces_hashes.len() as i64) == properties.pieces_num =>
+            {
+                meta_info.pieces_hashes.clone()
+            }
+            _ if with_hashes => api.pieces_hashes(hash).await?,
+            _ => Vec::new(),
+        };
+        let pieces_hashes = pieces_hashes
+            .iter()
+            .map(|s| PieceHash::from_hex(s))
+            .collect::<Result<_, _>>()?;
+
+        let mut torrent =
+            Torrent::from_parts(hash, properties, content, pieces_states, pieces_hashes)?;
+        if let Some(meta_info) = &meta_info {
+            torrent.content = with_padding_files(torrent.content, &meta_info.files);
+            torrent.pieces_roots = pieces_roots(&torrent.content, &meta_info.files);
+        }
+        if torrent.has_unaligned_files() {
+            return Err(MergeError::UnalignedFiles {
+                hash: hash.to_owned(),
+            }
+            .into());
+        }
+        Ok(torrent)
+    }
+
+    fn from_parts(
+        hash: &str,
+        properties: TorrentProperty,
+        mut content: Vec<TorrentContent>,
+        pieces_states: Vec<PieceState>,
+        pieces_hashes: Vec<PieceHash>,
+    ) -> Result<Self, MergeError> {
+        let piece_size = resolve_piece_size(hash, &properties)?;
+        // offsets are computed by adding up sizes, files must be in torrent order
+        content.sort_by_key(|f| f.index);
+
+        Ok(Torrent {
+            hash: hash.to_owned(),
+            properties,
+            piece_size,
+            content,
+            pieces_states,
+            pieces_hashes,
+            pieces_roots: HashMap::new(),
+        })
+    }
+
+    /// Data of a .torrent file, seen as a torrent that has all its pieces, with the piece hashes of the file
+    ///
+    /// `data_dir` is the save path: the directory the torrent was downloaded to
+    fn from_torrent_file(path: &str, data_dir: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let meta_info = bencode::MetaInfo::parse(&std::fs::read(path)?)
+            .map_err(|e| format!("Invalid .torrent file {:?}: {}", path, e))?;
+        info!("{}: info hash {}", path, meta_info.info_hash);
+
+        let pieces_num = meta_info.pieces_hashes.len();
+        let properties = serde_json::from_value(serde_json::json!({
+            "save_path": data_dir,
+            "piece_size": meta_info.piece_size,
+            "pieces_num": pieces_num,
+            "pieces_have": pieces_num,
+            "total_size": meta_info.files.iter().map(|f| f.size).sum::<u64>(),
+        }))?;
+        let content = meta_info
+            .files
+            .iter()
+            .enumerate()
+            .map(|(index, f)| TorrentContent {
+                index: index as u64,
+                name: f.path.clone(),
+                size: f.size,
+                progress: 1.,
+                priority: Priority::Normal,
+                is_seed: None,
+                piece_range: vec![],
+                availability: 0.,
+            })
+            .collect();
+        let pieces_hashes = meta_info
+            .pieces_hashes
+            .iter()
+            .map(|s| PieceHash::from_hex(s))
+            .collect::<Result<_, _>>()?;
+
+        let mut torrent = Torrent::from_parts(
+            &meta_info.info_hash,
+            properties,
+            content,
+            vec![PieceState::Downloaded; pieces_num],
+            pieces_hashes,
+        )?;
+        torrent.pieces_roots = pieces_roots(&torrent.content, &meta_info.files);
+        Ok(torrent)
+    }
+
+    /// Loose files of a directory, seen as a torrent that has all its pieces, with no piece hash
+    ///
+    /// File names are relative to `dir`, and the directory is the save path
+    fn from_dir(dir: &str, piece_size: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut files = Vec::new();
+        let mut dirs = vec![std::path::PathBuf::from(dir)];
+        while let Some(current) = dirs.pop() {
+            for entry in std::fs::read_dir(&current)? {
+                let path = entry?.path();
+                let metadata = std::fs::metadata(&path)?;
+                if metadata.is_dir() {
+                    dirs.push(path);
+                } else if metadata.is_file() {
+                    // names in torrents are separated by `/`, on every system
+                    let name = path
+                        .strip_prefix(dir)?
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .join("/");
+                    files.push((name, metadata.len()));
+                }
+            }
+        }
+        files.sort();
+
+        let total_size: u64 = files.iter().map(|(_, size)| size).sum();
+        let pieces_num = total_size.div_ceil(piece_size) as usize;
+        let properties = serde_json::from_value(serde_json::json!({
+            "save_path": dir,
+            "piece_size": piece_size,
+            "pieces_num": pieces_num,
+            "pieces_have": pieces_num,
+            "total_size": total_size,
+        }))?;
+        let content = files
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, size))| TorrentContent {
+                index: index as u64,
+                name,
+                size,
+                progress: 1.,
+                priority: Priority::Normal,
+                is_seed: None,
+                piece_range: vec![],
+                availability: 0.,
+            })
+            .collect();
+
+        Ok(Torrent::from_parts(
+            dir,
+            properties,
+            content,
+            vec![PieceState::Downloaded; pieces_num],
+            Vec::new(),
+        )?)
+    }
+
+    /// Pieces overlapping the file, end excluded
+    ///
+    /// The first and last pieces can be shared with the previous and next files
+    fn piece_range_for_file(&self, path: &str) -> Result<Range<usize>, Box<dyn std::error::Error>> {
+        let offset = get_file_offset(&self.content, path)?;
+        let size = self
+            .content
+            .iter()
+            .find(|f| f.name == path)
+            .ok_or_else(|| format!("File not found: {:?}", path))?
+            .size;
+
+        Ok(self.piece_range(offset, size))
+    }
+
+    /// Pieces overlapping `size` bytes at `offset` in the torrent, end excluded. None for 0 bytes
+    fn piece_range(&self, offset: u64, size: u64) -> Range<usize> {
+        let start = (offset / self.piece_size) as usize;
+        if size == 0 {
+            return start..start;
+        }
+        let end = (offset + size).div_ceil(self.piece_size) as usize;
+
+        start..end
+    }
+
+    /// Size of the whole torrent
+    fn total_size(&self) -> u64 {
+        self.content.iter().map(|f| f.size).sum()
+    }
+
+    /// Size of the data starting at `offset` in the torrent, cut at the end of the torrent
+    ///
+    /// The last piece is usually shorter than `piece_size`, its hash is computed on the remaining bytes only
+    fn size_until_end(&self, offset: u64, size: u64) -> u64 {
+        size.min(self.total_size().saturating_sub(offset))
+    }
+
+    /// The pieces are those of files that each start a piece, as in pure v2 torrents, and no padding says so
+    fn has_unaligned_files(&self) -> bool {
+        let pieces_num = self.pieces_states.len() as u64;
+        let aligned_pieces_num: u64 = self
+            .content
+            .iter()
+            .map(|f| f.size.div_ceil(self.piece_size))
+            .sum();
+        self.content.len() > 1
+            && pieces_num == aligned_pieces_num
+            && pieces_num != self.total_size().div_ceil(self.piece_size)
+    }
+
+    /// Padding is part of the data of the pieces: v1 piece hashes cover it, v2 piece hashes only cover the data of
+    /// their file
+    fn hashes_padding(&self) -> bool {
+        !matches!(self.pieces_hashes.first(), Some(PieceHash::V2(_)))
+    }
+
+    /// Size of the data that the hash of piece `idx` is computed for, see `PieceHash::hasher`
+    ///
+    /// The piece size, except for files of v2 torrents that fit in a single piece: their piece hash is the root of
+    /// the file, whose merkle tree only has the blocks of the file
+    fn piece_hash_size(&self, idx: usize) -> u64 {
+        if self.hashes_padding() {
+            return self.piece_size;
+        }
+        let segments: Vec<(String, FileBlock)> = piece_segments(self, idx)
+            .into_iter()
+            .filter(|(name, _)| !is_padding_file(name))
+            .collect();
+        match segments.as_slice() {
+            [(name, file_block)]
+                if file_block.offset == 0
+                    && self
+                        .content
+                        .iter()
+                        .any(|f| &f.name == name && f.size == file_block.size) =>
+            {
+                file_block.size
+            }
+            _ => self.piece_size,
+        }
+    }
+
+    /// Downloaded data of the file is in the file itself
+    ///
+    /// Pieces shared between a wanted file and a file that is not downloaded are kept in qBittorrent's part file
+    /// (`.<hash>.parts`), not in the unwanted file, whose layout is not exposed by the WebUI API
+    fn file_data_is_in_file(&self, path: &str) -> bool {
+        self.content
+            .iter()
+            .find(|f| f.name == path)
+            .is_some_and(|f| f.priority != Priority::DoNotDownload)
+    }
+
+    fn piece_is_downloaded(&self, piece: &TorrentPiece) -> bool {
+        let piece = match self.pieces_states.get(piece.idx) {
+            Some(p) => p,
+            None => {
+                // beyond last piece if alignment between src and dst is different
+                // TODO: proper fix
+                return false;
+            }
+        };
+        matches!(piece, PieceState::Downloaded)
+    }
+}
+
+#[derive(Debug)]
+enum MergeError {
+    /// Piece size is unknown, usually because metadata is not downloaded yet
+    NoPieceSize { hash: String },
+    /// Files start on piece boundaries (pure v2 torrent of several files), without a .torrent file to tell where
+    UnalignedFiles { hash: String },
+}
+
+impl std::fmt::Display for MergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeError::NoPieceSize { hash } => write!(f, "Unknown piece size for {}", hash),
+            MergeError::UnalignedFiles { hash } => write!(
+                f,
+                "{}: each file starts a piece (pure v2 torrent of several files), its .torrent file is needed",
+                hash
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Piece size from the properties, or computed from the total size and number of pieces
+///
+/// Piece sizes are powers of 2, so the smallest one that gives the right number of pieces is the right one
+fn resolve_piece_size(hash: &str, properties: &TorrentProperty) -> Result<u64, MergeError> {
+    if let Some(piece_size) = properties.piece_size.filter(|&size| size > 0) {
+        return Ok(piece_size as u64);
+    }
+
+    let no_piece_size = || MergeError::NoPieceSize {
+        hash: hash.to_owned(),
+    };
+    let total_size = properties
+        .total_size
+        .filter(|&size| size > 0)
+        .ok_or_else(no_piece_size)? as u64;
+    let pieces_num = properties
+        .pieces_num
+        .filter(|&num| num > 0)
+        .ok_or_else(no_piece_size)? as u64;
+
+    let piece_size = total_size.div_ceil(pieces_num).next_power_of_two();
+    if total_size.div_ceil(piece_size) == pieces_num {
+        Ok(piece_size)
+    } else {
+        Err(no_piece_size())
+    }
+}
+
+/// Padding file (BEP 47), aligning the next file on a piece boundary: `.pad/<size>` of libtorrent, or
+/// `_____padding_file_<n>` of older clients. Its data is zeros, and it is never written to disk
+pub(crate) fn is_padding_file(name: &str) -> bool {
+    let mut parts = name.rsplit(['/', '\\']);
+    let file_name = parts.next().unwrap_or_default();
+    file_name.starts_with("_____padding_file") || parts.next() == Some(".pad")
+}
+
+/// Content with the padding files of its .torrent file, which qBittorrent leaves out of the API
+///
+/// Offsets in the torrent count padding. Content that doesn't line up with the files of the .torrent file is kept as is
+fn with_padding_files(
+    content: Vec<TorrentContent>,
+    files: &[bencode::MetaFile],
+) -> Vec<TorrentContent> {
+    if content.len() == files.len() {
+        return content;
+    }
+
+    let mut real_files = content.iter();
+    let mut padded = Vec::with_capacity(files.len());
+    for file in files {
+        if file.padding {
+            padded.push(TorrentContent {
+                // not a file of the client, its index is never sent back
+                index: u64::MAX,
+                name: file.path.clone(),
+                size: file.size,
+                progress: 1.,
+                priority: Priority::Normal,
+                is_seed: None,
+                piece_range: vec![],
+                availability: 0.,
+            });
+            continue;
+        }
+        match real_files.next() {
+            Some(f) if f.size == file.size => padded.push(f.clone()),
+            _ => return content,
+        }
+    }
+    if real_files.next().is_some() {
+        return content;
+    }
+    padded
+}
+
+/// Names of the files of `content` as they are laid out on disk in `dir`, given the `content_path` of the torrent:
+/// its root folder, or its file for torrents of a single file
+///
+/// The root folder can be renamed, or not created ("Don't create subfolder"), without the names of the files
+/// following, e.g. with older versions of qBittorrent
+fn with_content_layout(
+    hash: &str,
+    mut content: Vec<TorrentContent>,
+    dir: &str,
+    content_path: &str,
+) -> Vec<TorrentContent> {
+    let normalize = |path: &str| path.replace('\\', "/").trim_end_matches('/').to_owned();
+    let (dir, content_path) = (normalize(dir), normalize(content_path));
+    let on_disk_root = match content_path.rsplit_once('/') {
+        _ if content_path == dir => None,
+        Some((parent, root)) if parent == dir => {
+            Some(root.trim_end_matches(client::INCOMPLETE_FILE_EXT))
+        }
+        // moved, or not under the path this tool sees
+        _ => return content,
+    };
+
+    let root = match content.as_slice() {
+        [f] if !f.name.contains('/') => Some(f.name.as_str()),
+        _ => content
+            .iter()
+            .map(|f| f.name.split_once('/').map(|(root, _)| root))
+            .all_equal_value()
+            .ok()
+            .flatten(),
+    };
+    let (Some(root), false) = (root, on_disk_root == root) else {
+        return content;
+    };
+    let root = root.to_owned();
+    info!(
+        "{}: content is in {:?}, not {:?}",
+        hash,
+        on_disk_root.unwrap_or("."),
+        root
+    );
+
+    for f in &mut content {
+        let rest = &f.name[root.len()..];
+        f.name = match on_disk_root {
+            Some(on_disk_root) => format!("{}{}", on_disk_root, rest),
+            None => rest.trim_start_matches('/').to_owned(),
+        };
+    }
+    content
+}
+
+/// Roots of the files of `content`, from the files of its .torrent file
+///
+/// Files are matched by position, padding included: names can differ when files were renamed in the client
+fn pieces_roots(
+    content: &[TorrentContent],
+    files: &[bencode::MetaFile],
+) -> HashMap<String, [u8; 32]> {
+    if content.len() != files.len() {
+        return HashMap::new();
+    }
+    content
+        .iter()
+        .zip(files)
+        .filter(|(f, file)| f.size == file.size)
+        .filter_map(|(f, file)| Some((f.name.clone(), file.pieces_root?)))
+        .collect()
+}
+
+/// Size of the blocks hashed into the merkle trees of v2 torrents
+const V2_BLOCK_SIZE: usize = 16 * 1024;
+
+/// Expected hash of a piece: SHA-1 for v1 torrents, root of the SHA-256 merkle tree of the piece for v2 torrents
+///
+/// Hashes of different versions never compare equal, data is always checked with the algorithm of the destination
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum PieceHash {
+    V1([u8; 20]),
+    V2([u8; 32]),
+}
+
+impl PieceHash {
+    /// Piece hash as returned by qBittorrent, the version is given by its length
+    fn from_hex(s: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let bytes = hex::decode(s)?;
+        match bytes.len() {
+            20 => Ok(PieceHash::V1(bytes.try_into().unwrap())),
+            32 => Ok(PieceHash::V2(bytes.try_into().unwrap())),
+            len => Err(format!("Unexpected piece hash length: {} bytes", len).into()),
+        }
+    }
+
+    /// A real piece hash, not a placeholder for unknown metadata
+    fn is_known(&self) -> bool {
+        match self {
+            PieceHash::V1(hash) => hash != &[0; 20],
+            PieceHash::V2(hash) => hash != &[0; 32],
+        }
+    }
+
+    /// Hasher of the same version, for pieces of `piece_size` bytes
+    fn hasher(&self, piece_size: u64) -> PieceHasher {
+        match self {
+            PieceHash::V1(_) => PieceHasher::V1(Sha1::new()),
+            PieceHash::V2(_) => PieceHasher::V2 {
+                leaves_num: (piece_size as usize / V2_BLOCK_SIZE).max(1),
+                leaves: Vec::new(),
+                block: Sha256::new(),
+                block_len: 0,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for PieceHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PieceHash::V1(hash) => write!(f, "{}", hex::encode(hash)),
+            PieceHash::V2(hash) => write!(f, "{}", hex::encode(hash)),
+        }
+    }
+}
+
+/// Hash of piece data, fed in any number of parts
+enum PieceHasher {
+    V1(Sha1),
+    /// SHA-256 of each 16 KiB block, and merkle tree of the block hashes, padded with zeros up to the piece size
+    V2 {
+        leaves_num: usize,
+        leaves: Vec<[u8; 32]>,
+        block: Sha256,
+        block_len: usize,
+    },
+}
+
+impl PieceHasher {
+    fn update(&mut self, mut data: &[u8]) {
+        match self {
+            PieceHasher::V1(hasher) => hasher.update(data),
+            PieceHasher::V2 {
+                leaves,
+                block,
+                block_len,
+                ..
+            } => {
+                while !data.is_empty() {
+                    let len = data.len().min(V2_BLOCK_SIZE - *block_len);
+                    block.update(&data[..len]);
+                    *block_len += len;
+                    data = &data[len..];
+                    if *block_len == V2_BLOCK_SIZE {
+                        leaves.push(std::mem::take(block).finalize().into());
+                        *block_len = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    fn finalize(self) -> PieceHash {
+        match self {
+            PieceHasher::V1(hasher) => PieceHash::V1(hasher.finalize().into()),
+            PieceHasher::V2 {
+                leaves_num,
+                mut leaves,
+                block,
+                block_len,
+            } => {
+                if block_len > 0 {
+                    leaves.push(block.finalize().into());
+                }
+                leaves.resize(leaves_num.max(leaves.len()).next_power_of_two(), [0; 32]);
+                while leaves.len() > 1 {
+                    leaves = leaves
+                        .chunks(2)
+                        .map(|pair| {
+                            let mut hasher = Sha256::new();
+                            hasher.update(pair[0]);
+                            hasher.update(pair[1]);
+                            hasher.finalize().into()
+                        })
+                        .collect();
+                }
+                PieceHash::V2(leaves[0])
+            }
+        }
+    }
+
+    fn digest(mut self, data: &[u8]) -> PieceHash {
+        self.update(data);
+        self.finalize()
+    }
+}
+
+/// A chunk of a file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct FileBlock {
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct TorrentPiece {
+    idx: usize,
+    piece_size: u64,
+}
+
+/// Parts of a piece, one per file it overlaps, in torrent order
+///
+/// Empty files are skipped, and the last piece of the torrent is cut to its real size
+fn piece_segments(torrent: &Torrent, idx: usize) -> Vec<(String, FileBlock)> {
+    let start = idx as u64 * torrent.piece_size;
+    let end = start + torrent.size_until_end(start, torrent.piece_size);
+
+    let mut segments = Vec::new();
+    let mut file_start = 0;
+    for f in &torrent.content {
+        let file_end = file_start + f.size;
+        if torrent.piece_range(file_start, f.size).contains(&idx) {
+            let offset = start.max(file_start);
+            segments.push((
+                f.name.clone(),
+                FileBlock {
+                    offset: offset - file_start,
+                    size: end.min(file_end) - offset,
+                },
+            ));
+        }
+        file_start = file_end;
+    }
+
+    segments
+}
+
+fn file_block_to_pieces(
+    torrent: &Torrent,
+    path: &str,
+    file_block: &FileBlock,
+) -> Result<Vec<TorrentPiece>, Box<dyn std::error::Error>> {
+    let piece_size = torrent.piece_size;
+    let file = torrent
+        .content
+        .iter()
+        .find(|f| f.name == path)
+        .ok_or_else(|| format!("File not found {:?}", path))?;
+    if file_block.offset > file.size {
+        return Err(format!("Offset beyond file {} {}", file_block.offset, path).into());
+    }
+
+    // a block can't overlap the pieces of the next file, e.g. at the end of a file on a piece boundary
+    let file_range = torrent.piece_range_for_file(path)?;
+    let offset = get_file_offset(&torrent.content, path)? + file_block.offset;
+    let block_range = torrent.piece_range(offset, file_block.size);
+
+    let result: Vec<TorrentPiece> = (block_range.start.max(file_range.start)
+        ..block_range.end.min(file_range.end))
+        .map(|idx| TorrentPiece { idx, piece_size })
+        .collect();
+
+    Ok(result)
+}
+
+fn convert_filename(
+    same_files: &[FileMatch],
+    filename: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    counterpart_filenames(same_files, filename)
+        .map(|list| list[0].clone())
+        .ok_or_else(|| format!("File not found {:?}", filename).into())
+}
+
+/// All the files matched with `filename` in the other torrent, the first one is the one `convert_filename` gives
+fn counterpart_filenames<'a>(same_files: &'a [FileMatch], filename: &str) -> Option<&'a [String]> {
+    for FileMatch {
+        src_files: list_a,
+        dst_files: list_b,
+        ..
+    } in same_files
+    {
+        if list_a.iter().any(|name| name == filename) {
+            return Some(list_b);
+        }
+        if list_b.iter().any(|name| name == filename) {
+            return Some(list_a);
+        }
+    }
+
+    None
+}
+
+/// Location of a torrent file on disk, see `resolve_file_path`
+fn get_file_path(
+    preferences: &Preferences,
+    torrent_property: &TorrentProperty,
+    content: &[TorrentContent],
+    path: &str,
+) -> String {
+    resolve_file_path(preferences, torrent_property, content, path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Follow symlinks in a local path. Paths that don't exist are kept as is
+fn resolve_symlinks(path: String) -> String {
+    match std::fs::canonicalize(&path) {
+        Ok(resolved) => strip_verbatim_prefix(&resolved.to_string_lossy()),
+        Err(_) => path,
+    }
+}
+
+/// Usual form of a Windows verbatim path, as given by `canonicalize`: `\\?\D:\a` is `D:\a`, and
+/// `\\?\UNC\nas\share` is `\\nas\share`. Verbatim paths don't accept `/` separators, and don't compare equal to
+/// the paths given by the clients
+fn strip_verbatim_prefix(path: &str) -> String {
+    if let Some(unc) = path.strip_prefix(r"\\?\UNC\") {
+        return format!(r"\\{}", unc);
+    }
+    match path.strip_prefix(r"\\?\") {
+        Some(local) if local.as_bytes().get(1) == Some(&b':') => local.to_owned(),
+        _ => path.to_owned(),
+    }
+}
+
+/// Both paths are the same local file, through symlinks or hardlinks
+fn is_same_file(a: &str, b: &str) -> bool {
+    if resolve_symlinks(a.to_owned()) == resolve_symlinks(b.to_owned()) {
+        return true;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if let (Ok(a), Ok(b)) = (std::fs::metadata(a), std::fs::metadata(b)) {
+            return a.dev() == b.dev() && a.ino() == b.ino();
+        }
+    }
+
+    false
+}
+
+#[cfg(feature = "compressed")]
+fn get_read_file(path: &str) -> std::io::Result<std::io::BufReader<File>> {
+    let f = OpenOptions::new().read(true).open(path)?;
+    Ok(std::io::BufReader::new(f))
+}
+
+/// Open a destination file for writing. It is never truncated, and only created with `create`
+fn get_write_file(path: &str, create: bool) -> std::io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(create)
+        .truncate(false)
+        .open(path)
+}
+
+/// Create a local destination file of `size` bytes, and its directory. It is sparse, unless `preallocate` allocates
+/// its blocks. Existing files are left alone
+fn create_sized_file(path: &str, size: u64, preallocate: bool) -> std::io::Result<()> {
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let f = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(()),
+        f => f?,
+    };
+    f.set_len(size)?;
+    if preallocate {
+        preallocate_file(&f, size)?;
+    }
+    Ok(())
+}
+
+/// Allocate the blocks of a file, so that writes don't fail on a full disk and the file is not fragmented
+#[cfg(target_os = "linux")]
+fn preallocate_file(f: &File, size: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is open for as long as `f`
+    match unsafe { libc::posix_fallocate(f.as_raw_fd(), 0, size as libc::off_t) } {
+        0 => Ok(()),
+        e => Err(std::io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// Allocate the blocks of a file by writing zeros, there is no portable call
+#[cfg(not(target_os = "linux"))]
+fn preallocate_file(f: &File, size: u64) -> std::io::Result<()> {
+    let zeros = vec![0; CHUNK_SIZE.min(size) as usize];
+    for offset in (0..size).step_by(zeros.len().max(1)) {
+        let size = zeros.len().min((size - offset) as usize);
+        write_piece(
+            f,
+            FileBlock {
+                offset,
+                size: size as u64,
+            },
+            &zeros[..size],
+        )?;
+    }
+    Ok(())
+}
+
+/// Write a block at its offset. The file has no cursor to move, a handle can be shared by several threads
+fn write_piece(f: &File, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+    #[cfg(unix)]
+    std::os::unix::fs::FileExt::write_all_at(f, data, file_block.offset)?;
+    #[cfg(not(unix))]
+    {
+        let mut written = 0;
+        while written < data.len() {
+            let offset = file_block.offset + written as u64;
+            match std::os::windows::fs::FileExt::seek_write(f, &data[written..], offset)? {
+                0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                n => written += n,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a block at its offset, like `write_piece`
+fn read_piece(f: &File, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0; file_block.size as usize];
+    #[cfg(unix)]
+    std::os::unix::fs::FileExt::read_exact_at(f, &mut buf, file_block.offset)?;
+    #[cfg(not(unix))]
+    {
+        let mut read = 0;
+        while read < buf.len() {
+            let offset = file_block.offset + read as u64;
+            match std::os::windows::fs::FileExt::seek_read(f, &mut buf[read..], offset)? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+    }
+    Ok(buf)
+}
+
+/// Files opened by previous reads and writes, least recently used first, so that files are not opened again for
+/// every piece
+struct FileHandles {
+    capacity: usize,
+    /// Path, opened for writing, handle
+    files: std::sync::Mutex<Vec<(String, bool, Arc<File>)>>,
+}
+
+/// Handles kept open by `LocalStore`, at most
+const CACHED_FILE_HANDLES: usize = 16;
+
+impl Default for FileHandles {
+    fn default() -> Self {
+        FileHandles::new(CACHED_FILE_HANDLES)
+    }
+}
+
+impl FileHandles {
+    fn new(capacity: usize) -> Self {
+        FileHandles {
+            capacity,
+            files: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Handle of `path` for reading, or for writing (created with `create`), opened when it is not cached yet
+    fn get(&self, path: &str, write: bool, create: bool) -> std::io::Result<Arc<File>> {
+        let cached = |files: &mut Vec<(String, bool, Arc<File>)>| {
+            let i = files
+                .iter()
+                .position(|(p, w, _)| p == path && *w == write)?;
+            let entry = files.remove(i);
+            let f = entry.2.clone();
+            files.push(entry);
+            Some(f)
+        };
+        if let Some(f) = cached(&mut self.files.lock().unwrap()) {
+            return Ok(f);
+        }
+
+        // slow opens, e.g. on network filesystems, don't hold the other threads
+        let f = Arc::new(match write {
+            true => get_write_file(path, create)?,
+            false => OpenOptions::new().read(true).open(path)?,
+        });
+        let mut files = self.files.lock().unwrap();
+        if let Some(f) = cached(&mut files) {
+            return Ok(f);
+        }
+        if self.capacity > 0 {
+            if files.len() == self.capacity {
+                files.remove(0);
+            }
+            files.push((path.to_owned(), write, f.clone()));
+        }
+        Ok(f)
+    }
+}
+
+/// Map a file in memory, read-only
+fn map_file(f: &File) -> std::io::Result<memmap2::Mmap> {
+    // SAFETY: the mapping is only read. Data written to the file meanwhile at worst fails the hash check, a file
+    // truncated meanwhile is the caller's problem, as documented for --mmap
+    unsafe { memmap2::Mmap::map(f) }
+}
+
+/// Block of a mapped file, fails like `read_piece` when it goes past the end of the file
+fn mapped_block(map: &memmap2::Mmap, file_block: FileBlock) -> std::io::Result<&[u8]> {
+    let start = file_block.offset as usize;
+    map.get(start..start + file_block.size as usize)
+        .ok_or_else(|| std::io::ErrorKind::UnexpectedEof.into())
+}
+
+/// Where torrent data is read from and written to
+trait PieceStore: Send + Sync {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>>;
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()>;
+    /// Fails if the file exists but can't be written
+    fn check_writable(&self, path: &str) -> std::io::Result<()>;
+    /// Fails if the data of the file doesn't have the expected size. Only checked for data that is not read directly
+    fn check_size(&self, _path: &str, _size: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+    /// Create the destination file `path` with its `size` when it doesn't exist, for stores that create missing files
+    fn create_file(&self, _path: &str, _size: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+    /// Feed a block to `hasher` from where it is stored, without copying it. `None` when the store can't, the block
+    /// must be read
+    fn hash_in_place(
+        &self,
+        _path: &str,
+        _file_block: FileBlock,
+        _hasher: &mut PieceHasher,
+    ) -> Option<std::io::Result<()>> {
+        None
+    }
+    /// Close the files kept open between blocks, they may be moved or deleted before the next use
+    fn close_files(&self) {}
+    /// Read several blocks, stores may submit them at once. Result of each block
+    fn read_blocks(&self, blocks: &[(&str, FileBlock)]) -> Vec<std::io::Result<Vec<u8>>> {
+        blocks
+            .iter()
+            .map(|&(path, file_block)| self.read_block(path, file_block))
+            .collect()
+    }
+    /// Write several blocks, like `read_blocks`
+    fn write_blocks(&self, blocks: &[(&str, FileBlock, &[u8])]) -> Vec<std::io::Result<()>> {
+        blocks
+            .iter()
+            .map(|&(path, file_block, data)| self.write_block(path, file_block, data))
+            .collect()
+    }
+}
+
+/// Fails if the local file exists but can't be written
+fn check_writable_file(path: &str) -> std::io::Result<()> {
+    // files that don't exist must not be created here
+    match get_write_file(path, false) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Files on the local filesystem
+#[derive(Default)]
+struct LocalStore {
+    /// Create destination files that don't exist
+    create_missing: bool,
+    /// Allocate the blocks of the files created, instead of leaving them sparse
+    preallocate: bool,
+    /// Read files through memory mappings, blocks are hashed in place
+    mmap: bool,
+    handles: FileHandles,
+}
+
+impl PieceStore for LocalStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        let f = self.handles.get(path, false, false)?;
+        if self.mmap {
+            return Ok(mapped_block(&map_file(&f)?, file_block)?.to_vec());
+        }
+        read_piece(&f, file_block)
+    }
+
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+        let f = self.handles.get(path, true, self.create_missing)?;
+        write_piece(&f, file_block, data)
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        check_writable_file(path)
+    }
+
+    fn create_file(&self, path: &str, size: u64) -> std::io::Result<()> {
+        match self.create_missing {
+            true => create_sized_file(path, size, self.preallocate),
+            false => Ok(()),
+        }
+    }
+
+    fn hash_in_place(
+        &self,
+        path: &str,
+        file_block: FileBlock,
+        hasher: &mut PieceHasher,
+    ) -> Option<std::io::Result<()>> {
+        if !self.mmap {
+            return None;
+        }
+        Some(self.handles.get(path, false, false).and_then(|f| {
+            let map = map_file(&f)?;
+            hasher.update(mapped_block(&map, file_block)?);
+            Ok(())
+        }))
+    }
+
+    fn close_files(&self) {
+        self.handles.files.lock().unwrap().clear();
+    }
+}
+
+/// Files on a remote host, over SFTP. Authentication goes through the SSH agent
+#[cfg(feature = "sftp")]
+struct SftpStore {
+    // the session must outlive the SFTP channel
+    _session: ssh2::Session,
+    sftp: std::sync::Mutex<ssh2::Sftp>,
+    /// Create destination files that don't exist
+    create_missing: bool,
+}
+
+#[cfg(feature = "sftp")]
+impl SftpStore {
+    /// Connect to `user@host[:port]`
+    fn connect(
+        destination: &str,
+        create_missing: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (user, host) = destination
+            .split_once('@')
+            .ok_or_else(|| format!("Expected user@host[:port], got {:?}", destination))?;
+        let address = if host.contains(':') {
+            host.to_owned()
+        } else {
+            format!("{}:22", host)
+        };
+
+        let tcp = std::net::TcpStream::connect(address)?;
+        let mut session = ssh2::Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+        session.userauth_agent(user)?;
+        let sftp = session.sftp()?;
+
+        Ok(SftpStore {
+            _session: session,
+            sftp: std::sync::Mutex::new(sftp),
+            create_missing,
+        })
+    }
+}
+
+#[cfg(feature = "sftp")]
+impl PieceStore for SftpStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        let sftp = self.sftp.lock().unwrap();
+        let mut f = sftp.open(std::path::Path::new(path))?;
+        let mut buf = vec![0; file_block.size as usize];
+        f.seek(std::io::SeekFrom::Start(file_block.offset))?;
+        f.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+        let sftp = self.sftp.lock().unwrap();
+        // never truncated
+        let flags = match self.create_missing {
+            true => ssh2::OpenFlags::WRITE | ssh2::OpenFlags::CREATE,
+            false => ssh2::OpenFlags::WRITE,
+        };
+        let mut f = sftp.open_mode(
+            std::path::Path::new(path),
+            flags,
+            0o644,
+            ssh2::OpenType::File,
+        )?;
+        f.seek(std::io::SeekFrom::Start(file_block.offset))?;
+        f.write_all(data)?;
+        f.flush()
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        let sftp = self.sftp.lock().unwrap();
+        match sftp.open_mode(
+            std::path::Path::new(path),
+            ssh2::OpenFlags::WRITE,
+            0o644,
+            ssh2::OpenType::File,
+        ) {
+            Err(e) if e.code() != ssh2::ErrorCode::SFTP(2) => Err(e.into()), // 2 is "no such file"
+            _ => Ok(()),
+        }
+    }
+
+    /// Always sparse: SFTP can't allocate blocks
+    fn create_file(&self, path: &str, size: u64) -> std::io::Result<()> {
+        if !self.create_missing {
+            return Ok(());
+        }
+        let sftp = self.sftp.lock().unwrap();
+        let path = std::path::Path::new(path);
+        if sftp.stat(path).is_ok() {
+            return Ok(());
+        }
+        let dirs: Vec<_> = path.ancestors().skip(1).collect();
+        for dir in dirs.into_iter().rev() {
+            if !dir.as_os_str().is_empty() && sftp.stat(dir).is_err() {
+                sftp.mkdir(dir, 0o755)?;
+            }
+        }
+
+        let mut f = sftp.open_mode(
+            path,
+            ssh2::OpenFlags::WRITE | ssh2::OpenFlags::CREATE | ssh2::OpenFlags::EXCLUSIVE,
+            0o644,
+            ssh2::OpenType::File,
+        )?;
+        f.setstat(ssh2::FileStat {
+            size: Some(size),
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: None,
+        })?;
+        Ok(())
+    }
+}
+
+/// Writes pushed back through qBittorrent instead of being written to files, reads go to `inner`
+///
+/// The WebUI API has no endpoint to write torrent data, so every write fails. This is the place for a backend
+/// of a client that can take piece data
+struct DaemonWriteStore {
+    inner: Arc<dyn PieceStore>,
+}
+
+impl DaemonWriteStore {
+    fn unsupported() -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the qBittorrent WebUI API can't write torrent data",
+        )
+    }
+}
+
+impl PieceStore for DaemonWriteStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        self.inner.read_block(path, file_block)
+    }
+
+    fn read_blocks(&self, blocks: &[(&str, FileBlock)]) -> Vec<std::io::Result<Vec<u8>>> {
+        self.inner.read_blocks(blocks)
+    }
+
+    fn write_block(
+        &self,
+        _path: &str,
+        _file_block: FileBlock,
+        _data: &[u8],
+    ) -> std::io::Result<()> {
+        Err(Self::unsupported())
+    }
+
+    fn check_writable(&self, _path: &str) -> std::io::Result<()> {
+        Err(Self::unsupported())
+    }
+
+    fn check_size(&self, path: &str, size: u64) -> std::io::Result<()> {
+        self.inner.check_size(path, size)
+    }
+
+    fn hash_in_place(
+        &self,
+        path: &str,
+        file_block: FileBlock,
+        hasher: &mut PieceHasher,
+    ) -> Option<std::io::Result<()>> {
+        self.inner.hash_in_place(path, file_block, hasher)
+    }
+
+    fn close_files(&self) {
+        self.inner.close_files()
+    }
+}
+
+/// Padding files (BEP 47) read as zeros, and writes to them are dropped: clients don't create them on disk
+struct PaddingStore {
+    inner: Arc<dyn PieceStore>,
+}
+
+impl PieceStore for PaddingStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        match is_padding_file(path) {
+            true => Ok(vec![0; file_block.size as usize]),
+            false => self.inner.read_block(path, file_block),
+        }
+    }
+
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+        match is_padding_file(path) {
+            true => Ok(()),
+            false => self.inner.write_block(path, file_block, data),
+        }
+    }
+
+    fn read_blocks(&self, blocks: &[(&str, FileBlock)]) -> Vec<std::io::Result<Vec<u8>>> {
+        let files: Vec<_> = blocks
+            .iter()
+            .filter(|(path, _)| !is_padding_file(path))
+            .copied()
+            .collect();
+        let mut read = self.inner.read_blocks(&files).into_iter();
+        blocks
+            .iter()
+            .map(|&(path, file_block)| match is_padding_file(path) {
+                true => Ok(vec![0; file_block.size as usize]),
+                false => read.next().unwrap(),
+            })
+            .collect()
+    }
+
+    fn write_blocks(&self, blocks: &[(&str, FileBlock, &[u8])]) -> Vec<std::io::Result<()>> {
+        let files: Vec<_> = blocks
+            .iter()
+            .filter(|(path, _, _)| !is_padding_file(path))
+            .copied()
+            .collect();
+        let mut written = self.inner.write_blocks(&files).into_iter();
+        blocks
+            .iter()
+            .map(|(path, _, _)| match is_padding_file(path) {
+                true => Ok(()),
+                false => written.next().unwrap(),
+            })
+            .collect()
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        match is_padding_file(path) {
+            true => Ok(()),
+            false => self.inner.check_writable(path),
+        }
+    }
+
+    fn check_size(&self, path: &str, size: u64) -> std::io::Result<()> {
+        match is_padding_file(path) {
+            true => Ok(()),
+            false => self.inner.check_size(path, size),
+        }
+    }
+
+    fn create_file(&self, path: &str, size: u64) -> std::io::Result<()> {
+        match is_padding_file(path) {
+            true => Ok(()),
+            false => self.inner.create_file(path, size),
+        }
+    }
+
+    fn hash_in_place(
+        &self,
+        path: &str,
+        file_block: FileBlock,
+        hasher: &mut PieceHasher,
+    ) -> Option<std::io::Result<()>> {
+        match is_padding_file(path) {
+            true => None,
+            false => self.inner.hash_in_place(path, file_block, hasher),
+        }
+    }
+
+    fn close_files(&self) {
+        self.inner.close_files()
+    }
+}
+
+/// Blocks read recently, least recently used first, up to `capacity` bytes
+struct BlockCache {
+    capacity: u64,
+    size: u64,
+    blocks: std::collections::VecDeque<(String, FileBlock, Arc<Vec<u8>>)>,
+}
+
+impl BlockCache {
+    /// Data of a block, from a cached block that contains it
+    fn get(&mut self, path: &str, file_block: FileBlock) -> Option<Vec<u8>> {
+        let i = self.blocks.iter().position(|(p, cached, _)| {
+            p == path
+                && cached.offset <= file_block.offset
+                && file_block.offset + file_block.size <= cached.offset + cached.size
+        })?;
+        let entry = self.blocks.remove(i)?;
+        let start = (file_block.offset - entry.1.offset) as usize;
+        let data = entry.2[start..start + file_block.size as usize].to_vec();
+        self.blocks.push_back(entry);
+        Some(data)
+    }
+
+    /// Blocks bigger than a quarter of the cache are not kept, they would evict everything else
+    fn insert(&mut self, path: &str, file_block: FileBlock, data: &[u8]) {
+        if file_block.size > self.capacity / 4 {
+            return;
+        }
+        self.remove(path, file_block);
+        while self.size + file_block.size > self.capacity {
+            let Some((_, evicted, _)) = self.blocks.pop_front() else {
+                break;
+            };
+            self.size -= evicted.size;
+        }
+        self.size += file_block.size;
+        self.blocks
+            .push_back((path.to_owned(), file_block, Arc::new(data.to_vec())));
+    }
+
+    /// Drop the cached blocks that overlap a block, e.g. when it is written
+    fn remove(&mut self, path: &str, file_block: FileBlock) {
+        let size = &mut self.size;
+        self.blocks.retain(|(p, cached, _)| {
+            let overlaps = p == path
+                && cached.offset < file_block.offset + file_block.size
+                && file_block.offset < cached.offset + cached.size;
+            if overlaps {
+                *size -= cached.size;
+            }
+            !overlaps
+        });
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.size = 0;
+    }
+}
+
+/// Keeps the blocks read in a `BlockCache`, so that a range read again, e.g. a donor file that is the counterpart of
+/// several destination files, is taken from memory. The cache is emptied with `close_files`, as the files may change
+/// before the next merge
+struct CachingStore {
+    inner: Arc<dyn PieceStore>,
+    cache: std::sync::Mutex<BlockCache>,
+}
+
+impl CachingStore {
+    fn new(inner: Arc<dyn PieceStore>, capacity: u64) -> Self {
+        CachingStore {
+            inner,
+            cache: std::sync::Mutex::new(BlockCache {
+                capacity,
+                size: 0,
+                blocks: Default::default(),
+            }),
+        }
+    }
+}
+
+impl PieceStore for CachingStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        if let Some(data) = self.cache.lock().unwrap().get(path, file_block) {
+            return Ok(data);
+        }
+        let data = self.inner.read_block(path, file_block)?;
+        self.cache.lock().unwrap().insert(path, file_block, &data);
+        Ok(data)
+    }
+
+    fn read_blocks(&self, blocks: &[(&str, FileBlock)]) -> Vec<std::io::Result<Vec<u8>>> {
+        let cached: Vec<_> = {
+            let mut cache = self.cache.lock().unwrap();
+            blocks
+                .iter()
+                .map(|&(path, file_block)| cache.get(path, file_block))
+                .collect()
+        };
+        let missing: Vec<_> = blocks
+            .iter()
+            .zip(&cached)
+            .filter(|(_, data)| data.is_none())
+            .map(|(block, _)| *block)
+            .collect();
+        let mut read = self.inner.read_blocks(&missing).into_iter();
+
+        let mut cache = self.cache.lock().unwrap();
+        blocks
+            .iter()
+            .zip(cached)
+            .map(|(&(path, file_block), data)| match data {
+                Some(data) => Ok(data),
+                None => {
+                    let data = read.next().unwrap();
+                    if let Ok(data) = &data {
+                        cache.insert(path, file_block, data);
+                    }
+                    data
+                }
+            })
+            .collect()
+    }
+
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+        self.cache.lock().unwrap().remove(path, file_block);
+        self.inner.write_block(path, file_block, data)
+    }
+
+    fn write_blocks(&self, blocks: &[(&str, FileBlock, &[u8])]) -> Vec<std::io::Result<()>> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for &(path, file_block, _) in blocks {
+                cache.remove(path, file_block);
+            }
+        }
+        self.inner.write_blocks(blocks)
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        self.inner.check_writable(path)
+    }
+
+    fn check_size(&self, path: &str, size: u64) -> std::io::Result<()> {
+        self.inner.check_size(path, size)
+    }
+
+    fn create_file(&self, path: &str, size: u64) -> std::io::Result<()> {
+        self.inner.create_file(path, size)
+    }
+
+    fn hash_in_place(
+        &self,
+        path: &str,
+        file_block: FileBlock,
+        hasher: &mut PieceHasher,
+    ) -> Option<std::io::Result<()>> {
+        self.inner.hash_in_place(path, file_block, hasher)
+    }
+
+    fn close_files(&self) {
+        self.cache.lock().unwrap().clear();
+        self.inner.close_files()
+    }
+}
+
+/// Limits a flow of bytes to `rate` per second, with bursts of up to a second
+///
+/// Bytes are taken even when there are not enough tokens left, the caller then sleeps until the debt is paid back, so
+/// blocks bigger than a second of data still go through
+struct TokenBucket {
+    rate: u64,
+    /// Tokens available, negative while in debt, and when they were counted
+    state: std::sync::Mutex<(f64, std::time::Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            state: std::sync::Mutex::new((rate as f64, std::time::Instant::now())),
+        }
+    }
+
+    /// Take `bytes` tokens, sleeping as long as needed to stay under the rate. This blocks the thread: stores are only
+    /// used from blocking tasks during merges
+    fn take(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, counted) = &mut *state;
+            let now = std::time::Instant::now();
+            *tokens = (*tokens + now.duration_since(*counted).as_secs_f64() * self.rate as f64)
+                .min(self.rate as f64)
+                - bytes as f64;
+            *counted = now;
+            match *tokens < 0. {
+                true => Duration::from_secs_f64(-*tokens / self.rate as f64),
+                false => Duration::ZERO,
+            }
+        };
+        std::thread::sleep(wait);
+    }
+}
+
+/// Reads and writes limited to a number of bytes per second, so that the disks shared with the client (or anything
+/// else) are not saturated
+struct ThrottledStore {
+    inner: Arc<dyn PieceStore>,
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+}
+
+impl ThrottledStore {
+    fn throttle(bucket: &Option<TokenBucket>, bytes: u64) {
+        if let Some(bucket) = bucket {
+            bucket.take(bytes);
+        }
+    }
+}
+
+impl PieceStore for ThrottledStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        Self::throttle(&self.read, file_block.size);
+        self.inner.read_block(path, file_block)
+    }
+
+    fn read_blocks(&self, blocks: &[(&str, FileBlock)]) -> Vec<std::io::Result<Vec<u8>>> {
+        Self::throttle(&self.read, blocks.iter().map(|(_, block)| block.size).sum());
+        self.inner.read_blocks(blocks)
+    }
+
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+        Self::throttle(&self.write, file_block.size);
+        self.inner.write_block(path, file_block, data)
+    }
+
+    fn write_blocks(&self, blocks: &[(&str, FileBlock, &[u8])]) -> Vec<std::io::Result<()>> {
+        Self::throttle(
+            &self.write,
+            blocks.iter().map(|(_, block, _)| block.size).sum(),
+        );
+        self.inner.write_blocks(blocks)
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        self.inner.check_writable(path)
+    }
+
+    fn check_size(&self, path: &str, size: u64) -> std::io::Result<()> {
+        self.inner.check_size(path, size)
+    }
+
+    fn create_file(&self, path: &str, size: u64) -> std::io::Result<()> {
+        self.inner.create_file(path, size)
+    }
+
+    /// Mapped pages are read from the disk while they are hashed
+    fn hash_in_place(
+        &self,
+        path: &str,
+        file_block: FileBlock,
+        hasher: &mut PieceHasher,
+    ) -> Option<std::io::Result<()>> {
+        let hashed = self.inner.hash_in_place(path, file_block, hasher)?;
+        Self::throttle(&self.read, file_block.size);
+        Some(hashed)
+    }
+
+    fn close_files(&self) {
+        self.inner.close_files()
+    }
+}
+
+/// Saves the data of each block to a journal before it is overwritten, see `undo_journal`
+///
+/// The journal has one JSON line per write: path, offset, size and the original data in base64, `null` when there was
+/// none: the file didn't exist, or the block is past the end of a file created by this run. Lines are written before
+/// the data is, a block whose original data can't be read otherwise is not written
+struct JournalStore {
+    inner: Arc<dyn PieceStore>,
+    journal: std::sync::Mutex<std::io::LineWriter<File>>,
+    /// Files that didn't exist before `create_file`
+    created: std::sync::Mutex<HashSet<String>>,
+}
+
+impl JournalStore {
+    /// Entries are appended to the journal, it can be shared by several runs
+    fn open(inner: Arc<dyn PieceStore>, path: &str) -> std::io::Result<Self> {
+        let f = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JournalStore {
+            inner,
+            journal: std::sync::Mutex::new(std::io::LineWriter::new(f)),
+            created: Default::default(),
+        })
+    }
+
+    /// Original data of a block from its read, `None` when there is none to save
+    fn original(
+        &self,
+        path: &str,
+        read: std::io::Result<Vec<u8>>,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        match read {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e)
+                if e.kind() == std::io::ErrorKind::UnexpectedEof
+                    && self.created.lock().unwrap().contains(path) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(std::io::Error::new(
+                e.kind(),
+                format!("Can't read the original data of {}: {}", path, e),
+            )),
+        }
+    }
+
+    /// Append the original data of a block, before it is written
+    fn save(
+        &self,
+        path: &str,
+        file_block: FileBlock,
+        original: Option<Vec<u8>>,
+    ) -> std::io::Result<()> {
+        let entry = serde_json::json!({
+            "path": path,
+            "offset": file_block.offset,
+            "size": file_block.size,
+            "data": original.map(|data| base64::engine::general_purpose::STANDARD.encode(data)),
+        });
+        writeln!(self.journal.lock().unwrap(), "{}", entry)
+    }
+}
+
+impl PieceStore for JournalStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        self.inner.read_block(path, file_block)
+    }
+
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+        let original = self.original(path, self.inner.read_block(path, file_block))?;
+        self.save(path, file_block, original)?;
+        self.inner.write_block(path, file_block, data)
+    }
+
+    fn read_blocks(&self, blocks: &[(&str, FileBlock)]) -> Vec<std::io::Result<Vec<u8>>> {
+        self.inner.read_blocks(blocks)
+    }
+
+    fn write_blocks(&self, blocks: &[(&str, FileBlock, &[u8])]) -> Vec<std::io::Result<()>> {
+        let ranges: Vec<_> = blocks
+            .iter()
+            .map(|&(path, file_block, _)| (path, file_block))
+            .collect();
+        let saved: Vec<_> = ranges
+            .iter()
+            .zip(self.inner.read_blocks(&ranges))
+            .map(|(&(path, file_block), read)| {
+                let original = self.original(path, read)?;
+                self.save(path, file_block, original)
+            })
+            .collect();
+        // blocks that could not be saved are not written
+        let to_write: Vec<_> = blocks
+            .iter()
+            .zip(&saved)
+            .filter(|(_, saved)| saved.is_ok())
+            .map(|(block, _)| *block)
+            .collect();
+        let mut written = self.inner.write_blocks(&to_write).into_iter();
+        saved
+            .into_iter()
+            .map(|saved| saved.and_then(|()| written.next().unwrap()))
+            .collect()
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        self.inner.check_writable(path)
+    }
+
+    fn check_size(&self, path: &str, size: u64) -> std::io::Result<()> {
+        self.inner.check_size(path, size)
+    }
+
+    fn create_file(&self, path: &str, size: u64) -> std::io::Result<()> {
+        let empty = FileBlock { offset: 0, size: 0 };
+        let missing = matches!(
+            self.inner.read_block(path, empty),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound
+        );
+        self.inner.create_file(path, size)?;
+        if missing {
+            self.created.lock().unwrap().insert(path.to_owned());
+        }
+        Ok(())
+    }
+
+    fn hash_in_place(
+        &self,
+        path: &str,
+        file_block: FileBlock,
+        hasher: &mut PieceHasher,
+    ) -> Option<std::io::Result<()>> {
+        self.inner.hash_in_place(path, file_block, hasher)
+    }
+
+    fn close_files(&self) {
+        self.inner.close_files()
+    }
+}
+
+/// Write back the original data saved by `JournalStore`, newest entries first so that blocks written several
+/// times get their oldest data
+fn undo_journal(
+    store: &dyn PieceStore,
+    path: &str,
+) -> Result<RunOutcome, Box<dyn std::error::Error>> {
+    let journal =
+        std::fs::read_to_string(path).map_err(|e| format!("Can't read journal {}: {}", path, e))?;
+    let entries = journal
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid journal {}: {}", path, e))?;
+
+    let (mut restored, mut failed) = (0, 0);
+    for entry in entries.iter().rev() {
+        let (Some(path), Some(offset), Some(size)) = (
+            entry["path"].as_str(),
+            entry["offset"].as_u64(),
+            entry["size"].as_u64(),
+        ) else {
+            return Err(format!("Invalid journal entry: {}", entry).into());
+        };
+        let Some(data) = entry["data"].as_str() else {
+            info!(
+                "{}: {} bytes at {} didn't exist before they were written, left as they are",
+                path, size, offset
+            );
+            continue;
+        };
+        let data = base64::engine::general_purpose::STANDARD.decode(data)?;
+        match store.write_block(path, FileBlock { offset, size }, &data) {
+            Ok(()) => restored += 1,
+            Err(e) => {
+                error!("Can't write to {}: {}", path, e);
+                failed += 1;
+            }
+        }
+    }
+
+    info!(
+        "Restored {} blocks from {}, {} could not be restored",
+        restored, path, failed
+    );
+    if restored > 0 {
+        info!("Recheck the torrents of these files, so that the client sees their original data");
+    }
+    Ok(match (restored, failed) {
+        (_, 0) => RunOutcome::Complete,
+        (0, _) => RunOutcome::NothingRestored,
+        _ => RunOutcome::Partial,
+    })
+}
+
+/// Extensions of the compressed variants of a donor file, in the order they are tried
+#[cfg(feature = "compressed")]
+const COMPRESSED_EXTENSIONS: [&str; 2] = ["zst", "gz"];
+
+/// Local files, read from a compressed variant (`<file>.zst` or `<file>.gz`) when they don't exist
+///
+/// Compressed files can't be seeked: each read decompresses the file from the start
+#[cfg(feature = "compressed")]
+struct DecompressingStore {
+    inner: Arc<dyn PieceStore>,
+}
+
+#[cfg(feature = "compressed")]
+impl DecompressingStore {
+    /// Compressed variant of a file that doesn't exist
+    fn compressed_path(path: &str) -> Option<String> {
+        if std::path::Path::new(path).exists() {
+            return None;
+        }
+        COMPRESSED_EXTENSIONS
+            .iter()
+            .map(|extension| format!("{}.{}", path, extension))
+            .find(|compressed| std::path::Path::new(compressed).is_file())
+    }
+
+    fn open(compressed_path: &str) -> std::io::Result<Box<dyn Read>> {
+        let f = get_read_file(compressed_path)?;
+        if compressed_path.ends_with(".zst") {
+            Ok(Box::new(zstd::stream::read::Decoder::with_buffer(f)?))
+        } else {
+            Ok(Box::new(flate2::bufread::MultiGzDecoder::new(f)))
+        }
+    }
+}
+
+#[cfg(feature = "compressed")]
+impl PieceStore for DecompressingStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        let compressed_path = match Self::compressed_path(path) {
+            Some(compressed_path) => compressed_path,
+            None => return self.inner.read_block(path, file_block),
+        };
+        debug!("Reading {:?} from {}", file_block, compressed_path);
+
+        let mut f = Self::open(&compressed_path)?;
+        let skipped = std::io::copy(
+            &mut f.by_ref().take(file_block.offset),
+            &mut std::io::sink(),
+        )?;
+        if skipped < file_block.offset {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut buf = vec![0; file_block.size as usize];
+        f.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+        self.inner.write_block(path, file_block, data)
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        self.inner.check_writable(path)
+    }
+
+    fn create_file(&self, path: &str, size: u64) -> std::io::Result<()> {
+        self.inner.create_file(path, size)
+    }
+
+    fn check_size(&self, path: &str, size: u64) -> std::io::Result<()> {
+        let compressed_path = match Self::compressed_path(path) {
+            Some(compressed_path) => compressed_path,
+            None => return self.inner.check_size(path, size),
+        };
+
+        let decompressed_size =
+            std::io::copy(&mut Self::open(&compressed_path)?, &mut std::io::sink())?;
+        if decompressed_size != size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} is {} bytes once decompressed, expected {}",
+                    compressed_path, decompressed_size, size
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn hash_in_place(
+        &self,
+        path: &str,
+        file_block: FileBlock,
+        hasher: &mut PieceHasher,
+    ) -> Option<std::io::Result<()>> {
+        match Self::compressed_path(path) {
+            Some(_) => None,
+            None => self.inner.hash_in_place(path, file_block, hasher),
+        }
+    }
+
+    fn close_files(&self) {
+        self.inner.close_files()
+    }
+}
+
+/// Operations submitted to io_uring at once, bigger batches are submitted in several rounds
+#[cfg(feature = "io-uring")]
+const URING_ENTRIES: u32 = 256;
+
+/// Local files read and written through io_uring (Linux only): the blocks of a batch are submitted with a single
+/// system call, instead of one each
+#[cfg(feature = "io-uring")]
+struct UringStore {
+    ring: std::sync::Mutex<io_uring::IoUring>,
+    /// Create destination files that don't exist
+    create_missing: bool,
+    /// Allocate the blocks of the files created, instead of leaving them sparse
+    preallocate: bool,
+    handles: FileHandles,
+}
+
+#[cfg(feature = "io-uring")]
+impl UringStore {
+    fn new(create_missing: bool, preallocate: bool, handles: FileHandles) -> std::io::Result<Self> {
+        Ok(UringStore {
+            ring: std::sync::Mutex::new(io_uring::IoUring::new(URING_ENTRIES)?),
+            create_missing,
+            preallocate,
+            handles,
+        })
+    }
+
+    /// Run the operations, and the number of bytes transferred by each one
+    ///
+    /// The buffers and the files of the operations must outlive the call
+    fn run(&self, entries: &[io_uring::squeue::Entry]) -> Vec<std::io::Result<usize>> {
+        let mut ring = self.ring.lock().unwrap();
+        let mut results = Vec::with_capacity(entries.len());
+        for round in entries.chunks(URING_ENTRIES as usize) {
+            let mut round_results: Vec<Option<std::io::Result<usize>>> =
+                round.iter().map(|_| None).collect();
+            for (i, entry) in round.iter().enumerate() {
+                // SAFETY: the caller keeps the buffers and the files alive, and the completions are waited for
+                unsafe { ring.submission().push(&entry.clone().user_data(i as u64)) }
+                    .expect("a round fits in the submission queue");
+            }
+            let mut completed = 0;
+            while completed < round.len() {
+                // operations in flight write to the buffers, they can't be given up
+                match ring.submit_and_wait(round.len() - completed) {
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    result => {
+                        result.expect("io_uring failed with operations in flight");
+                    }
+                }
+                for cqe in ring.completion() {
+                    round_results[cqe.user_data() as usize] = Some(match cqe.result() {
+                        errno if errno < 0 => Err(std::io::Error::from_raw_os_error(-errno)),
+                        size => Ok(size as usize),
+                    });
+                    completed += 1;
+                }
+            }
+            results.extend(round_results.into_iter().map(Option::unwrap));
+        }
+        results
+    }
+}
+
+#[cfg(feature = "io-uring")]
+impl PieceStore for UringStore {
+    fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+        self.read_blocks(&[(path, file_block)]).pop().unwrap()
+    }
+
+    fn write_block(&self, path: &str, file_block: FileBlock, data: &[u8]) -> std::io::Result<()> {
+        self.write_blocks(&[(path, file_block, data)])
+            .pop()
+            .unwrap()
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        check_writable_file(path)
+    }
+
+    fn create_file(&self, path: &str, size: u64) -> std::io::Result<()> {
+        match self.create_missing {
+            true => create_sized_file(path, size, self.preallocate),
+            false => Ok(()),
+        }
+    }
+
+    fn close_files(&self) {
+        self.handles.files.lock().unwrap().clear();
+    }
+
+    fn read_blocks(&self, blocks: &[(&str, FileBlock)]) -> Vec<std::io::Result<Vec<u8>>> {
+        use std::os::fd::AsRawFd;
+
+        let files: Vec<_> = blocks
+            .iter()
+            .map(|(path, _)| self.handles.get(path, false, false))
+            .collect();
+        let mut buffers: Vec<Vec<u8>> = blocks
+            .iter()
+            .map(|(_, file_block)| vec![0; file_block.size as usize])
+            .collect();
+        let entries: Vec<_> = files
+            .iter()
+            .zip(&mut buffers)
+            .zip(blocks)
+            .filter_map(|((f, buf), (_, file_block))| {
+                let f = f.as_ref().ok()?;
+                let read = io_uring::opcode::Read::new(
+                    io_uring::types::Fd(f.as_raw_fd()),
+                    buf.as_mut_ptr(),
+                    buf.len().try_into().ok()?,
+                );
+                Some(read.offset(file_block.offset).build())
+            })
+            .collect();
+        let mut read = self.run(&entries).into_iter();
+
+        files
+            .into_iter()
+            .zip(buffers)
+            .zip(blocks)
+            .map(|((f, mut buf), &(_, file_block))| {
+                let f = f?;
+                // too big for an operation
+                let Ok(_) = u32::try_from(buf.len()) else {
+                    return read_piece(&f, file_block);
+                };
+                let size = read.next().unwrap()?;
+                // cut short, the rest is read as usual, and fails at the end of the file
+                if size < buf.len() {
+                    let rest = FileBlock {
+                        offset: file_block.offset + size as u64,
+                        size: (buf.len() - size) as u64,
+                    };
+                    buf[size..].copy_from_slice(&read_piece(&f, rest)?);
+                }
+                Ok(buf)
+            })
+            .collect()
+    }
+
+    fn write_blocks(&self, blocks: &[(&str, FileBlock, &[u8])]) -> Vec<std::io::Result<()>> {
+        use std::os::fd::AsRawFd;
+
+        let files: Vec<_> = blocks
+            .iter()
+            .map(|(path, _, _)| self.handles.get(path, true, self.create_missing))
+            .collect();
+        let entries: Vec<_> = files
+            .iter()
+            .zip(blocks)
+            .filter_map(|(f, (_, file_block, data))| {
+                let f = f.as_ref().ok()?;
+                let write = io_uring::opcode::Write::new(
+                    io_uring::types::Fd(f.as_raw_fd()),
+                    data.as_ptr(),
+                    data.len().try_into().ok()?,
+                );
+                Some(write.offset(file_block.offset).build())
+            })
+            .collect();
+        let mut written = self.run(&entries).into_iter();
+
+        files
+            .into_iter()
+            .zip(blocks)
+            .map(|(f, &(_, file_block, data))| {
+                let f = f?;
+                let Ok(_) = u32::try_from(data.len()) else {
+                    return write_piece(&f, file_block, data);
+                };
+                let size = written.next().unwrap()?;
+                if size < data.len() {
+                    let rest = FileBlock {
+                        offset: file_block.offset + size as u64,
+                        size: (data.len() - size) as u64,
+                    };
+                    write_piece(&f, rest, &data[size..])?;
+                }
+                Ok(())
+            })
+            .collect()
+    }
+}
+
+/// Default of `Options::max_buffer`: blocks bigger than this are never fully loaded in memory
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Split a block in chunks of at most `max_buffer` bytes
+fn chunks(file_block: FileBlock, max_buffer: u64) -> impl Iterator<Item = FileBlock> {
+    (file_block.offset..file_block.offset + file_block.size)
+        .step_by(max_buffer as usize)
+        .map(move |offset| FileBlock {
+            offset,
+            size: max_buffer.min(file_block.offset + file_block.size - offset),
+        })
+}
+
+/// Hash of blocks put end to end, read chunk by chunk
+fn hash_blocks_chunked(
+    store: &dyn PieceStore,
+    blocks: &[(&str, FileBlock)],
+    mut hasher: PieceHasher,
+    max_buffer: u64,
+) -> std::io::Result<PieceHash> {
+    for &(path, file_block) in blocks {
+        if let Some(hashed) = store.hash_in_place(path, file_block, &mut hasher) {
+            hashed?;
+            continue;
+        }
+        for chunk in chunks(file_block, max_buffer) {
+            hasher.update(&store.read_block(path, chunk)?);
+        }
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// Copy a block between 2 identical files, chunk by chunk
+fn copy_block_chunked(
+    store: &dyn PieceStore,
+    src_path: &str,
+    dst_path: &str,
+    file_block: FileBlock,
+    max_buffer: u64,
+) -> std::io::Result<()> {
+    for chunk in chunks(file_block, max_buffer) {
+        let data = store.read_block(src_path, chunk)?;
+        store.write_block(dst_path, chunk, &data)?;
+    }
+
+    Ok(())
+}
+
+/// Read blocks of files, and check the hash of their data put end to end. Used on destination pieces before and after writing them
+///
+/// Incomplete destination files are usually sparse: pieces that were not downloaded yet are holes, and read as zeros.
+/// Only the given blocks are read, never the neighbouring ranges, so holes next to them can't make the check fail
+fn blocks_have_hash(
+    store: &dyn PieceStore,
+    blocks: &[(&str, FileBlock)],
+    hash: &PieceHash,
+    piece_size: u64,
+    max_buffer: u64,
+) -> std::io::Result<bool> {
+    Ok(&hash_blocks_chunked(store, blocks, hash.hasher(piece_size), max_buffer)? == hash)
+}
+
+/// Bounds the number of files that are open at the same time
+#[derive(Debug, Clone)]
+struct FileHandlePool {
+    semaphore: Arc<Semaphore>,
+}
+impl FileHandlePool {
+    fn new(max_open_files: usize) -> Self {
+        FileHandlePool {
+            semaphore: Arc::new(Semaphore::new(max_open_files)),
+        }
+    }
+
+    /// Wait for a slot, the file must be closed before the permit is dropped
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        if self.semaphore.available_permits() == 0 {
+            info!("File handle pool saturated, waiting");
+        }
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("File handle pool closed")
+    }
+}
+
+/// Threads hashing the data read, so that hashing a batch overlaps with reading the next one, and the async tasks
+/// don't wait for it
+#[derive(Clone)]
+struct HashPool {
+    pool: Arc<rayon::ThreadPool>,
+}
+impl HashPool {
+    /// One thread per CPU with 0 `threads`
+    fn new(threads: usize) -> Result<Self, rayon::ThreadPoolBuildError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("hash-{}", i))
+            .build()?;
+        Ok(HashPool {
+            pool: Arc::new(pool),
+        })
+    }
+
+    /// Data read for each piece of a batch, with its hash. The pieces are hashed in parallel
+    async fn hash(
+        &self,
+        batch: &[PlannedRead],
+        batch_data: Vec<std::io::Result<SourceData>>,
+    ) -> Vec<std::io::Result<(SourceData, PieceHash)>> {
+        use rayon::prelude::*;
+
+        let hashers: Vec<PieceHasher> = batch.iter().map(PlannedRead::hasher).collect();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.pool.spawn(move || {
+            let hashed = hashers
+                .into_par_iter()
+                .zip(batch_data)
+                .map(|(hasher, source_data)| {
+                    let source_data = source_data?;
+                    let hash = match &source_data {
+                        SourceData::InMemory(data) => hasher.digest(data),
+                        SourceData::Hashed(hash) => *hash,
+                    };
+                    Ok((source_data, hash))
+                })
+                .collect();
+            let _ = tx.send(hashed);
+        });
+        rx.await.expect("Hash pool stopped")
+    }
+}
+
+/// Part of a destination piece that is in a single file, and the source file that has the same data
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    src_filename: String,
+    dst_filename: String,
+    /// Same offset in both files, as they are identical
+    file_block: FileBlock,
+}
+
+/// Split a destination piece in segments, and find the source file of each of them
+///
+/// A piece overlapping several destination files is assembled from the matching source files, wherever they are in
+/// the source torrent. Segments only cover the destination piece: with bigger donor pieces, only the needed part of
+/// them is read, the donor pieces are only used to know if the data is there
+fn plan_piece(
+    src: &Torrent,
+    dst: &Torrent,
+    same_files: &[FileMatch],
+    dst_piece_idx: usize,
+    pieces_from_donor_only: bool,
+    trust_donor_state: bool,
+) -> Result<Vec<Segment>, SkipReason> {
+    // any data would be compared with a meaningless hash
+    if !dst
+        .pieces_hashes
+        .get(dst_piece_idx)
+        .is_some_and(PieceHash::is_known)
+    {
+        return Err(SkipReason::NoExpectedHash);
+    }
+
+    let mut segments = Vec::new();
+    let mut src_pieces = Vec::new();
+    for (dst_filename, file_block) in piece_segments(dst, dst_piece_idx) {
+        // zeros, see `PaddingStore`
+        if is_padding_file(&dst_filename) {
+            if dst.hashes_padding() {
+                segments.push(Segment {
+                    src_filename: dst_filename.clone(),
+                    dst_filename,
+                    file_block,
+                });
+            }
+            continue;
+        }
+        let candidates =
+            counterpart_filenames(same_files, &dst_filename).ok_or(SkipReason::NoSourceFile)?;
+
+        // first candidate that is still in the source and has the data
+        let mut found = None;
+        let mut mapping_stale = true;
+        for src_filename in candidates {
+            let pieces = match file_block_to_pieces(src, src_filename, &file_block) {
+                Ok(pieces) => pieces,
+                Err(e) => {
+                    warn!("Stale mapping {} -> {}: {}", dst_filename, src_filename, e);
+                    continue;
+                }
+            };
+            mapping_stale = false;
+            if src.file_data_is_in_file(src_filename)
+                && pieces.iter().all(|piece| src.piece_is_downloaded(piece))
+            {
+                found = Some((src_filename.clone(), pieces));
+                break;
+            }
+            debug!("Skipping unavailable pieces: {:?}", pieces);
+        }
+        let (src_filename, pieces) = match found {
+            Some(found) => found,
+            None if mapping_stale => return Err(SkipReason::MappingStale),
+            None => return Err(SkipReason::Unavailable),
+        };
+
+        src_pieces.extend(pieces);
+        segments.push(Segment {
+            src_filename,
+            dst_filename,
+            file_block,
+        });
+    }
+    if src_pieces.is_empty() {
+        return Err(SkipReason::NoSourceFile);
+    }
+
+    if pieces_from_donor_only {
+        // only a single donor piece with the very same hash, no data built from several pieces
+        // When trusting the donor state, its hashes are not known: a single aligned piece of the same size is enough,
+        // the data read is still checked against the destination hash before writing
+        src_pieces.sort_by_key(|piece| piece.idx);
+        src_pieces.dedup_by_key(|piece| piece.idx);
+        let is_same_piece = match src_pieces.as_slice() {
+            [src_piece] => {
+                src_piece.piece_size == dst.piece_size
+                    && (trust_donor_state
+                        || src.pieces_hashes.get(src_piece.idx)
+                            == dst.pieces_hashes.get(dst_piece_idx))
+            }
+            _ => false,
+        };
+        if !is_same_piece {
+            debug!("Not an identical donor piece: {:?}", &src_pieces);
+            return Err(SkipReason::NotIdentical);
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Whether the segments of a destination piece are exactly a source piece with the same known hash: same piece size,
+/// and the same ranges of the same files. The data is then known to match without being hashed, as long as the source
+/// piece on disk is still the one that was downloaded
+fn is_identical_piece(
+    src: &Torrent,
+    dst: &Torrent,
+    segments: &[Segment],
+    dst_piece_idx: usize,
+) -> bool {
+    let Some(first) = segments.first() else {
+        return false;
+    };
+    let src_pieces =
+        file_block_to_pieces(src, &first.src_filename, &first.file_block).unwrap_or_default();
+    let [src_piece] = src_pieces.as_slice() else {
+        return false;
+    };
+    // hashes of different versions never compare equal
+    src.piece_size == dst.piece_size
+        && src.pieces_states.get(src_piece.idx) == Some(&PieceState::Downloaded)
+        && src.pieces_hashes.get(src_piece.idx).is_some_and(|hash| {
+            hash.is_known() && dst.pieces_hashes.get(dst_piece_idx) == Some(hash)
+        })
+        && piece_segments(src, src_piece.idx)
+            == segments
+                .iter()
+                .map(|s| (s.src_filename.clone(), s.file_block))
+                .collect::<Vec<_>>()
+}
+
+/// User script deciding which pieces are restored, and which donors are used first
+///
+/// `fn restore(piece)` gets a map with `src_hash`, `dst_hash`, `piece` (index), `size`, `src_files` and `dst_files`,
+/// and returns `false` to leave the piece alone. `fn donor_rank(src_hash, dst_hash)` returns a number, donors with the
+/// lowest rank are used first. Both functions are optional
+#[cfg(feature = "script")]
+pub struct PieceScript {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+}
+
+#[cfg(feature = "script")]
+impl PieceScript {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let engine = rhai::Engine::new();
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| format!("Can't load script {}: {}", path, e))?;
+
+        Ok(PieceScript { engine, ast })
+    }
+
+    fn has_fn(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name)
+    }
+
+    /// Whether the piece should be restored. Pieces are left alone if the script fails
+    fn restore(
+        &self,
+        src_hash: &str,
+        dst_hash: &str,
+        piece_idx: usize,
+        segments: &[Segment],
+    ) -> bool {
+        if !self.has_fn("restore") {
+            return true;
+        }
+
+        let filenames = |filename: fn(&Segment) -> &String| -> rhai::Array {
+            segments
+                .iter()
+                .map(|s| filename(s).clone().into())
+                .collect()
+        };
+        let mut piece = rhai::Map::new();
+        piece.insert("src_hash".into(), src_hash.to_owned().into());
+        piece.insert("dst_hash".into(), dst_hash.to_owned().into());
+        piece.insert("piece".into(), (piece_idx as i64).into());
+        piece.insert(
+            "size".into(),
+            (segments.iter().map(|s| s.file_block.size).sum::<u64>() as i64).into(),
+        );
+        piece.insert("src_files".into(), filenames(|s| &s.src_filename).into());
+        piece.insert("dst_files".into(), filenames(|s| &s.dst_filename).into());
+
+        self.engine
+            .call_fn::<bool>(&mut rhai::Scope::new(), &self.ast, "restore", (piece,))
+            .unwrap_or_else(|e| {
+                error!("Script failed on piece {}: {}", piece_idx, e);
+                false
+            })
+    }
+
+    /// Order of a donor, lowest first. 0 if the script doesn't say or fails
+    fn donor_rank(&self, src_hash: &str, dst_hash: &str) -> i64 {
+        if !self.has_fn("donor_rank") {
+            return 0;
+        }
+
+        self.engine
+            .call_fn::<i64>(
+                &mut rhai::Scope::new(),
+                &self.ast,
+                "donor_rank",
+                (src_hash.to_owned(), dst_hash.to_owned()),
+            )
+            .unwrap_or_else(|e| {
+                error!(
+                    "Script failed on donor {} for {}: {}",
+                    src_hash, dst_hash, e
+                );
+                0
+            })
+    }
+}
+
+#[cfg(feature = "script")]
+impl std::fmt::Debug for PieceScript {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PieceScript").finish_non_exhaustive()
+    }
+}
+
+/// A `Segment` with the paths of its files
+#[derive(Debug, Clone)]
+struct PlannedSegment {
+    src_path: String,
+    dst_path: String,
+    segment: Segment,
+}
+
+/// Source ranges to read, and the destination piece they restore
+#[derive(Debug, Clone)]
+struct PlannedRead {
+    dst_piece_idx: usize,
+    /// In piece order
+    segments: Vec<PlannedSegment>,
+    hash: PieceHash,
+    /// Size the hash of the destination piece is computed for, see `Torrent::piece_hash_size`: v2 hashes depend on it
+    piece_size: u64,
+    /// The source data is a whole downloaded piece with the same hash, see `is_identical_piece`: it is copied without
+    /// being hashed first, with `Options::trust_identical_pieces`
+    identical: bool,
+}
+impl PlannedRead {
+    /// Hasher for the data of the piece, with the algorithm of the destination
+    fn hasher(&self) -> PieceHasher {
+        self.hash.hasher(self.piece_size)
+    }
+
+    /// Size of the piece
+    fn size(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|s| s.segment.file_block.size)
+            .sum()
+    }
+
+    fn src_blocks(&self) -> Vec<(&str, FileBlock)> {
+        self.segments
+            .iter()
+            .map(|s| (s.src_path.as_str(), s.segment.file_block))
+            .collect()
+    }
+
+    fn dst_blocks(&self) -> Vec<(&str, FileBlock)> {
+        self.segments
+            .iter()
+            .map(|s| (s.dst_path.as_str(), s.segment.file_block))
+            .collect()
+    }
+
+    /// Hash the source ranges where they are stored, `None` if the store can't
+    fn hash_in_place(&self, store: &dyn PieceStore) -> Option<std::io::Result<SourceData>> {
+        let mut hasher = self.hasher();
+        for (path, file_block) in self.src_blocks() {
+            if let Err(e) = store.hash_in_place(path, file_block, &mut hasher)? {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(SourceData::Hashed(hasher.finalize())))
+    }
+
+    /// Read the source ranges
+    ///
+    /// Pieces bigger than `max_buffer` are only hashed, they will be copied chunk by chunk if the hash matches. So
+    /// are pieces that the store hashes in place
+    fn read(&self, store: &dyn PieceStore, max_buffer: u64) -> std::io::Result<SourceData> {
+        if let Some(data) = self.hash_in_place(store) {
+            return data;
+        }
+        if self.size() > max_buffer {
+            let hash = hash_blocks_chunked(store, &self.src_blocks(), self.hasher(), max_buffer)?;
+            Ok(SourceData::Hashed(hash))
+        } else {
+            let mut data = Vec::with_capacity(self.size() as usize);
+            for (path, file_block) in self.src_blocks() {
+                data.extend(store.read_block(path, file_block)?);
+            }
+            Ok(SourceData::InMemory(data))
+        }
+    }
+
+    /// Read a batch of pieces in a background task
+    async fn spawn_batch(
+        batch: &[PlannedRead],
+        file_pool: &FileHandlePool,
+        store: &Arc<dyn PieceStore>,
+        max_buffer: u64,
+    ) -> tokio::task::JoinHandle<Vec<std::io::Result<SourceData>>> {
+        let batch = batch.to_vec();
+        let store = store.clone();
+        let permit = file_pool.acquire().await;
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            // nothing is copied to hash mapped files, reading pieces together would only copy more
+            if let Some(hashed) = batch
+                .iter()
+                .map(|read| match read.identical {
+                    true => Some(Ok(SourceData::Hashed(read.hash))),
+                    false => read.hash_in_place(store.as_ref()),
+                })
+                .collect::<Option<Vec<_>>>()
+            {
+                return hashed;
+            }
+            Self::read_batch(&batch, store.as_ref(), max_buffer)
+        })
+    }
+
+    /// Read pieces with a single call to the store, see `read_coalesced`. Pieces bigger than `max_buffer` are only
+    /// hashed as usual, identical pieces are not read: they are copied later
+    fn read_batch(
+        batch: &[PlannedRead],
+        store: &dyn PieceStore,
+        max_buffer: u64,
+    ) -> Vec<std::io::Result<SourceData>> {
+        let blocks: Vec<(&str, FileBlock)> = batch
+            .iter()
+            .filter(|read| !read.identical && read.size() <= max_buffer)
+            .flat_map(|read| read.src_blocks())
+            .collect();
+        let mut blocks_data = read_coalesced(store, &blocks).into_iter();
+
+        batch
+            .iter()
+            .map(|read| {
+                if read.identical {
+                    return Ok(SourceData::Hashed(read.hash));
+                }
+                if read.size() > max_buffer {
+                    return read.read(store, max_buffer);
+                }
+                // every block is consumed, even after an error
+                let parts: Vec<_> = blocks_data.by_ref().take(read.segments.len()).collect();
+                let mut data = Vec::with_capacity(read.size() as usize);
+                for part in parts {
+                    data.extend(part?);
+                }
+                Ok(SourceData::InMemory(data))
+            })
+            .collect()
+    }
+
+    /// Write a batch of pieces in a background task, see `write_batch`, then hash what was written. For each piece,
+    /// whether the destination has its hash, or the error writing it
+    async fn spawn_write_batch(
+        pieces: Vec<(PlannedRead, SourceData)>,
+        file_pool: &FileHandlePool,
+        store: &Arc<dyn PieceStore>,
+        max_buffer: u64,
+    ) -> tokio::task::JoinHandle<std::io::Result<Vec<std::io::Result<bool>>>> {
+        let store = store.clone();
+        let permit = file_pool.acquire().await;
+
+        // throttled stores sleep, which must not happen on the async workers
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let pieces: Vec<_> = pieces.iter().map(|(read, data)| (read, data)).collect();
+            let written = Self::write_batch(&pieces, store.as_ref(), max_buffer);
+            pieces
+                .iter()
+                .zip(written)
+                .map(|((planned_read, _), written)| match written {
+                    Err(e) => Ok(Err(e)),
+                    // identical pieces were not hashed before writing, they are checked here like the others
+                    Ok(()) => blocks_have_hash(
+                        store.as_ref(),
+                        &planned_read.dst_blocks(),
+                        &planned_read.hash,
+                        planned_read.piece_size,
+                        max_buffer,
+                    )
+                    .map(Ok),
+                })
+                .collect()
+        })
+    }
+
+    /// Write pieces to the destination files, from the data read or copied from the source files when it was too big
+    /// to be read. The data read is written with a single call to the store
+    fn write_batch(
+        pieces: &[(&PlannedRead, &SourceData)],
+        store: &dyn PieceStore,
+        max_buffer: u64,
+    ) -> Vec<std::io::Result<()>> {
+        let mut blocks = Vec::new();
+        for (planned_read, source_data) in pieces {
+            if let SourceData::InMemory(data) = source_data {
+                let mut start = 0;
+                for s in &planned_read.segments {
+                    let file_block = s.segment.file_block;
+                    let end = start + file_block.size as usize;
+                    blocks.push((s.dst_path.as_str(), file_block, &data[start..end]));
+                    start = end;
+                }
+            }
+        }
+        let mut written = store.write_blocks(&blocks).into_iter();
+
+        pieces
+            .iter()
+            .map(|(planned_read, source_data)| match source_data {
+                SourceData::InMemory(_) => {
+                    // every segment is consumed, even after an error
+                    let segments: Vec<_> =
+                        written.by_ref().take(planned_read.segments.len()).collect();
+                    segments.into_iter().collect()
+                }
+                SourceData::Hashed(_) => planned_read.segments.iter().try_for_each(|s| {
+                    copy_block_chunked(
+                        store,
+                        &s.src_path,
+                        &s.dst_path,
+                        s.segment.file_block,
+                        max_buffer,
+                    )
+                }),
+            })
+            .collect()
+    }
+}
+
+/// Read blocks, those that follow each other in a file as a single range, e.g. consecutive pieces of a big file:
+/// sequential reads are much faster than one read per piece on spinning disks. Result of each block
+///
+/// When the read of a range fails, its blocks are read one by one, so that an error only affects its own pieces
+fn read_coalesced(
+    store: &dyn PieceStore,
+    blocks: &[(&str, FileBlock)],
+) -> Vec<std::io::Result<Vec<u8>>> {
+    // path, range, and the blocks that it covers
+    let mut ranges: Vec<(&str, FileBlock, Range<usize>)> = Vec::new();
+    for (i, &(path, file_block)) in blocks.iter().enumerate() {
+        match ranges.last_mut() {
+            Some((range_path, range, covered))
+                if *range_path == path && range.offset + range.size == file_block.offset =>
+            {
+                range.size += file_block.size;
+                covered.end = i + 1;
+            }
+            _ => ranges.push((path, file_block, i..i + 1)),
+        }
+    }
+    let ranges_data = store.read_blocks(
+        &ranges
+            .iter()
+            .map(|&(path, range, _)| (path, range))
+            .collect::<Vec<_>>(),
+    );
+
+    let mut data = Vec::with_capacity(blocks.len());
+    for ((_, range, covered), range_data) in ranges.into_iter().zip(ranges_data) {
+        match range_data {
+            Ok(range_data) if covered.len() == 1 => data.push(Ok(range_data)),
+            Ok(range_data) => data.extend(blocks[covered].iter().map(|(_, file_block)| {
+                let start = (file_block.offset - range.offset) as usize;
+                Ok(range_data[start..start + file_block.size as usize].to_vec())
+            })),
+            Err(e) if covered.len() == 1 => data.push(Err(e)),
+            Err(_) => data.extend(store.read_blocks(&blocks[covered])),
+        }
+    }
+    data
+}
+
+/// Pieces are read by batches of up to this size, so that small pieces don't cost a task and a file opening each
+const BATCH_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Split consecutive planned reads in batches of up to `BATCH_SIZE` bytes, or `max_buffer` if lower. Bigger pieces
+/// are alone in their batch
+fn batches(planned_reads: &[PlannedRead], max_buffer: u64) -> Vec<&[PlannedRead]> {
+    let batch_size = BATCH_SIZE.min(max_buffer);
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (i, planned_read) in planned_reads.iter().enumerate() {
+        if i > start && size + planned_read.size() > batch_size {
+            batches.push(&planned_reads[start..i]);
+            start = i;
+            size = 0;
+        }
+        size += planned_read.size();
+    }
+    if start < planned_reads.len() {
+        batches.push(&planned_reads[start..]);
+    }
+
+    batches
+}
+
+/// Part of the file `dst_filename` of `file_size` bytes that the planned reads would overwrite
+fn overwrite_fraction(planned_reads: &[PlannedRead], dst_filename: &str, file_size: u64) -> f64 {
+    if file_size == 0 {
+        return 0.;
+    }
+    let bytes: u64 = planned_reads
+        .iter()
+        .flat_map(|read| &read.segments)
+        .filter(|s| s.segment.dst_filename == dst_filename)
+        .map(|s| s.segment.file_block.size)
+        .sum();
+
+    bytes as f64 / file_size as f64
+}
+
+/// Data read for a `PlannedRead`
+enum SourceData {
+    /// The whole source block
+    InMemory(Vec<u8>),
+    /// Only the hash of the destination block, it is too big to be kept in memory
+    Hashed(PieceHash),
+}
+
+/// What to do with a piece read for a `PlannedRead`, once its data is checked
+enum PieceStep {
+    ReadFailed(std::io::Error),
+    HashMismatch,
+    /// The destination already has the data
+    AlreadyCorrect,
+    /// Would be written, in dry run
+    DryRun,
+    /// Written with the other pieces of its batch
+    Write,
+}
+
+/// How a file match was found, from the least to the most confident
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum MatchSource {
+    /// Files have the same size
+    Size,
+    /// Files have the same merkle root, they are identical
+    MerkleRoot,
+}
+
+impl std::fmt::Display for MatchSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchSource::Size => write!(f, "size"),
+            MatchSource::MerkleRoot => write!(f, "merkle root"),
+        }
+    }
+}
+
+/// Files from the source and destination torrents that are probably identical
+#[derive(Debug, Clone)]
+struct FileMatch {
+    src_files: Vec<String>,
+    dst_files: Vec<String>,
+    size: u64,
+    /// Most confident strategy that matched the files
+    match_source: MatchSource,
+}
+
+/// Leave out the destination files that are not wanted ("do not download"): they are not repaired, they may not even
+/// be allocated. Matches left without any destination file are removed
+fn retain_wanted_files(same_files: &mut Vec<FileMatch>, dst: &Torrent) {
+    same_files.retain_mut(|same_file| {
+        let (wanted, unwanted): (Vec<String>, Vec<String>) = same_file
+            .dst_files
+            .drain(..)
+            .partition(|name| dst.file_data_is_in_file(name));
+        if !unwanted.is_empty() {
+            info!("Skipping {:?}: not wanted", unwanted);
+        }
+        same_file.dst_files = wanted;
+        !same_file.dst_files.is_empty()
+    });
+}
+
+/// Leave out the destination files that `Options::include` and `Options::exclude` don't select, like
+/// `retain_wanted_files`
+fn retain_selected_files(same_files: &mut Vec<FileMatch>, options: &Options) {
+    if options.include.is_empty() && options.exclude.is_empty() {
+        return;
+    }
+    let is_selected = |name: &str| {
+        (options.include.is_empty() || options.include.iter().any(|f| f.is_match(name)))
+            && !options.exclude.iter().any(|f| f.is_match(name))
+    };
+    same_files.retain_mut(|same_file| {
+        let (selected, skipped): (Vec<String>, Vec<String>) = same_file
+            .dst_files
+            .drain(..)
+            .partition(|name| is_selected(name));
+        if !skipped.is_empty() {
+            info!("Skipping {:?}: filtered out", skipped);
+        }
+        same_file.dst_files = selected;
+        !same_file.dst_files.is_empty()
+    });
+}
+
+fn find_same_size_files(t1: &Torrent, t2: &Torrent) -> Vec<FileMatch> {
+    let mut t1_files: HashMap<u64, Vec<String>> = HashMap::new();
+    // padding files all have the same few sizes, and are zeros
+    for f in t1.content.iter().filter(|f| !is_padding_file(&f.name)) {
+        let size = f.size;
+        let name = f.name.clone();
+
+        t1_files.entry(size).or_default().push(name);
+    }
+    let mut t2_files: HashMap<u64, Vec<String>> = HashMap::new();
+    for f in t2.content.iter().filter(|f| !is_padding_file(&f.name)) {
+        let size = f.size;
+        let name = f.name.clone();
+
+        t2_files.entry(size).or_default().push(name);
+    }
+
+    let t1_keys: HashSet<u64> = t1_files.keys().copied().collect();
+    let t2_keys: HashSet<u64> = t2_files.keys().copied().collect();
+
+    // sorted, so that the same files are matched on every run
+    let mut common_files: Vec<FileMatch> = Vec::new();
+    for common in t1_keys.intersection(&t2_keys).sorted() {
+        let a: Vec<String> = t1_files
+            .get(common)
+            .unwrap()
+            .iter()
+            .cloned()
+            .sorted()
+            .collect();
+        let b: Vec<String> = t2_files
+            .get(common)
+            .unwrap()
+            .iter()
+            .cloned()
+            .sorted()
+            .collect();
+
+        // files whose roots are known on both sides are identical if their roots are, and different otherwise
+        let b_roots: HashSet<&[u8; 32]> = b.iter().filter_map(|f| t2.pieces_roots.get(f)).collect();
+        let same_roots: BTreeSet<&[u8; 32]> = a
+            .iter()
+            .filter_map(|f| t1.pieces_roots.get(f))
+            .filter(|root| b_roots.contains(root))
+            .collect();
+        for root in &same_roots {
+            common_files.push(FileMatch {
+                src_files: a
+                    .iter()
+                    .filter(|f| t1.pieces_roots.get(*f) == Some(root))
+                    .cloned()
+                    .collect(),
+                dst_files: b
+                    .iter()
+                    .filter(|f| t2.pieces_roots.get(*f) == Some(root))
+                    .cloned()
+                    .collect(),
+                size: *common,
+                match_source: MatchSource::MerkleRoot,
+            });
+        }
+
+        let not_matched_by_root = |torrent: &Torrent, f: &String| {
+            torrent
+                .pieces_roots
+                .get(f)
+                .is_none_or(|root| !same_roots.contains(root))
+        };
+        let a: Vec<String> = a
+            .into_iter()
+            .filter(|f| not_matched_by_root(t1, f))
+            .collect();
+        let b: Vec<String> = b
+            .into_iter()
+            .filter(|f| not_matched_by_root(t2, f))
+            .collect();
+        let all_roots_known = a.iter().all(|f| t1.pieces_roots.contains_key(f))
+            && b.iter().all(|f| t2.pieces_roots.contains_key(f));
+        if a.is_empty() || b.is_empty() || all_roots_known {
+            continue;
+        }
+
+        common_files.push(FileMatch {
+            src_files: a,
+            dst_files: b,
+            size: *common,
+            match_source: MatchSource::Size,
+        });
+    }
+
+    common_files
+}
+
+/// Order matched files so that the most valuable ones are restored first. Pieces of a file are always restored in order
+fn sort_by_priority(same_files: &mut [FileMatch], dst: &Torrent, order: PriorityOrder) {
+    let dst_file = |same_file: &FileMatch| {
+        dst.content
+            .iter()
+            .find(|f| f.name == same_file.dst_files[0])
+            .map(|f| (f.priority, f.index))
+            .unwrap_or((Priority::Normal, u64::MAX))
+    };
+
+    match order {
+        PriorityOrder::HighPriorityFilesFirst => {
+            same_files.sort_by_key(|same_file| std::cmp::Reverse(dst_file(same_file).0))
+        }
+        PriorityOrder::Sequential => same_files.sort_by_key(|same_file| dst_file(same_file).1),
+        PriorityOrder::LargestFileFirst => {
+            same_files.sort_by_key(|same_file| std::cmp::Reverse(same_file.size))
+        }
+    }
+}
+
+fn get_missing_pieces(torrent: &Torrent, path: &str) -> Vec<usize> {
+    let piece_range = torrent.piece_range_for_file(path).expect("File not found");
+
+    piece_range
+        .filter(|&idx| torrent.pieces_states.get(idx) != Some(&PieceState::Downloaded))
+        .collect()
+}
+
+/// Missing pieces of `dst` whose hash is the hash of a downloaded piece of `src`
+///
+/// No data is read, so this only finds pieces that are identical in both torrents, i.e. same piece size and same alignment
+fn find_pieces_by_hash(src: &Torrent, dst: &Torrent) -> Vec<usize> {
+    let available_hashes: HashSet<&PieceHash> = src
+        .pieces_hashes
+        .iter()
+        .zip(&src.pieces_states)
+        .filter(|(_, state)| state == &&PieceState::Downloaded)
+        .map(|(hash, _)| hash)
+        .collect();
+
+    dst.pieces_hashes
+        .iter()
+        .zip(&dst.pieces_states)
+        .enumerate()
+        .filter(|(_, (hash, state))| {
+            state != &&PieceState::Downloaded && hash.is_known() && available_hashes.contains(hash)
+        })
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+/// Log, for each torrent, the missing pieces that no torrent has, grouped by file
+///
+/// Pieces are looked up by hash, like `--hash-only`: a piece that could be rebuilt from differently aligned pieces
+/// is still reported as missing
+fn report_missing_everywhere(torrents: &[Torrent]) {
+    let available_hashes: HashSet<&PieceHash> = torrents
+        .iter()
+        .flat_map(|t| t.pieces_hashes.iter().zip(&t.pieces_states))
+        .filter(|(_, state)| state == &&PieceState::Downloaded)
+        .map(|(hash, _)| hash)
+        .collect();
+
+    for torrent in torrents {
+        let missing: HashSet<usize> = torrent
+            .pieces_hashes
+            .iter()
+            .zip(&torrent.pieces_states)
+            .enumerate()
+            .filter(|(_, (hash, state))| {
+                state != &&PieceState::Downloaded && !available_hashes.contains(hash)
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+
+        info!(
+            "{}: {} pieces are missing everywhere",
+            torrent.hash,
+            missing.len()
+        );
+        for f in torrent.content.iter().filter(|f| !is_padding_file(&f.name)) {
+            let Ok(piece_range) = torrent.piece_range_for_file(&f.name) else {
+                continue;
+            };
+            let n_missing = piece_range.filter(|idx| missing.contains(idx)).count();
+            if n_missing > 0 {
+                info!("{:8} {}", n_missing, f.name);
+            }
+        }
+    }
+}
+
+/// Result of hashing a piece on disk, see `verify_pieces`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PieceCheck {
+    Intact,
+    /// The data doesn't have the hash of the piece, or can't be read, e.g. the file doesn't exist
+    Bad,
+    /// The hash of the piece is missing or all zeros
+    Unknown,
+}
+
+/// Hash the data of each piece of `torrent`, read from `paths` by file name
+fn verify_pieces(
+    store: &dyn PieceStore,
+    torrent: &Torrent,
+    paths: &HashMap<String, String>,
+    progress: Option<&ProgressBar>,
+    max_buffer: u64,
+) -> Vec<PieceCheck> {
+    (0..torrent.pieces_states.len())
+        .map(|idx| {
+            let segments = piece_segments(torrent, idx);
+            if let Some(progress) = progress {
+                progress.inc(segments.iter().map(|(_, block)| block.size).sum());
+            }
+            let Some(hash) = torrent.pieces_hashes.get(idx).filter(|h| h.is_known()) else {
+                return PieceCheck::Unknown;
+            };
+            let blocks: Vec<(&str, FileBlock)> = segments
+                .iter()
+                .filter(|(name, _)| torrent.hashes_padding() || !is_padding_file(name))
+                .map(|(name, block)| (paths[name].as_str(), *block))
+                .collect();
+            let hash_size = torrent.piece_hash_size(idx);
+            match blocks_have_hash(store, &blocks, hash, hash_size, max_buffer) {
+                Ok(true) => PieceCheck::Intact,
+                Ok(false) => PieceCheck::Bad,
+                Err(e) => {
+                    debug!("{}: can't read piece {}: {}", torrent.hash, idx, e);
+                    PieceCheck::Bad
+                }
+            }
+        })
+        .collect()
+}
+
+/// Pieces as ranges, e.g. `3-7,9`
+fn format_pieces(pieces: impl IntoIterator<Item = usize>) -> String {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for idx in pieces {
+        match ranges.last_mut() {
+            Some(range) if range.end == idx => range.end += 1,
+            _ => ranges.push(idx..idx + 1),
+        }
+    }
+    ranges
+        .iter()
+        .map(|range| match range.len() {
+            1 => range.start.to_string(),
+            _ => format!("{}-{}", range.start, range.end - 1),
+        })
+        .join(",")
+}
+
+/// Log the pieces that are intact, by file, and how they compare with the piece states of the client. Returns the
+/// number of pieces that the client has, but that fail the check
+fn log_verification(torrent: &Torrent, checks: &[PieceCheck]) -> usize {
+    let count = |check| checks.iter().filter(|&&c| c == check).count();
+    info!(
+        "{}: {}/{} pieces intact, {} bad, {} without a hash",
+        torrent.hash,
+        count(PieceCheck::Intact),
+        checks.len(),
+        count(PieceCheck::Bad),
+        count(PieceCheck::Unknown)
+    );
+    for f in torrent.content.iter().filter(|f| !is_padding_file(&f.name)) {
+        let Ok(piece_range) = torrent.piece_range_for_file(&f.name) else {
+            continue;
+        };
+        let n_pieces = piece_range.len();
+        let bad: Vec<usize> = piece_range
+            .clone()
+            .filter(|&idx| checks[idx] == PieceCheck::Bad)
+            .collect();
+        let intact = piece_range
+            .filter(|&idx| checks[idx] == PieceCheck::Intact)
+            .count();
+        if bad.is_empty() {
+            info!("{:>8}/{} intact  {}", intact, n_pieces, f.name);
+        } else {
+            info!(
+                "{:>8}/{} intact  {}, bad pieces: {}",
+                intact,
+                n_pieces,
+                f.name,
+                format_pieces(bad)
+            );
+        }
+    }
+
+    let states = checks.iter().zip(&torrent.pieces_states).enumerate();
+    let damaged: Vec<usize> = states
+        .clone()
+        .filter(|(_, (check, state))| {
+            **check == PieceCheck::Bad && **state == PieceState::Downloaded
+        })
+        .map(|(idx, _)| idx)
+        .collect();
+    let unseen: Vec<usize> = states
+        .filter(|(_, (check, state))| {
+            **check == PieceCheck::Intact && **state != PieceState::Downloaded
+        })
+        .map(|(idx, _)| idx)
+        .collect();
+    if !damaged.is_empty() {
+        error!(
+            "{}: {} pieces marked as downloaded fail the check: {}",
+            torrent.hash,
+            damaged.len(),
+            format_pieces(damaged.iter().copied())
+        );
+    }
+    if !unseen.is_empty() {
+        info!(
+            "{}: {} pieces not marked as downloaded are intact, a recheck would add them: {}",
+            torrent.hash,
+            unseen.len(),
+            format_pieces(unseen)
+        );
+    }
+
+    damaged.len()
+}
+
+/// Hash-check a torrent on disk, see `Options::verify`
+fn verify_torrent(
+    options: &Options,
+    store: &dyn PieceStore,
+    torrent: &Torrent,
+    preferences: &Preferences,
+) -> usize {
+    let paths: HashMap<String, String> = torrent
+        .content
+        .iter()
+        .map(|f| {
+            let path = get_file_path(preferences, &torrent.properties, &torrent.content, &f.name);
+            (f.name.clone(), options.resolve_path(path))
+        })
+        .collect();
+    let progress = options.progress.as_ref().map(|bars| {
+        let bar = bars.add(ProgressBar::new(torrent.total_size()));
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{prefix} [{bar:30}] {binary_bytes}/{binary_total_bytes} hashed, {binary_bytes_per_sec}, ETA {eta}",
+            )
+            .unwrap()
+            .progress_chars("=> "),
+        );
+        bar.set_prefix(torrent.hash.chars().take(8).collect::<String>());
+        bar
+    });
+
+    let checks = verify_pieces(
+        store,
+        torrent,
+        &paths,
+        progress.as_ref(),
+        options.max_buffer,
+    );
+    if let Some(progress) = progress {
+        progress.finish_and_clear();
+    }
+    log_verification(torrent, &checks)
+}
+
+/// Hash-check the torrents, or the .torrent file of `Options::verify_torrent_file`, and log which pieces are intact
+async fn verify_torrents(
+    api: Option<&dyn TorrentClient>,
+    options: &Options,
+    hashes: &[String],
+) -> Result<RunOutcome, Box<dyn std::error::Error>> {
+    let store = open_store(options)?;
+    let mut damaged = 0;
+    match (&options.verify_torrent_file, &options.verify_data, api) {
+        (Some(path), Some(data_dir), _) => {
+            let torrent = Torrent::from_torrent_file(path, data_dir)?;
+            damaged += verify_torrent(options, store.as_ref(), &torrent, &Preferences::default());
+        }
+        (_, _, Some(api)) => {
+            for hash in hashes {
+                let torrent = Torrent::new(api, hash).await?;
+                let preferences = api.preferences(hash).await?;
+                damaged += verify_torrent(options, store.as_ref(), &torrent, &preferences);
+            }
+        }
+        _ => return Err("Nothing to verify".into()),
+    }
+
+    Ok(match damaged {
+        0 => RunOutcome::Complete,
+        _ => RunOutcome::Damaged,
+    })
+}
+
+/// How the data of a range compares between 2 torrents
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Divergence {
+    Identical,
+    Divergent,
+    /// One of the torrents doesn't have the data
+    Unknown,
+}
+
+/// Ranges whose data is read are compared by blocks of this size, the size of the requests between peers
+const DIVERGENCE_BLOCK_SIZE: u64 = 16 * 1024;
+
+/// Ranges of a file shared by 2 torrents, with how their data compares
+///
+/// Ranges follow the pieces of `dst`. Pieces that are identical in both torrents (same size and alignment) with the
+/// same hash are identical without reading them. Other ranges are read from both files and compared by blocks of
+/// `DIVERGENCE_BLOCK_SIZE`, so that a piece that differs by a few bytes isn't divergent as a whole. Ranges that
+/// can't be read are unknown. Consecutive ranges with the same result are merged
+fn divergence_map(
+    store: &dyn PieceStore,
+    src: &Torrent,
+    src_filename: &str,
+    src_path: &str,
+    dst: &Torrent,
+    dst_filename: &str,
+    dst_path: &str,
+) -> Vec<(FileBlock, Divergence)> {
+    let mut map: Vec<(FileBlock, Divergence)> = Vec::new();
+    let Ok(piece_range) = dst.piece_range_for_file(dst_filename) else {
+        return map;
+    };
+
+    for dst_idx in piece_range {
+        let Some((_, file_block)) = piece_segments(dst, dst_idx)
+            .into_iter()
+            .find(|(name, _)| name == dst_filename)
+        else {
+            continue;
+        };
+        let src_pieces = file_block_to_pieces(src, src_filename, &file_block).unwrap_or_default();
+
+        let available = dst.file_data_is_in_file(dst_filename)
+            && src.file_data_is_in_file(src_filename)
+            && !src_pieces.is_empty()
+            && dst.pieces_states.get(dst_idx) == Some(&PieceState::Downloaded)
+            && src_pieces
+                .iter()
+                .all(|piece| src.piece_is_downloaded(piece));
+        // hashes of different versions can't be compared, data is read
+        let same_version = matches!(
+            (src.pieces_hashes.first(), dst.pieces_hashes.first()),
+            (Some(PieceHash::V1(_)), Some(PieceHash::V1(_)))
+                | (Some(PieceHash::V2(_)), Some(PieceHash::V2(_)))
+        );
+        let same_piece = match src_pieces.as_slice() {
+            [src_piece] if same_version => {
+                piece_segments(src, src_piece.idx) == [(src_filename.to_owned(), file_block)]
+                    && piece_segments(dst, dst_idx) == [(dst_filename.to_owned(), file_block)]
+            }
+            _ => false,
+        };
+
+        let ranges = if !available {
+            vec![(file_block, Divergence::Unknown)]
+        } else if same_piece
+            && src.pieces_hashes.get(src_pieces[0].idx) == dst.pieces_hashes.get(dst_idx)
+        {
+            vec![(file_block, Divergence::Identical)]
+        } else {
+            match (
+                store.read_block(src_path, file_block),
+                store.read_block(dst_path, file_block),
+            ) {
+                (Ok(src_data), Ok(dst_data)) => compare_blocks(file_block, &src_data, &dst_data),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!(
+                        "{} / {}: can't read {}..{}: {}",
+                        src_path,
+                        dst_path,
+                        file_block.offset,
+                        file_block.offset + file_block.size,
+                        e
+                    );
+                    vec![(file_block, Divergence::Unknown)]
+                }
+            }
+        };
+
+        for (block, divergence) in ranges {
+            match map.last_mut() {
+                Some((last_block, last_divergence))
+                    if *last_divergence == divergence
+                        && last_block.offset + last_block.size == block.offset =>
+                {
+                    last_block.size += block.size
+                }
+                _ => map.push((block, divergence)),
+            }
+        }
+    }
+
+    map
+}
+
+/// Identical and divergent blocks of `file_block`, from its data in both files
+fn compare_blocks(
+    file_block: FileBlock,
+    src_data: &[u8],
+    dst_data: &[u8],
+) -> Vec<(FileBlock, Divergence)> {
+    let block_size = DIVERGENCE_BLOCK_SIZE as usize;
+    src_data
+        .chunks(block_size)
+        .zip(dst_data.chunks(block_size))
+        .enumerate()
+        .map(|(idx, (src_block, dst_block))| {
+            let block = FileBlock {
+                offset: file_block.offset + (idx * block_size) as u64,
+                size: src_block.len() as u64,
+            };
+            match src_block == dst_block {
+                true => (block, Divergence::Identical),
+                false => (block, Divergence::Divergent),
+            }
+        })
+        .collect()
+}
+
+/// Print the divergence map of all the files shared by 2 torrents. Nothing is written
+async fn compare_torrents(
+    api: &dyn TorrentClient,
+    options: &Options,
+    store: &dyn PieceStore,
+    src_hash: &str,
+    dst_hash: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let src_preferences = api.preferences(src_hash).await?;
+    let dst_preferences = api.preferences(dst_hash).await?;
+    let src = Torrent::new(api, src_hash).await?;
+    let dst = Torrent::new(api, dst_hash).await?;
+
+    println!("{} / {}", src_hash, dst_hash);
+    for same_file in find_same_size_files(&src, &dst) {
+        let (src_filename, dst_filename) = (&same_file.src_files[0], &same_file.dst_files[0]);
+        let src_path = options.resolve_path(get_file_path(
+            &src_preferences,
+            &src.properties,
+            &src.content,
+            src_filename,
+        ));
+        let dst_path = options.resolve_path(get_file_path(
+            &dst_preferences,
+            &dst.properties,
+            &dst.content,
+            dst_filename,
+        ));
+
+        let map = divergence_map(
+            store,
+            &src,
+            src_filename,
+            &src_path,
+            &dst,
+            dst_filename,
+            &dst_path,
+        );
+
+        println!("  {} / {}", src_filename, dst_filename);
+        for (file_block, divergence) in map {
+            println!(
+                "    {:>14} {:>14} {:?}",
+                file_block.offset,
+                file_block.offset + file_block.size,
+                divergence
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// (donor, destination) indexes of the torrents that have files of the same size, the destination missing pieces
+///
+/// Files are grouped by size across all the torrents, so that torrents that share nothing are never compared
+fn scan_pairs(torrents: &[Torrent], options: &Options) -> BTreeSet<(usize, usize)> {
+    let mut by_size: BTreeMap<u64, BTreeSet<usize>> = BTreeMap::new();
+    for (idx, torrent) in torrents.iter().enumerate() {
+        for f in torrent.content.iter().filter(|f| !is_padding_file(&f.name)) {
+            if options.min_file_size.is_none_or(|min| f.size >= min)
+                && options.max_file_size.is_none_or(|max| f.size <= max)
+            {
+                by_size.entry(f.size).or_default().insert(idx);
+            }
+        }
+    }
+
+    let is_incomplete = |torrent: &Torrent| {
+        torrent
+            .pieces_states
+            .iter()
+            .any(|state| state != &PieceState::Downloaded)
+    };
+    by_size
+        .values()
+        .flat_map(|group| {
+            group
+                .iter()
+                .filter(|&&dst| is_incomplete(&torrents[dst]))
+                .flat_map(move |&dst| {
+                    group
+                        .iter()
+                        .filter(move |&&src| src != dst)
+                        .map(move |&src| (src, dst))
+                })
+        })
+        .collect()
+}
+
+/// Bytes of the missing pieces of `dst` that the matched files of `src` have, as `MergeEngine::plan` would look for them
+///
+/// Only the part of a piece that is in the matched file is counted. Nothing is read, data may still turn out to differ
+fn recoverable_bytes(src: &Torrent, dst: &Torrent, same_files: &[FileMatch]) -> u64 {
+    let mut bytes = 0;
+    for same_file in same_files {
+        let dst_filename = &same_file.dst_files[0];
+        if !dst.file_data_is_in_file(dst_filename) {
+            continue;
+        }
+        for idx in get_missing_pieces(dst, dst_filename) {
+            let Some((_, file_block)) = piece_segments(dst, idx)
+                .into_iter()
+                .find(|(name, _)| name == dst_filename)
+            else {
+                continue;
+            };
+            let available = same_file.src_files.iter().any(|src_filename| {
+                src.file_data_is_in_file(src_filename)
+                    && file_block_to_pieces(src, src_filename, &file_block)
+                        .is_ok_and(|pieces| pieces.iter().all(|p| src.piece_is_downloaded(p)))
+            });
+            if available {
+                bytes += file_block.size;
+            }
+        }
+    }
+
+    bytes
+}
+
+/// Size of the data compared by `--sample-hash`
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Compare the start of the first range of a file that both torrents have
+///
+/// `None` if there is no such range, files can't be told apart without downloading more
+fn sample_is_identical(
+    store: &dyn PieceStore,
+    (src, src_filename, src_path): (&Torrent, &str, &str),
+    (dst, dst_filename, dst_path): (&Torrent, &str, &str),
+) -> std::io::Result<Option<bool>> {
+    if !src.file_data_is_in_file(src_filename) || !dst.file_data_is_in_file(dst_filename) {
+        return Ok(None);
+    }
+    let Ok(piece_range) = dst.piece_range_for_file(dst_filename) else {
+        return Ok(None);
+    };
+
+    for dst_idx in piece_range {
+        if dst.pieces_states.get(dst_idx) != Some(&PieceState::Downloaded) {
+            continue;
+        }
+        let Some((_, file_block)) = piece_segments(dst, dst_idx)
+            .into_iter()
+            .find(|(name, _)| name == dst_filename)
+        else {
+            continue;
+        };
+        let src_has_it = file_block_to_pieces(src, src_filename, &file_block)
+            .is_ok_and(|pieces| pieces.iter().all(|p| src.piece_is_downloaded(p)));
+        if !src_has_it {
+            continue;
+        }
+
+        let sample = FileBlock {
+            offset: file_block.offset,
+            size: file_block.size.min(SAMPLE_SIZE),
+        };
+        return Ok(Some(
+            store.read_block(src_path, sample)? == store.read_block(dst_path, sample)?,
+        ));
+    }
+
+    Ok(None)
+}
+
+/// A donor for an incomplete torrent, found by `scan_torrents`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ScanCandidate {
+    src_hash: String,
+    dst_hash: String,
+    /// Files of the destination matched with files of the donor
+    files: usize,
+    recoverable_bytes: u64,
+}
+
+/// Print the (incomplete torrent, donor) pairs of `hashes` that are worth merging, most recoverable bytes first
+///
+/// Only piece states are fetched, and with `--sample-hash` a sample of each matched file. Nothing is written
+async fn scan_torrents(
+    api: &dyn TorrentClient,
+    options: &Options,
+    hashes: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let infos: HashMap<String, TorrentInfo> = api
+        .torrents(Some(hashes))
+        .await?
+        .into_iter()
+        .filter_map(|t| Some((t.hash.as_deref()?.to_lowercase(), t)))
+        .collect();
+    let is_src = |hash: &str| {
+        infos
+            .get(hash)
+            .is_some_and(|t| options.src_filter.matches(t))
+    };
+    let is_dst = |hash: &str| {
+        infos
+            .get(hash)
+            .is_some_and(|t| options.dst_filter.matches(t))
+    };
+
+    let mut torrents = Vec::new();
+    // torrents that can't be donors or destinations are not fetched
+    for hash in hashes.iter().filter(|hash| is_src(hash) || is_dst(hash)) {
+        match Torrent::fetch(api, hash, false).await {
+            Ok(torrent) => torrents.push(torrent),
+            Err(e) => error!("{}: {}", hash, e),
+        }
+    }
+
+    let groups = match options.group_by {
+        Some(GroupBy::Category) => group_by_category(api, hashes).await?,
+        None => vec![hashes.to_vec()],
+    };
+    let same_group = |a: &String, b: &String| {
+        groups
+            .iter()
+            .any(|group| group.contains(a) && group.contains(b))
+    };
+
+    let store = match options.sample_hash {
+        true => Some(open_store(options)?),
+        false => None,
+    };
+    let mut preferences: HashMap<&str, Preferences> = HashMap::new();
+    let mut candidates = Vec::new();
+    for (src_idx, dst_idx) in scan_pairs(&torrents, options) {
+        let (src, dst) = (&torrents[src_idx], &torrents[dst_idx]);
+        if !same_group(&src.hash, &dst.hash) || !is_src(&src.hash) || !is_dst(&dst.hash) {
+            continue;
+        }
+        let mut same_files = find_same_size_files(src, dst);
+        same_files.retain(|same_file| {
+            options
+                .min_file_size
+                .is_none_or(|min| same_file.size >= min)
+                && options
+                    .max_file_size
+                    .is_none_or(|max| same_file.size <= max)
+        });
+        if !options.include_unwanted {
+            retain_wanted_files(&mut same_files, dst);
+        }
+        retain_selected_files(&mut same_files, options);
+
+        if let Some(store) = &store {
+            for torrent in [src, dst] {
+                if !preferences.contains_key(torrent.hash.as_str()) {
+                    let p = api.preferences(&torrent.hash).await?;
+                    preferences.insert(&torrent.hash, p);
+                }
+            }
+            let path = |torrent: &Torrent, filename: &str| {
+                options.resolve_path(get_file_path(
+                    &preferences[torrent.hash.as_str()],
+                    &torrent.properties,
+                    &torrent.content,
+                    filename,
+                ))
+            };
+            same_files.retain(|same_file| {
+                let (src_filename, dst_filename) =
+                    (&same_file.src_files[0], &same_file.dst_files[0]);
+                match sample_is_identical(
+                    store.as_ref(),
+                    (src, src_filename, &path(src, src_filename)),
+                    (dst, dst_filename, &path(dst, dst_filename)),
+                ) {
+                    Ok(Some(false)) => {
+                        debug!("{} / {}: samples differ", src_filename, dst_filename);
+                        false
+                    }
+                    Ok(_) => true,
+                    Err(e) => {
+                        warn!(
+                            "{} / {}: can't read sample: {}",
+                            src_filename, dst_filename, e
+                        );
+                        true
+                    }
+                }
+            });
+        }
+
+        let recoverable_bytes = recoverable_bytes(src, dst, &same_files);
+        if recoverable_bytes > 0 {
+            candidates.push(ScanCandidate {
+                src_hash: src.hash.clone(),
+                dst_hash: dst.hash.clone(),
+                files: same_files.len(),
+                recoverable_bytes,
+            });
+        }
+    }
+    // stable, ties keep the order of the hashes
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.recoverable_bytes));
+
+    info!("{} merge candidates", candidates.len());
+    let name = |hash: &str| {
+        infos
+            .get(hash)
+            .and_then(|t| t.name.as_deref())
+            .unwrap_or_default()
+    };
+    for c in &candidates {
+        println!(
+            "{:>14} {} <- {} ({} files) {} <- {}",
+            c.recoverable_bytes,
+            c.dst_hash,
+            c.src_hash,
+            c.files,
+            name(&c.dst_hash),
+            name(&c.src_hash)
+        );
+    }
+
+    Ok(())
+}
+
+fn get_file_offset(
+    torrent_content: &[TorrentContent],
+    path: &str,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut offset = 0;
+    let mut found = false;
+    for file in torrent_content.iter() {
+        if file.name == path {
+            found = true;
+            break;
+        }
+        offset += file.size;
+    }
+
+    if !found {
+        return Err(format!("File not found: {:?}", path).into());
+    }
+
+    Ok(offset)
+}
+
+/// Counters of a merge between 2 torrents
+#[derive(Debug, Default, Clone)]
+pub struct MergeReport {
+    pub restored_pieces: u64,
+    pub restored_bytes: u64,
+    pub unavailable_pieces: u64,
+    /// Pieces that could not be read from the donor, e.g. bad sectors
+    pub donor_read_errors: u64,
+    /// Pieces whose matched source files are not in the source anymore
+    pub mapping_stale: u64,
+    pub complete_files: u64,
+    pub failed_writes: u64,
+    pub not_identical_pieces: u64,
+    /// Destination pieces without a usable hash, that can't be checked
+    pub no_expected_hash: u64,
+    pub already_correct_pieces: u64,
+    /// Files skipped because too much of them would be overwritten
+    pub overwrite_limit_files: u64,
+    /// Pieces skipped because an interrupted run already processed them
+    pub resumed_pieces: u64,
+    /// The merge was interrupted by Ctrl-C, the other pieces were not processed
+    pub interrupted: bool,
+    /// Writes that would have been done, in dry run mode
+    patch: Vec<PatchEntry>,
+    /// Destination pieces that are still missing after the merge
+    still_missing: Vec<MissingFile>,
+    /// Ranges of destination files that the donor doesn't have
+    coverage_gaps: Vec<CoverageGap>,
+    /// Outcome of each destination piece, by file
+    files: Vec<FileOutcome>,
+}
+impl MergeReport {
+    /// Counters, and the outcome of each piece by file, for `--output json`
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "restored_pieces": self.restored_pieces,
+            "restored_bytes": self.restored_bytes,
+            "unavailable_pieces": self.unavailable_pieces,
+            "donor_read_errors": self.donor_read_errors,
+            "mapping_stale": self.mapping_stale,
+            "complete_files": self.complete_files,
+            "failed_writes": self.failed_writes,
+            "not_identical_pieces": self.not_identical_pieces,
+            "no_expected_hash": self.no_expected_hash,
+            "already_correct_pieces": self.already_correct_pieces,
+            "overwrite_limit_files": self.overwrite_limit_files,
+            "resumed_pieces": self.resumed_pieces,
+            "interrupted": self.interrupted,
+            "files": self.files.iter().map(FileOutcome::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Add the counters of the execution of one file
+    fn add_file(&mut self, file: MergeReport) {
+        self.restored_pieces += file.restored_pieces;
+        self.restored_bytes += file.restored_bytes;
+        self.donor_read_errors += file.donor_read_errors;
+        self.failed_writes += file.failed_writes;
+        self.already_correct_pieces += file.already_correct_pieces;
+        self.interrupted |= file.interrupted;
+        self.patch.extend(file.patch);
+    }
+
+    fn log(&self, options: &Options) {
+        if options.dry_run {
+            info!("Restorable pieces (dry run): {}", self.restored_pieces);
+        } else {
+            info!("Retored pieces: {}", self.restored_pieces);
+        }
+        info!("Unavailable pieces: {}", self.unavailable_pieces);
+        info!("Donor read errors: {}", self.donor_read_errors);
+        info!("Stale file mappings: {}", self.mapping_stale);
+        info!("Already complete files: {}", self.complete_files);
+        info!("Failed writes: {}", self.failed_writes);
+        if self.no_expected_hash > 0 {
+            warn!("Pieces without expected hash: {}", self.no_expected_hash);
+        }
+        if options.skip_correct_pieces {
+            info!("Already correct pieces: {}", self.already_correct_pieces);
+        }
+        if options.pieces_from_donor_only {
+            info!(
+                "Pieces without identical donor piece: {}",
+                self.not_identical_pieces
+            );
+        }
+        if options.max_overwrite_fraction.is_some() {
+            info!(
+                "Files over the overwrite limit: {}",
+                self.overwrite_limit_files
+            );
+        }
+        if options.state_file.is_some() {
+            info!(
+                "Pieces already processed by a previous run: {}",
+                self.resumed_pieces
+            );
+        }
+        if options.report_unavailable_files.is_some() {
+            for gap in &self.coverage_gaps {
+                info!(
+                    "Not in the donor: {} ({} bytes): {}",
+                    gap.path,
+                    gap.size(),
+                    gap.ranges
+                        .iter()
+                        .map(|r| format!("{}..{}", r.offset, r.offset + r.size))
+                        .join(", ")
+                );
+            }
+        }
+    }
+}
+
+/// Ranges of a destination file that a donor could not provide, because its pieces are not downloaded
+#[derive(Debug, Clone)]
+struct CoverageGap {
+    src_hash: String,
+    dst_hash: String,
+    path: String,
+    /// In file order, consecutive ranges are merged
+    ranges: Vec<FileBlock>,
+}
+impl CoverageGap {
+    fn size(&self) -> u64 {
+        self.ranges.iter().map(|r| r.size).sum()
+    }
+}
+
+/// Group the ranges of unavailable pieces by file, merging consecutive ranges
+fn coverage_gaps(
+    src_hash: &str,
+    dst_hash: &str,
+    ranges: impl IntoIterator<Item = (String, FileBlock)>,
+) -> Vec<CoverageGap> {
+    let mut by_file: BTreeMap<String, Vec<FileBlock>> = BTreeMap::new();
+    for (path, file_block) in ranges {
+        by_file.entry(path).or_default().push(file_block);
+    }
+
+    by_file
+        .into_iter()
+        .map(|(path, mut blocks)| {
+            blocks.sort_by_key(|b| b.offset);
+            let mut ranges: Vec<FileBlock> = Vec::new();
+            for block in blocks {
+                match ranges.last_mut() {
+                    Some(last) if last.offset + last.size >= block.offset => {
+                        last.size = last.size.max(block.offset + block.size - last.offset)
+                    }
+                    _ => ranges.push(block),
+                }
+            }
+            CoverageGap {
+                src_hash: src_hash.to_owned(),
+                dst_hash: dst_hash.to_owned(),
+                path,
+                ranges,
+            }
+        })
+        .collect()
+}
+
+/// Write the coverage gaps of a run as JSON: a list of objects with src_hash, dst_hash, file and the ranges
+fn write_coverage_gaps<'a>(
+    path: &str,
+    gaps: impl IntoIterator<Item = &'a CoverageGap>,
+) -> std::io::Result<()> {
+    let gaps: Vec<serde_json::Value> = gaps
+        .into_iter()
+        .map(|gap| {
+            serde_json::json!({
+                "src_hash": gap.src_hash,
+                "dst_hash": gap.dst_hash,
+                "file": gap.path,
+                "missing_bytes": gap.size(),
+                "ranges": gap
+                    .ranges
+                    .iter()
+                    .map(|r| serde_json::json!({"offset": r.offset, "size": r.size}))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    let mut f = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut f, &gaps)?;
+    writeln!(f)?;
+    f.flush()
+}
+
+/// Plans saved by `--plan-file`: a list of `MergePlan::to_json`
+fn read_plan_file(path: &str) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+    let content =
+        std::fs::read(path).map_err(|e| format!("Can't read plan file {}: {}", path, e))?;
+    match serde_json::from_slice(&content) {
+        Ok(serde_json::Value::Array(plans)) => Ok(plans),
+        _ => Err(format!("Invalid plan file {}: expected a list of plans", path).into()),
+    }
+}
+
+/// A write that would restore a destination piece
+#[derive(Debug, Clone)]
+struct PatchEntry {
+    dst_hash: String,
+    path: String,
+    file_block: FileBlock,
+    /// Expected hash of the destination piece
+    hash: PieceHash,
+}
+
+/// Write the manifest of a dry run: one tab separated line per write, with torrent hash, file, offset, size and piece hash
+fn write_patch(path: &str, entries: &[PatchEntry]) -> std::io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    for entry in entries {
+        writeln!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            entry.dst_hash, entry.path, entry.file_block.offset, entry.file_block.size, entry.hash
+        )?;
+    }
+    f.flush()
+}
+
+/// Missing pieces of a destination file
+#[derive(Debug, Clone)]
+struct MissingFile {
+    dst_hash: String,
+    /// Index of the file in the torrent, as used to set file priorities
+    index: u64,
+    path: String,
+    pieces: Vec<usize>,
+}
+
+/// Write the pieces still missing after a run: one tab separated line per file, with torrent hash, file index, file and comma separated pieces
+fn write_missing<'a>(
+    path: &str,
+    entries: impl IntoIterator<Item = &'a MissingFile>,
+) -> std::io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    for entry in entries {
+        writeln!(
+            f,
+            "{}\t{}\t{}\t{}",
+            entry.dst_hash,
+            entry.index,
+            entry.path,
+            entry.pieces.iter().join(",")
+        )?;
+    }
+    f.flush()
+}
+
+/// Quote a CSV field if needed
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Audit log of `--piece-log`, one CSV line per destination piece that was read from a source
+///
+/// Lines are written as pieces are processed, the log is complete up to the last piece even if the run is interrupted
+struct PieceLog {
+    f: std::sync::Mutex<std::io::LineWriter<File>>,
+}
+
+impl PieceLog {
+    fn create(path: &str) -> std::io::Result<Self> {
+        let mut f = std::io::LineWriter::new(File::create(path)?);
+        writeln!(f, "dst_hash,piece,src_files,src_ranges,hash,outcome,error")?;
+        Ok(PieceLog {
+            f: std::sync::Mutex::new(f),
+        })
+    }
+
+    /// `hash_match` is None when the data could not be read. Source files and their ranges are separated by `;` for
+    /// pieces that span several files
+    fn log(
+        &self,
+        dst_hash: &str,
+        planned_read: &PlannedRead,
+        hash_match: Option<bool>,
+        outcome: Result<(), SkipReason>,
+        error: Option<&dyn std::fmt::Display>,
+    ) {
+        let blocks = planned_read.src_blocks();
+        let line = [
+            dst_hash.to_owned(),
+            planned_read.dst_piece_idx.to_string(),
+            blocks.iter().map(|(path, _)| path).join(";"),
+            blocks
+                .iter()
+                .map(|(_, block)| format!("{}-{}", block.offset, block.offset + block.size))
+                .join(";"),
+            match hash_match {
+                Some(true) => "match",
+                Some(false) => "mismatch",
+                None => "",
+            }
+            .to_owned(),
+            match outcome {
+                Ok(()) => "restored",
+                Err(reason) => reason.name(),
+            }
+            .to_owned(),
+            error.map(|e| e.to_string()).unwrap_or_default(),
+        ]
+        .iter()
+        .map(|field| csv_field(field))
+        .join(",");
+        // the merge goes on without its audit log rather than stopping halfway
+        if let Err(e) = writeln!(self.f.lock().unwrap(), "{}", line) {
+            error!("Can't write to the piece log: {}", e);
+        }
+    }
+}
+
+/// Destination pieces processed by previous runs, by pair, see `Options::state_file`
+///
+/// One JSON line is appended per piece as the merge goes, so an interrupted run leaves the pieces it got to. Only
+/// pieces with a final outcome are recorded: restored, already correct, or not matching the hash. Pieces that could
+/// not be read or written are tried again
+struct Checkpoint {
+    /// Whether each processed piece was restored, by source and destination hash
+    done: HashMap<(String, String), HashMap<usize, bool>>,
+    f: std::sync::Mutex<std::io::LineWriter<File>>,
+}
+
+impl Checkpoint {
+    fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut done: HashMap<(String, String), HashMap<usize, bool>> = HashMap::new();
+        let mut cut_short = false;
+        match std::fs::read_to_string(path) {
+            Ok(state) => {
+                cut_short = !state.is_empty() && !state.ends_with('\n');
+                // the last line may be cut short by the interruption
+                for entry in state
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+                {
+                    let (Some(src_hash), Some(dst_hash), Some(piece)) = (
+                        entry["src_hash"].as_str(),
+                        entry["dst_hash"].as_str(),
+                        entry["piece"].as_u64(),
+                    ) else {
+                        continue;
+                    };
+                    done.entry((src_hash.to_owned(), dst_hash.to_owned()))
+                        .or_default()
+                        .insert(piece as usize, entry["restored"] == true);
+                }
+                let pieces: usize = done.values().map(HashMap::len).sum();
+                info!(
+                    "Resuming from {}: {} pieces already processed",
+                    path, pieces
+                );
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("Can't read state file {}: {}", path, e).into()),
+        }
+
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        if cut_short {
+            writeln!(f)?;
+        }
+        Ok(Checkpoint {
+            done,
+            f: std::sync::Mutex::new(std::io::LineWriter::new(f)),
+        })
+    }
+
+    /// Whether a previous run restored the piece, `None` if it didn't process it
+    fn processed(&self, src_hash: &str, dst_hash: &str, idx: usize) -> Option<bool> {
+        self.done
+            .get(&(src_hash.to_owned(), dst_hash.to_owned()))
+            .and_then(|pieces| pieces.get(&idx))
+            .copied()
+    }
+
+    fn record(&self, src_hash: &str, dst_hash: &str, idx: usize, restored: bool) {
+        let entry = serde_json::json!({
+            "src_hash": src_hash,
+            "dst_hash": dst_hash,
+            "piece": idx,
+            "restored": restored,
+        });
+        // the piece is only processed again by the next run
+        if let Err(e) = writeln!(self.f.lock().unwrap(), "{}", entry) {
+            error!("Can't write to the state file: {}", e);
+        }
+    }
+}
+
+/// How a run ended, see `RunOutcome::exit_code`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// Every piece that a source had was restored, or there was nothing to restore. Also the outcome of the commands
+    /// that don't merge, e.g. `scan`
+    Complete,
+    /// Some pieces were restored, others could not be because of errors, e.g. a donor that can't be read
+    Partial,
+    /// Pieces are missing, and none was restored
+    NothingRestored,
+    /// A torrent has fewer pieces after the recheck than before the merge, with `--verify-after-recheck`, or `verify`
+    /// found pieces that the client has but that fail the hash check
+    Damaged,
+    /// Stopped by Ctrl-C
+    Interrupted,
+}
+
+impl RunOutcome {
+    /// Exit code of the `merge` binary, errors that stop the run (configuration, API) are 4
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunOutcome::Complete => 0,
+            RunOutcome::Partial => 2,
+            RunOutcome::NothingRestored => 3,
+            RunOutcome::Damaged => 5,
+            // as shells report processes killed by SIGINT
+            RunOutcome::Interrupted => 130,
+        }
+    }
+}
+
+/// Totals over all the pairs of a run
+#[derive(Debug, Default, Clone)]
+struct BatchReport {
+    pairs: u64,
+    pairs_restored: u64,
+    pairs_errored: u64,
+    restored_pieces: u64,
+    restored_bytes: u64,
+    donor_read_errors: u64,
+    failed_writes: u64,
+    /// A merge was interrupted, the report is partial
+    interrupted: bool,
+    patch: Vec<PatchEntry>,
+    /// Pieces that no source could restore, by destination hash and file index
+    still_missing: BTreeMap<(String, u64), MissingFile>,
+    coverage_gaps: Vec<CoverageGap>,
+}
+impl BatchReport {
+    /// Add the report of a pair, `None` if the merge failed
+    fn add(&mut self, report: Option<&MergeReport>) {
+        self.pairs += 1;
+        match report {
+            Some(report) => {
+                if report.restored_pieces > 0 {
+                    self.pairs_restored += 1;
+                }
+                self.restored_pieces += report.restored_pieces;
+                self.restored_bytes += report.restored_bytes;
+                self.donor_read_errors += report.donor_read_errors;
+                self.failed_writes += report.failed_writes;
+                self.interrupted |= report.interrupted;
+                self.patch.extend_from_slice(&report.patch);
+                self.coverage_gaps.extend_from_slice(&report.coverage_gaps);
+                for missing in &report.still_missing {
+                    let key = (missing.dst_hash.clone(), missing.index);
+                    match self.still_missing.get_mut(&key) {
+                        // restored by a previous source
+                        Some(previous) => {
+                            previous.pieces.retain(|idx| missing.pieces.contains(idx))
+                        }
+                        None => {
+                            self.still_missing.insert(key, missing.clone());
+                        }
+                    }
+                }
+            }
+            None => self.pairs_errored += 1,
+        }
+    }
+
+    fn log(&self) {
+        info!("Total:");
+        info!("Pairs: {}", self.pairs);
+        info!("Pairs with restored pieces: {}", self.pairs_restored);
+        info!("Pairs with errors: {}", self.pairs_errored);
+        info!("Restored pieces: {}", self.restored_pieces);
+        info!("Restored bytes: {}", self.restored_bytes);
+        info!("Donor read errors: {}", self.donor_read_errors);
+        info!("Failed writes: {}", self.failed_writes);
+        info!(
+            "Still missing pieces: {}",
+            self.still_missing().map(|f| f.pieces.len()).sum::<usize>()
+        );
+    }
+
+    /// Files that still have missing pieces
+    fn still_missing(&self) -> impl Iterator<Item = &MissingFile> {
+        self.still_missing.values().filter(|f| !f.pieces.is_empty())
+    }
+
+    /// Pieces that could not be restored because of an error, as opposed to pieces that no source has
+    fn has_errors(&self) -> bool {
+        self.pairs_errored > 0 || self.donor_read_errors > 0 || self.failed_writes > 0
+    }
+
+    fn outcome(&self) -> RunOutcome {
+        if self.interrupted {
+            RunOutcome::Interrupted
+        } else if self.restored_pieces == 0
+            && (self.has_errors() || self.still_missing().next().is_some())
+        {
+            RunOutcome::NothingRestored
+        } else if self.has_errors() {
+            RunOutcome::Partial
+        } else {
+            RunOutcome::Complete
+        }
+    }
+
+    /// Totals, and `pairs` as given, for `--output json`
+    fn to_json(&self, pairs: Vec<serde_json::Value>) -> serde_json::Value {
+        serde_json::json!({
+            "pairs": pairs,
+            "total": {
+                "pairs": self.pairs,
+                "pairs_restored": self.pairs_restored,
+                "pairs_errored": self.pairs_errored,
+                "restored_pieces": self.restored_pieces,
+                "restored_bytes": self.restored_bytes,
+                "donor_read_errors": self.donor_read_errors,
+                "failed_writes": self.failed_writes,
+                "interrupted": self.interrupted,
+                "still_missing_pieces": self.still_missing().map(|f| f.pieces.len()).sum::<usize>(),
+            },
+        })
+    }
+}
+
+/// Report of a pair for `--output json`, with the error if the merge failed
+fn pair_json(
+    src_hash: &str,
+    dst_hash: &str,
+    result: &Result<MergeReport, Box<dyn std::error::Error>>,
+) -> serde_json::Value {
+    let mut json = match result {
+        Ok(report) => report.to_json(),
+        Err(e) => serde_json::json!({"error": e.to_string()}),
+    };
+    json["src_hash"] = src_hash.into();
+    json["dst_hash"] = dst_hash.into();
+    json
+}
+
+/// Write a JSON report to `path`, or to stdout
+fn write_json_report(path: Option<&str>, report: &serde_json::Value) -> std::io::Result<()> {
+    let mut f: Box<dyn Write> = match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    serde_json::to_writer_pretty(&mut f, report)?;
+    writeln!(f)?;
+    f.flush()
+}
+
+/// Why a destination piece was not restored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum SkipReason {
+    /// The piece belongs to a file that has no match in the source
+    NoSourceFile,
+    /// Source pieces are not downloaded
+    Unavailable,
+    /// None of the matched source files is in the source torrent, e.g. it was renamed
+    MappingStale,
+    /// Not an identical piece in the source, with `--pieces-from-donor-only`
+    NotIdentical,
+    /// The destination piece hash is missing or all zeros, data can't be checked
+    NoExpectedHash,
+    /// Source data could not be read
+    ReadFailed,
+    /// The user script said not to restore the piece
+    #[cfg(feature = "script")]
+    Script,
+    /// Source data doesn't have the expected hash
+    HashMismatch,
+    /// Destination already has the right data
+    AlreadyCorrect,
+    /// Destination could not be written, or did not read back correctly
+    WriteFailed,
+    /// Too much of the file would be overwritten, with `--max-overwrite-fraction`
+    OverwriteLimit,
+    /// An interrupted run already processed the piece, with `--state-file`
+    Resumed,
+}
+
+impl SkipReason {
+    /// Name in reports, e.g. `hash-mismatch`
+    fn name(&self) -> &'static str {
+        match self {
+            SkipReason::NoSourceFile => "no-source-file",
+            SkipReason::Unavailable => "unavailable",
+            SkipReason::MappingStale => "mapping-stale",
+            SkipReason::NotIdentical => "not-identical",
+            SkipReason::NoExpectedHash => "no-expected-hash",
+            SkipReason::ReadFailed => "read-failed",
+            #[cfg(feature = "script")]
+            SkipReason::Script => "script",
+            SkipReason::HashMismatch => "hash-mismatch",
+            SkipReason::AlreadyCorrect => "already-correct",
+            SkipReason::WriteFailed => "write-failed",
+            SkipReason::OverwriteLimit => "overwrite-limit",
+            SkipReason::Resumed => "resumed",
+        }
+    }
+}
+
+/// What happened to the missing pieces of a destination file, in the order they were processed
+#[derive(Debug, Clone)]
+struct FileOutcome {
+    path: String,
+    missing_pieces: usize,
+    /// Bytes restored, or why the piece was not
+    pieces: Vec<(usize, Result<u64, SkipReason>)>,
+}
+
+impl FileOutcome {
+    /// Add an event to the outcomes of its file
+    fn record(files: &mut Vec<FileOutcome>, event: &MergeEvent) {
+        fn file<'f>(files: &'f mut [FileOutcome], path: &str) -> Option<&'f mut FileOutcome> {
+            files.iter_mut().rev().find(|file| file.path == path)
+        }
+        match event {
+            MergeEvent::FileStarted {
+                path,
+                missing_pieces,
+            } => files.push(FileOutcome {
+                path: path.clone(),
+                missing_pieces: *missing_pieces,
+                pieces: Vec::new(),
+            }),
+            MergeEvent::FileFinished { .. } => (),
+            MergeEvent::PieceRestored { path, idx, size } => {
+                if let Some(file) = file(files, path) {
+                    file.pieces.push((*idx, Ok(*size)));
+                }
+            }
+            MergeEvent::PieceSkipped { path, idx, reason } => {
+                if let Some(file) = file(files, path) {
+                    file.pieces.push((*idx, Err(*reason)));
+                }
+            }
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let restored = self.pieces.iter().filter_map(|(_, r)| r.as_ref().ok());
+        serde_json::json!({
+            "file": self.path,
+            "missing_pieces": self.missing_pieces,
+            "restored_pieces": restored.clone().count(),
+            "restored_bytes": restored.sum::<u64>(),
+            "pieces": self.pieces.iter().map(|(idx, outcome)| match outcome {
+                Ok(size) => serde_json::json!({"piece": idx, "outcome": "restored", "bytes": size}),
+                Err(reason) => serde_json::json!({"piece": idx, "outcome": "skipped", "reason": reason.name()}),
+            }).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Progress of a merge, piece by piece
+#[derive(Debug, Clone)]
+enum MergeEvent {
+    FileStarted {
+        path: String,
+        missing_pieces: usize,
+    },
+    FileFinished {
+        path: String,
+    },
+    PieceRestored {
+        path: String,
+        idx: usize,
+        size: u64,
+    },
+    PieceSkipped {
+        path: String,
+        idx: usize,
+        reason: SkipReason,
+    },
+}
+
+impl std::fmt::Display for MergeEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeEvent::FileStarted {
+                path,
+                missing_pieces,
+            } => write!(f, "started {} ({} missing pieces)", path, missing_pieces),
+            MergeEvent::FileFinished { path } => write!(f, "finished {}", path),
+            MergeEvent::PieceRestored { idx, .. } => write!(f, "restored piece {}", idx),
+            MergeEvent::PieceSkipped { idx, reason, .. } => {
+                write!(f, "skipped piece {}: {:?}", idx, reason)
+            }
+        }
+    }
+}
+
+/// Progress of a merge so far, for periodic summaries
+struct MergeStats {
+    dst_hash: String,
+    started: std::time::Instant,
+    processed_pieces: usize,
+    restored_pieces: usize,
+    restored_bytes: u64,
+    /// Files started and not finished yet, several with `jobs`
+    current_files: Vec<String>,
+}
+
+impl MergeStats {
+    fn new(dst_hash: &str) -> Self {
+        MergeStats {
+            dst_hash: dst_hash.to_owned(),
+            started: std::time::Instant::now(),
+            processed_pieces: 0,
+            restored_pieces: 0,
+            restored_bytes: 0,
+            current_files: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, event: &MergeEvent) {
+        match event {
+            MergeEvent::FileStarted { path, .. } => self.current_files.push(path.clone()),
+            MergeEvent::FileFinished { path } => self.current_files.retain(|p| p != path),
+            MergeEvent::PieceRestored { size, .. } => {
+                self.processed_pieces += 1;
+                self.restored_pieces += 1;
+                self.restored_bytes += size;
+            }
+            MergeEvent::PieceSkipped { .. } => self.processed_pieces += 1,
+        }
+    }
+
+    /// Restored bytes per second since the start
+    fn throughput(&self) -> f64 {
+        self.restored_bytes as f64 / self.started.elapsed().as_secs_f64().max(1e-3)
+    }
+
+    fn log(&self) {
+        info!(
+            "{}: {} pieces processed, {} restored, {:.1} MiB/s, {}",
+            self.dst_hash,
+            self.processed_pieces,
+            self.restored_pieces,
+            self.throughput() / (1024. * 1024.),
+            if self.current_files.is_empty() {
+                "between files".to_owned()
+            } else {
+                format!("working on {}", self.current_files.join(", "))
+            }
+        );
+    }
+}
+
+/// Progress bars of a merge: bytes read from the donor, with throughput and ETA, and pieces of the current files
+struct MergeProgress {
+    bars: MultiProgress,
+    total: ProgressBar,
+    /// Bars of the files started and not finished yet, several with `jobs`
+    files: HashMap<String, ProgressBar>,
+    restored_bytes: u64,
+    /// Pieces that will be reported for each file
+    file_pieces: HashMap<String, usize>,
+    /// Size of the pieces that are read, the others are skipped without reading anything
+    piece_sizes: HashMap<usize, u64>,
+}
+
+impl MergeProgress {
+    fn new(bars: &MultiProgress, plan: &MergePlan) -> Self {
+        let total = bars.add(ProgressBar::new(plan.planned_bytes()));
+        total.set_style(
+            ProgressStyle::with_template(
+                "{prefix} [{bar:30}] {binary_bytes}/{binary_total_bytes} read, {binary_bytes_per_sec}, ETA {eta}, {msg}",
+            )
+            .unwrap()
+            .progress_chars("=> "),
+        );
+        total.set_prefix(plan.dst_hash.chars().take(8).collect::<String>());
+        total.set_message("0 B restored");
+        MergeProgress {
+            bars: bars.clone(),
+            total,
+            files: HashMap::new(),
+            restored_bytes: 0,
+            file_pieces: plan
+                .files
+                .iter()
+                .map(|f| {
+                    (
+                        f.dst_filename.clone(),
+                        f.skipped.len() + f.planned_reads.len(),
+                    )
+                })
+                .collect(),
+            piece_sizes: plan
+                .files
+                .iter()
+                .flat_map(|f| &f.planned_reads)
+                .map(|r| (r.dst_piece_idx, r.size()))
+                .collect(),
+        }
+    }
+
+    fn record(&mut self, event: &MergeEvent) {
+        match event {
+            MergeEvent::FileStarted { path, .. } => {
+                let pieces = self.file_pieces.get(path).copied().unwrap_or_default();
+                let file = self.bars.add(ProgressBar::new(pieces as u64));
+                file.set_style(
+                    ProgressStyle::with_template("  [{bar:30}] {pos}/{len} pieces {wide_msg}")
+                        .unwrap()
+                        .progress_chars("=> "),
+                );
+                file.set_message(path.clone());
+                self.files.insert(path.clone(), file);
+            }
+            MergeEvent::FileFinished { path } => {
+                if let Some(file) = self.files.remove(path) {
+                    file.finish_and_clear();
+                }
+            }
+            MergeEvent::PieceRestored { path, idx, size } => {
+                self.restored_bytes += size;
+                self.total
+                    .set_message(format!("{} restored", HumanBytes(self.restored_bytes)));
+                self.piece_done(path, *idx);
+            }
+            MergeEvent::PieceSkipped { path, idx, .. } => self.piece_done(path, *idx),
+        }
+    }
+
+    fn piece_done(&self, path: &str, idx: usize) {
+        if let Some(file) = self.files.get(path) {
+            file.inc(1);
+        }
+        self.total
+            .inc(self.piece_sizes.get(&idx).copied().unwrap_or_default());
+    }
+
+    /// Remove the bars, the next merge gets its own
+    fn finish(self) {
+        for file in self.files.values() {
+            file.finish_and_clear();
+        }
+        self.total.finish_and_clear();
+    }
+}
+
+/// Next tick of `ticker`, never without one
+async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Open the files of a torrent for writing, to fail early before pausing anything
+///
+/// Files that don't exist yet are ignored, they are not written either
+async fn check_writable(
+    api: &dyn TorrentClient,
+    store: &dyn PieceStore,
+    hash: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let preferences = api.preferences(hash).await?;
+    let properties = api.properties(hash).await?;
+    let content = api.contents(hash).await?;
+
+    for f in &content {
+        let path = get_file_path(&preferences, &properties, &content, &f.name);
+        if let Err(e) = store.check_writable(&path) {
+            return Err(format!("Can't write to {:?}: {}", path, e).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// File listing the torrents paused by the current run, removed once they are resumed
+fn paused_torrents_path() -> std::path::PathBuf {
+    let state_dir = match (std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME")) {
+        (Some(state_home), _) => std::path::PathBuf::from(state_home),
+        (None, Some(home)) => std::path::Path::new(&home).join(".local/state"),
+        (None, None) => std::env::temp_dir(),
+    };
+
+    state_dir.join("qbittorrent-merger-paused")
+}
+
+fn read_paused_torrents() -> std::io::Result<Vec<String>> {
+    match std::fs::read_to_string(paused_torrents_path()) {
+        Ok(content) => Ok(content.lines().map(str::to_owned).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_paused_torrents(hashes: &[String]) -> std::io::Result<()> {
+    let path = paused_torrents_path();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    for hash in hashes {
+        writeln!(f, "{}", hash)?;
+    }
+    Ok(())
+}
+
+/// Remove torrents from the list of paused torrents, once they are resumed
+fn forget_paused_torrents(hashes: &[String]) -> std::io::Result<()> {
+    let paused: Vec<String> = read_paused_torrents()?
+        .into_iter()
+        .filter(|hash| !hashes.contains(hash))
+        .collect();
+    std::fs::remove_file(paused_torrents_path()).or_else(ignore_not_found)?;
+    match paused.is_empty() {
+        true => Ok(()),
+        false => write_paused_torrents(&paused),
+    }
+}
+
+fn ignore_not_found(e: std::io::Error) -> std::io::Result<()> {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    }
+}
+
+/// Marker recording the merges done into a destination torrent: `<dir>/<dst_hash>.merged.json`
+fn marker_path(dir: &str, dst_hash: &str) -> std::path::PathBuf {
+    std::path::Path::new(dir).join(format!("{}.merged.json", dst_hash))
+}
+
+/// Content of a marker, an empty object if there is none yet
+fn read_marker(path: &std::path::Path) -> std::io::Result<serde_json::Value> {
+    match std::fs::read(path) {
+        Ok(content) => serde_json::from_slice(&content)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(serde_json::json!({})),
+        Err(e) => Err(e),
+    }
+}
+
+/// The source was already merged into the destination, and got no new piece since
+fn marker_is_up_to_date(marker: &serde_json::Value, src_hash: &str, src_pieces_have: i64) -> bool {
+    marker["sources"][src_hash]["source_pieces_have"].as_i64() == Some(src_pieces_have)
+}
+
+/// Record a merge in the marker of the destination, previous sources are kept
+fn update_marker(
+    dir: &str,
+    dst_hash: &str,
+    src_hash: &str,
+    src_pieces_have: i64,
+    report: &MergeReport,
+) -> std::io::Result<()> {
+    let path = marker_path(dir, dst_hash);
+    let mut marker = read_marker(&path)?;
+    let merged_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    marker["dst_hash"] = dst_hash.into();
+    marker["merged_at"] = merged_at.into();
+    if !marker["sources"].is_object() {
+        marker["sources"] = serde_json::json!({});
+    }
+    marker["sources"][src_hash] = serde_json::json!({
+        "merged_at": merged_at,
+        "source_pieces_have": src_pieces_have,
+        "restored_pieces": report.restored_pieces,
+        "restored_bytes": report.restored_bytes,
+        "unavailable_pieces": report.unavailable_pieces,
+        "failed_writes": report.failed_writes,
+    });
+
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(path, serde_json::to_vec_pretty(&marker)?)
+}
+
+/// Split torrents by category, only torrents of the same category are merged together
+async fn group_by_category(
+    api: &dyn TorrentClient,
+    hashes: &[String],
+) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+    let torrents = api.torrents(Some(hashes)).await?;
+
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for hash in hashes {
+        let category = torrents
+            .iter()
+            .find(|t| t.hash.as_deref().map(str::to_lowercase).as_deref() == Some(hash.as_str()))
+            .and_then(|t| t.category.clone())
+            .unwrap_or_default();
+        groups.entry(category).or_default().push(hash.clone());
+    }
+
+    for (category, group) in &groups {
+        info!("category {:?}: {} torrents", category, group.len());
+    }
+
+    Ok(groups
+        .into_values()
+        .filter(|group| group.len() >= 2)
+        .collect())
+}
+
+/// (source, destination) pairs to merge, both ways for each couple of torrents of a group
+///
+/// The order doesn't depend on the order of the torrents: groups are sorted, and hashes in a group are sorted.
+/// When several donors have a piece, it is restored from the donor with the lowest hash, and then from the
+/// first matching file by name, see `find_same_size_files`
+fn donor_pairs(groups: &[Vec<String>]) -> Vec<(String, String)> {
+    groups
+        .iter()
+        .map(|group| group.iter().sorted().collect::<Vec<_>>())
+        .sorted()
+        .flat_map(|group| {
+            group
+                .into_iter()
+                .tuple_combinations()
+                .flat_map(|(a, b)| [(a.clone(), b.clone()), (b.clone(), a.clone())])
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Recheck a torrent, and wait until qBittorrent is done checking it
+async fn recheck_and_wait(
+    api: &dyn TorrentClient,
+    hash: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    api.recheck(&[hash.to_owned()]).await?;
+    wait_for_check(api, hash).await
+}
+
+/// Wait until qBittorrent is done checking a torrent
+async fn wait_for_check(
+    api: &dyn TorrentClient,
+    hash: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        // the check may not have started yet, give it some time
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let torrent = api
+            .torrents(Some(&[hash.to_owned()]))
+            .await?
+            .pop()
+            .ok_or_else(|| format!("Torrent not found: {}", hash))?;
+        match torrent.state {
+            Some(State::CheckingUP | State::CheckingDL | State::CheckingResumeData) => (),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// All the wanted files of the torrent are downloaded
+///
+/// qBittorrent moves a torrent out of the temp path once it is finished, even if files that are not wanted are missing
+fn is_finished(properties: &TorrentProperty, content: &[TorrentContent]) -> bool {
+    if properties.pieces_num.is_some() && properties.pieces_num == properties.pieces_have {
+        return true;
+    }
+
+    content
+        .iter()
+        .filter(|f| f.priority != Priority::DoNotDownload)
+        .all(|f| f.progress >= 1.)
+}
+
+/// Directory the files of a torrent are in, see `resolve_file_path`
+fn content_dir<'a>(
+    preferences: &'a Preferences,
+    properties: &'a TorrentProperty,
+    content: &[TorrentContent],
+) -> &'a str {
+    let temp_path = match preferences.temp_path.as_ref() {
+        Some(temp_path) if preferences.temp_path_enabled != Some(false) => Some(temp_path),
+        _ => None,
+    };
+
+    match temp_path {
+        Some(temp_path) if !is_finished(properties, content) => temp_path,
+        _ => properties.save_path.as_ref().unwrap(),
+    }
+}
+
+/// Location on disk of the file `path` of a torrent, as qBittorrent sees it
+///
+/// Finished torrents are in their save path. Others are in the temp path, if it is enabled. `path` is a name in the
+/// torrent, its parts are separated by `/`: they are joined with the separator of the system, Windows save paths
+/// (`D:\Downloads`, `\\nas\share`) don't end up with mixed separators
+///
+/// With "Append .!qB extension to incomplete files" (or "Append .part to incomplete files' names" in Transmission),
+/// files that are not complete yet are named `<file>.!qB` (`<file>.part`)
+pub fn resolve_file_path(
+    preferences: &Preferences,
+    properties: &TorrentProperty,
+    content: &[TorrentContent],
+    path: &str,
+) -> PathBuf {
+    let mut file_path = PathBuf::from(content_dir(preferences, properties, content));
+    file_path.extend(path.split('/'));
+    let incomplete = content.iter().any(|f| f.name == path && f.progress < 1.);
+    match preferences.incomplete_files_ext.as_deref() {
+        Some(ext) if incomplete => file_path.as_mut_os_string().push(ext),
+        _ => {}
+    }
+    file_path
+}
+
+/// Reads planned for a destination file
+#[derive(Debug)]
+struct FilePlan {
+    dst_filename: String,
+    /// Missing pieces of the file, in an eligible state
+    missing_pieces: usize,
+    /// Pieces left alone while planning, and why
+    skipped: Vec<(usize, SkipReason)>,
+    planned_reads: Vec<PlannedRead>,
+}
+
+/// Create the destination files the plan writes to, when the store creates missing files: they get their size before
+/// any piece is written, pieces are not written in order
+fn create_dst_files(
+    store: &dyn PieceStore,
+    dst_torrent: &Torrent,
+    files: &[FilePlan],
+) -> std::io::Result<()> {
+    let dst_files: HashMap<&str, &str> = files
+        .iter()
+        .flat_map(|f| &f.planned_reads)
+        .flat_map(|read| &read.segments)
+        .map(|s| (s.dst_path.as_str(), s.segment.dst_filename.as_str()))
+        .collect();
+    for (path, filename) in dst_files {
+        if let Some(f) = dst_torrent.content.iter().find(|f| f.name == filename) {
+            store.create_file(path, f.size)?;
+        }
+    }
+    Ok(())
+}
+
+/// What a donor can give to a destination, found by `MergeEngine::plan` without reading any data
+pub struct MergePlan {
+    src_hash: String,
+    dst_hash: String,
+    dst_torrent: Torrent,
+    files: Vec<FilePlan>,
+    /// Counters of the planning, the execution adds its own
+    report: MergeReport,
+}
+
+impl MergePlan {
+    /// The reads and writes of the plan as JSON, to be reviewed, and loaded back with `MergeEngine::load_plan`
+    ///
+    /// An object with `src_hash`, `dst_hash` and `files`, each with `dst_file`, `missing_pieces` and `pieces`. Each
+    /// piece has its index `piece`, its expected `hash` and `piece_size`, and `segments` with `src_file`, `dst_file`,
+    /// `src_path`, `dst_path`, `offset` and `size`
+    pub fn to_json(&self) -> serde_json::Value {
+        let files: Vec<serde_json::Value> = self
+            .files
+            .iter()
+            .filter(|f| !f.planned_reads.is_empty())
+            .map(|f| {
+                serde_json::json!({
+                    "dst_file": f.dst_filename,
+                    "missing_pieces": f.missing_pieces,
+                    "pieces": f.planned_reads.iter().map(|read| serde_json::json!({
+                        "piece": read.dst_piece_idx,
+                        "hash": read.hash.to_string(),
+                        "piece_size": read.piece_size,
+                        "identical": read.identical,
+                        "segments": read.segments.iter().map(|s| serde_json::json!({
+                            "src_file": s.segment.src_filename,
+                            "dst_file": s.segment.dst_filename,
+                            "src_path": s.src_path,
+                            "dst_path": s.dst_path,
+                            "offset": s.segment.file_block.offset,
+                            "size": s.segment.file_block.size,
+                        })).collect::<Vec<_>>(),
+                    })).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+
+        serde_json::json!({
+            "src_hash": self.src_hash,
+            "dst_hash": self.dst_hash,
+            "planned_pieces": self.planned_pieces(),
+            "planned_bytes": self.planned_bytes(),
+            "files": files,
+        })
+    }
+
+    /// Destination pieces that will be read from the donor
+    pub fn planned_pieces(&self) -> usize {
+        self.files.iter().map(|f| f.planned_reads.len()).sum()
+    }
+
+    /// Bytes that will be read from the donor
+    pub fn planned_bytes(&self) -> u64 {
+        self.files
+            .iter()
+            .flat_map(|f| &f.planned_reads)
+            .map(PlannedRead::size)
+            .sum()
+    }
+}
+
+/// Merges torrents of a qBittorrent instance, one (donor, destination) pair at a time
+///
+/// ```no_run
+/// # async fn merge(api: &qbittorrent_merger::QBittorrent) -> Result<(), Box<dyn std::error::Error>> {
+/// use qbittorrent_merger::{MergeEngine, Options};
+///
+/// let options = Options::default();
+/// let engine = MergeEngine::new(api, &options)?;
+/// let plan = engine.plan("src hash", "dst hash").await?;
+/// let report = engine.execute(plan).await?;
+/// println!("{} pieces restored", report.restored_pieces);
+/// # Ok(())
+/// # }
+/// ```
+pub struct MergeEngine<'a> {
+    api: &'a dyn TorrentClient,
+    options: &'a Options,
+    store: Arc<dyn PieceStore>,
+    file_pool: FileHandlePool,
+    hash_pool: HashPool,
+    piece_log: Option<PieceLog>,
+    checkpoint: Option<Checkpoint>,
+    /// Set on Ctrl-C, merges stop after the current piece
+    interrupted: Arc<AtomicBool>,
+    /// Pieces of each destination that merges of this engine restored, or found correct. The client only knows once
+    /// it rechecks, the next donors of a destination leave them alone
+    restored: std::sync::Mutex<HashMap<String, HashSet<usize>>>,
+}
+
+impl<'a> MergeEngine<'a> {
+    /// Fails if the data access selected in `options` is not available, e.g. SFTP without the feature
+    pub fn new(
+        api: &'a dyn TorrentClient,
+        options: &'a Options,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let store = open_store(options)?;
+        let store: Arc<dyn PieceStore> = match &options.journal {
+            Some(path) if !options.is_read_only() => Arc::new(JournalStore::open(store, path)?),
+            _ => store,
+        };
+        let store: Arc<dyn PieceStore> = match options.write_backend {
+            WriteBackend::Disk => store,
+            WriteBackend::Daemon => Arc::new(DaemonWriteStore { inner: store }),
+        };
+
+        Ok(MergeEngine {
+            api,
+            options,
+            store,
+            file_pool: FileHandlePool::new(options.max_open_files),
+            hash_pool: HashPool::new(options.hash_threads)?,
+            piece_log: options
+                .piece_log
+                .as_deref()
+                .map(PieceLog::create)
+                .transpose()?,
+            checkpoint: match &options.state_file {
+                Some(path) if !options.is_read_only() => Some(Checkpoint::open(path)?),
+                _ => None,
+            },
+            interrupted: Arc::new(AtomicBool::new(false)),
+            restored: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Forget the pieces restored in `dst_hash`, once it was rechecked: the client knows about them
+    pub fn forget_restored(&self, dst_hash: &str) {
+        self.restored.lock().unwrap().remove(dst_hash);
+    }
+
+    /// Stop the merges after the current piece, they return what they did so far
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    /// Returns once the merges are interrupted
+    async fn interrupted(&self) {
+        while !self.is_interrupted() {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    /// Interrupt the merges on Ctrl-C. A second Ctrl-C exits right away
+    fn interrupt_on_ctrl_c(&self) {
+        let interrupted = self.interrupted.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if interrupted.swap(true, Ordering::SeqCst) {
+                    std::process::exit(RunOutcome::Interrupted.exit_code());
+                }
+                warn!("Interrupted, stopping after the current piece. Press Ctrl-C again to exit right away");
+            }
+        });
+    }
+
+    /// Plan, then execute the merge of `src_hash` into `dst_hash`
+    pub async fn merge(
+        &self,
+        src_hash: &str,
+        dst_hash: &str,
+    ) -> Result<MergeReport, Box<dyn std::error::Error>> {
+        let plan = self.plan(src_hash, dst_hash).await?;
+        self.execute(plan).await
+    }
+
+    /// Plan saved by `MergePlan::to_json`, checked against the destination as it is now
+    ///
+    /// Fails if a piece doesn't have the same expected hash anymore: the plan is for another torrent. Pieces that were
+    /// downloaded since the plan was made are left out
+    pub async fn load_plan(
+        &self,
+        json: &serde_json::Value,
+    ) -> Result<MergePlan, Box<dyn std::error::Error>> {
+        fn field<'v>(
+            json: &'v serde_json::Value,
+            name: &str,
+        ) -> Result<&'v serde_json::Value, String> {
+            json.get(name)
+                .ok_or_else(|| format!("Invalid plan: no {:?}", name))
+        }
+        fn string(json: &serde_json::Value, name: &str) -> Result<String, String> {
+            field(json, name)?
+                .as_str()
+                .map(str::to_owned)
+                .ok_or_else(|| format!("Invalid plan: {:?} is not a string", name))
+        }
+        fn number(json: &serde_json::Value, name: &str) -> Result<u64, String> {
+            field(json, name)?
+                .as_u64()
+                .ok_or_else(|| format!("Invalid plan: {:?} is not a number", name))
+        }
+        fn array<'v>(
+            json: &'v serde_json::Value,
+            name: &str,
+        ) -> Result<&'v Vec<serde_json::Value>, String> {
+            field(json, name)?
+                .as_array()
+                .ok_or_else(|| format!("Invalid plan: {:?} is not a list", name))
+        }
+
+        let src_hash = string(json, "src_hash")?;
+        let dst_hash = string(json, "dst_hash")?;
+        let dst_torrent = Torrent::new(self.api, &dst_hash).await?;
+        // identical pieces are checked again against the source as it is now, never taken from the plan
+        let src_torrent =
+            if self.options.trust_identical_pieces && self.options.offline_donor().is_none() {
+                Some(Torrent::new(self.api, &src_hash).await?)
+            } else {
+                None
+            };
+
+        let mut files = Vec::new();
+        for file in array(json, "files")? {
+            let mut file_plan = FilePlan {
+                dst_filename: string(file, "dst_file")?,
+                missing_pieces: number(file, "missing_pieces")? as usize,
+                skipped: Vec::new(),
+                planned_reads: Vec::new(),
+            };
+            for piece in array(file, "pieces")? {
+                let dst_piece_idx = number(piece, "piece")? as usize;
+                let hash = PieceHash::from_hex(&string(piece, "hash")?)?;
+                if dst_torrent.pieces_hashes.get(dst_piece_idx) != Some(&hash) {
+                    return Err(format!(
+                        "{}: piece {} doesn't have the hash of the plan, the plan is for another torrent",
+                        dst_hash, dst_piece_idx
+                    )
+                    .into());
+                }
+                if dst_torrent.pieces_states.get(dst_piece_idx) == Some(&PieceState::Downloaded) {
+                    debug!("Downloaded since planned: {}", dst_piece_idx);
+                    continue;
+                }
+
+                let segments = array(piece, "segments")?
+                    .iter()
+                    .map(|s| {
+                        Ok(PlannedSegment {
+                            src_path: string(s, "src_path")?,
+                            dst_path: string(s, "dst_path")?,
+                            segment: Segment {
+                                src_filename: string(s, "src_file")?,
+                                dst_filename: string(s, "dst_file")?,
+                                file_block: FileBlock {
+                                    offset: number(s, "offset")?,
+                                    size: number(s, "size")?,
+                                },
+                            },
+                        })
+                    })
+                    .collect::<Result<Vec<PlannedSegment>, String>>()?;
+                let identical = src_torrent.as_ref().is_some_and(|src_torrent| {
+                    let segments: Vec<_> = segments.iter().map(|s| s.segment.clone()).collect();
+                    is_identical_piece(src_torrent, &dst_torrent, &segments, dst_piece_idx)
+                });
+                file_plan.planned_reads.push(PlannedRead {
+                    dst_piece_idx,
+                    segments,
+                    hash,
+                    piece_size: number(piece, "piece_size")?,
+                    identical,
+                });
+            }
+            files.push(file_plan);
+        }
+
+        Ok(MergePlan {
+            src_hash,
+            dst_hash,
+            dst_torrent,
+            files,
+            report: MergeReport::default(),
+        })
+    }
+
+    /// The ugly stuff
+    ///
+    /// Overall process:
+    /// 1) find files with same size, then for each file:
+    /// 2) find missing pieces that belong to said file, then for each piece:
+    /// 3) get the file offset for the piece in the src torrent, and check if it is downloaded
+    /// 4) convert to piece in the dst torrent
+    /// 5) convert to file offset in the dst torrent
+    ///
+    /// Nothing is read or written, `execute` copies the data
+    ///
+    /// Careful with:
+    /// * Pieces can have different sizes between torrents
+    /// * Pieces can be misaligned if some files are present before the file that we want to restore (acting as padding). In that case, if he padding file is incomplete, it is not possible to restore the 1st piece of the 2nd file, because we can not check a hash overlapping unknown data
+    /// * 1 piece from dst can have multiple corresponding pieces in src, because it can span multiple pieces
+    /// * Last piece is probably not handled correctly
+    ///
+    pub async fn plan(
+        &self,
+        src_hash: &str,
+        dst_hash: &str,
+    ) -> Result<MergePlan, Box<dyn std::error::Error>> {
+        let (api, options) = (self.api, self.options);
+        let dst_preferences = api.preferences(dst_hash).await?;
+        // loose files are where they are, there is no temp path
+        let src_preferences = match options.offline_donor() {
+            Some(_) => Preferences::default(),
+            None => api.preferences(src_hash).await?,
+        };
+
+        info!("src_hash: {}", src_hash);
+        info!("dst_hash: {}", dst_hash);
+
+        // the destination hashes are always needed, to check data before writing it
+        let mut dst_torrent = Torrent::new(api, dst_hash).await?;
+        // restored from a previous donor, each piece comes from the first donor that has it
+        if let Some(restored) = self.restored.lock().unwrap().get(dst_hash) {
+            for &idx in restored {
+                if let Some(state) = dst_torrent.pieces_states.get_mut(idx) {
+                    *state = PieceState::Downloaded;
+                }
+            }
+        }
+        let mut src_torrent = match (&options.donor_dir, &options.src_torrent, &options.src_data) {
+            (Some(dir), _, _) => Torrent::from_dir(dir, dst_torrent.piece_size)?,
+            (None, Some(path), Some(data_dir)) => Torrent::from_torrent_file(path, data_dir)?,
+            _ => Torrent::fetch(api, src_hash, !options.skips_donor_hashes()).await?,
+        };
+
+        if options.force_recheck_source && options.offline_donor().is_none() {
+            let pieces_have_before = src_torrent.properties.pieces_have.unwrap_or_default();
+            info!("Rechecking source...");
+            recheck_and_wait(api, src_hash).await?;
+            src_torrent.properties = api.properties(src_hash).await?;
+            src_torrent.pieces_states = api.pieces_states(src_hash).await?;
+
+            let failed_pieces =
+                pieces_have_before - src_torrent.properties.pieces_have.unwrap_or_default();
+            if failed_pieces > 0 {
+                warn!("Source pieces that failed the recheck: {}", failed_pieces);
+            } else {
+                info!("Source pieces that failed the recheck: 0");
+            }
+        }
+
+        let mut plan = MergePlan {
+            src_hash: src_hash.to_owned(),
+            dst_hash: dst_hash.to_owned(),
+            dst_torrent,
+            files: Vec::new(),
+            report: MergeReport::default(),
+        };
+        let dst_torrent = &plan.dst_torrent;
+        let report = &mut plan.report;
+
+        debug!("src_torrent.piece_size={}", src_torrent.piece_size);
+        info!("src content:");
+        for f in &src_torrent.content {
+            info!("{:10} {}", f.size, &f.name);
+        }
+        debug!("dst_torrent.piece_size={}", dst_torrent.piece_size);
+        info!("dst content:");
+        for f in &dst_torrent.content {
+            info!("{:10} {}", f.size, &f.name);
+        }
+
+        if options.hash_only {
+            let recoverable_pieces = find_pieces_by_hash(&src_torrent, dst_torrent);
+            info!(
+                "Recoverable pieces (hash only): {}/{}",
+                recoverable_pieces.len(),
+                dst_torrent
+                    .pieces_states
+                    .iter()
+                    .filter(|state| state != &&PieceState::Downloaded)
+                    .count()
+            );
+            return Ok(plan);
+        }
+
+        let mut same_files = find_same_size_files(&src_torrent, dst_torrent);
+        same_files.retain(|same_file| {
+            let keep = options
+                .min_file_size
+                .is_none_or(|min| same_file.size >= min)
+                && options
+                    .max_file_size
+                    .is_none_or(|max| same_file.size <= max);
+            if !keep {
+                info!(
+                    "Skipping {:?}: size {} out of bounds",
+                    same_file.dst_files, same_file.size
+                );
+            }
+            keep
+        });
+        if !options.include_unwanted {
+            retain_wanted_files(&mut same_files, dst_torrent);
+        }
+        retain_selected_files(&mut same_files, options);
+        if let Some(order) = options.priority_order {
+            sort_by_priority(&mut same_files, dst_torrent, order);
+        }
+        info!("same files:");
+        for same_file in &same_files {
+            info!(
+                "{:?} -> {:?} (match_source={})",
+                same_file.src_files, same_file.dst_files, same_file.match_source
+            );
+        }
+
+        // pieces that the donor doesn't have, by destination file
+        let mut unavailable = Vec::new();
+        // pieces that were planned for a file, pieces shared by 2 files are planned once
+        let mut tried = HashSet::new();
+        for same_file in &same_files {
+            let dst_filename = &same_file.dst_files[0];
+            info!("Working on {}", dst_filename);
+
+            let missing_pieces = get_missing_pieces(dst_torrent, dst_filename);
+            debug!(
+                "{} missing_pieces: {:?}",
+                missing_pieces.len(),
+                &missing_pieces
+            );
+            if missing_pieces.is_empty() {
+                info!("{} already complete", dst_filename);
+                report.complete_files += 1;
+                continue;
+            }
+
+            let missing_pieces: Vec<usize> = missing_pieces
+                .into_iter()
+                .filter(|&idx| {
+                    dst_torrent
+                        .pieces_states
+                        .get(idx)
+                        .is_none_or(|state| !options.exclude_piece_states.contains(state))
+                })
+                .collect();
+            if missing_pieces.is_empty() {
+                info!("{}: no missing piece in an eligible state", dst_filename);
+                continue;
+            }
+            if missing_pieces.len() < options.min_missing_pieces {
+                info!(
+                    "{}: only {} missing pieces, skipping",
+                    dst_filename,
+                    missing_pieces.len()
+                );
+                continue;
+            }
+
+            // e.g. save paths that are symlinks to the same directory, or hardlinked files
+            if let Ok(src_filename) = convert_filename(&same_files, dst_filename) {
+                let src_path = options.resolve_path(get_file_path(
+                    &src_preferences,
+                    &src_torrent.properties,
+                    &src_torrent.content,
+                    &src_filename,
+                ));
+                let dst_path = options.resolve_path(get_file_path(
+                    &dst_preferences,
+                    &dst_torrent.properties,
+                    &dst_torrent.content,
+                    dst_filename,
+                ));
+                if options.sftp.is_none() && is_same_file(&src_path, &dst_path) {
+                    warn!(
+                        "{} and {} are the same file on disk, skipping",
+                        src_path, dst_path
+                    );
+                    continue;
+                }
+                if let Err(e) = self.store.check_size(&src_path, same_file.size) {
+                    warn!("{}, skipping", e);
+                    continue;
+                }
+            }
+
+            let mut file_plan = FilePlan {
+                dst_filename: dst_filename.clone(),
+                missing_pieces: missing_pieces.len(),
+                skipped: Vec::new(),
+                planned_reads: Vec::new(),
+            };
+            for &dst_piece_idx in &missing_pieces {
+                // pieces shared with a previous file were already tried
+                if !tried.insert(dst_piece_idx) {
+                    continue;
+                }
+                debug!("Working on missing piece: {}", dst_piece_idx);
+                if let Some(checkpoint) = &self.checkpoint {
+                    if checkpoint
+                        .processed(src_hash, dst_hash, dst_piece_idx)
+                        .is_some()
+                    {
+                        report.resumed_pieces += 1;
+                        file_plan.skipped.push((dst_piece_idx, SkipReason::Resumed));
+                        continue;
+                    }
+                }
+
+                let segments = match plan_piece(
+                    &src_torrent,
+                    dst_torrent,
+                    &same_files,
+                    dst_piece_idx,
+                    options.pieces_from_donor_only,
+                    options.trust_donor_state,
+                ) {
+                    Ok(segments) => segments,
+                    Err(reason) => {
+                        match reason {
+                            SkipReason::Unavailable => {
+                                report.unavailable_pieces += 1;
+                                unavailable.extend(piece_segments(dst_torrent, dst_piece_idx));
+                            }
+                            SkipReason::MappingStale => report.mapping_stale += 1,
+                            SkipReason::NotIdentical => report.not_identical_pieces += 1,
+                            SkipReason::NoExpectedHash => report.no_expected_hash += 1,
+                            _ => {}
+                        }
+                        file_plan.skipped.push((dst_piece_idx, reason));
+                        continue;
+                    }
+                };
+                debug!("segments: {:?}", &segments);
+
+                #[cfg(feature = "script")]
+                if let Some(script) = &options.script {
+                    if !script.restore(src_hash, dst_hash, dst_piece_idx, &segments) {
+                        debug!("Left alone by the script: {}", dst_piece_idx);
+                        file_plan.skipped.push((dst_piece_idx, SkipReason::Script));
+                        continue;
+                    }
+                }
+
+                // only a client keeps the donor pieces states, loose files and .torrent files are assumed complete
+                let identical = options.trust_identical_pieces
+                    && options.offline_donor().is_none()
+                    && is_identical_piece(&src_torrent, dst_torrent, &segments, dst_piece_idx);
+                file_plan.planned_reads.push(PlannedRead {
+                    dst_piece_idx,
+                    segments: segments
+                        .into_iter()
+                        .map(|segment| PlannedSegment {
+                            src_path: options.resolve_path(get_file_path(
+                                &src_preferences,
+                                &src_torrent.properties,
+                                &src_torrent.content,
+                                &segment.src_filename,
+                            )),
+                            dst_path: options.resolve_path(get_file_path(
+                                &dst_preferences,
+                                &dst_torrent.properties,
+                                &dst_torrent.content,
+                                &segment.dst_filename,
+                            )),
+                            segment,
+                        })
+                        .collect(),
+                    hash: dst_torrent.pieces_hashes[dst_piece_idx],
+                    piece_size: dst_torrent.piece_hash_size(dst_piece_idx),
+                    identical,
+                });
+            }
+
+            if let Some(max_fraction) = options.max_overwrite_fraction {
+                let fraction =
+                    overwrite_fraction(&file_plan.planned_reads, dst_filename, same_file.size);
+                if fraction > max_fraction {
+                    warn!(
+                        "{}: {:.0}% of the file would be overwritten, more than {:.0}%, skipping. The files may not really be the same",
+                        dst_filename,
+                        fraction * 100.,
+                        max_fraction * 100.
+                    );
+                    report.overwrite_limit_files += 1;
+                    for planned_read in file_plan.planned_reads.drain(..) {
+                        // a piece shared with the next file is planned for it instead
+                        tried.remove(&planned_read.dst_piece_idx);
+                        file_plan
+                            .skipped
+                            .push((planned_read.dst_piece_idx, SkipReason::OverwriteLimit));
+                    }
+                }
+            }
+
+            plan.files.push(file_plan);
+        }
+
+        report.coverage_gaps = coverage_gaps(src_hash, dst_hash, unavailable);
+
+        Ok(plan)
+    }
+
+    /// Read, check and write the pieces of `plan`. Pieces are written only if they match the hash of the destination
+    pub async fn execute(
+        &self,
+        plan: MergePlan,
+    ) -> Result<MergeReport, Box<dyn std::error::Error>> {
+        let (events, mut events_rx) = mpsc::unbounded_channel();
+        let mut stats = MergeStats::new(&plan.dst_hash);
+        let mut ticker = match self.options.stats_interval {
+            0 => None,
+            seconds => {
+                let period = Duration::from_secs(seconds);
+                Some(tokio::time::interval_at(
+                    tokio::time::Instant::now() + period,
+                    period,
+                ))
+            }
+        };
+        let mut progress = self
+            .options
+            .progress
+            .as_ref()
+            .map(|bars| MergeProgress::new(bars, &plan));
+        let logger = tokio::spawn(async move {
+            let mut files: Vec<FileOutcome> = Vec::new();
+            loop {
+                tokio::select! {
+                    event = events_rx.recv() => match event {
+                        Some(event) => {
+                            debug!("{}", event);
+                            stats.record(&event);
+                            FileOutcome::record(&mut files, &event);
+                            if let Some(progress) = &mut progress {
+                                progress.record(&event);
+                            }
+                        }
+                        None => break,
+                    },
+                    _ = tick(&mut ticker) => stats.log(),
+                }
+            }
+            if let Some(progress) = progress {
+                progress.finish();
+            }
+            files
+        });
+
+        let result = self.execute_with_events(plan, events).await;
+        let files = logger.await?;
+        // a daemon keeps the store, torrents may be moved by then
+        self.store.close_files();
+
+        result.map(|report| MergeReport { files, ..report })
+    }
+
+    /// Same as `execute`, and send progress to `events` as the merge goes
+    async fn execute_with_events(
+        &self,
+        plan: MergePlan,
+        events: mpsc::UnboundedSender<MergeEvent>,
+    ) -> Result<MergeReport, Box<dyn std::error::Error>> {
+        let options = self.options;
+        let MergePlan {
+            src_hash,
+            dst_hash,
+            dst_torrent,
+            files,
+            mut report,
+        } = plan;
+
+        if options.hash_only {
+            return Ok(report);
+        }
+        if !options.dry_run {
+            create_dst_files(self.store.as_ref(), &dst_torrent, &files)?;
+        }
+
+        // pieces that are on disk after the merge: restored, would be restored in dry run, or already correct
+        let mut restored = HashSet::new();
+        // files don't share pieces, and the work is mostly waiting for the disks. Their reads, writes and checks run
+        // in blocking tasks, up to `jobs` files are on the disks at the same time
+        let executions: Vec<_> = futures_util::stream::iter(&files)
+            .map(|file_plan| self.execute_file(&src_hash, &dst_hash, file_plan, &events))
+            .buffered(options.jobs)
+            .try_collect()
+            .await?;
+        for (file_report, file_restored) in executions {
+            report.add_file(file_report);
+            restored.extend(file_restored);
+        }
+        self.restored
+            .lock()
+            .unwrap()
+            .entry(dst_hash.clone())
+            .or_default()
+            .extend(&restored);
+
+        // files that are not wanted are not downloaded, their pieces are not really missing
+        for file in dst_torrent
+            .content
+            .iter()
+            .filter(|f| f.priority != Priority::DoNotDownload && !is_padding_file(&f.name))
+        {
+            let pieces: Vec<usize> = get_missing_pieces(&dst_torrent, &file.name)
+                .into_iter()
+                .filter(|idx| !restored.contains(idx))
+                .collect();
+            // complete files are kept too, pieces missing after another merge may be restored by this one
+            report.still_missing.push(MissingFile {
+                dst_hash: dst_hash.clone(),
+                index: file.index,
+                path: file.name.clone(),
+                pieces,
+            });
+        }
+
+        report.log(options);
+
+        Ok(report)
+    }
+
+    /// Whether the destination of a piece already has the data, qBittorrent may not know yet that it is there
+    async fn dst_is_correct(&self, planned_read: &PlannedRead) -> bool {
+        let permit = self.file_pool.acquire().await;
+        let (store, planned_read) = (self.store.clone(), planned_read.clone());
+        let max_buffer = self.options.max_buffer;
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            blocks_have_hash(
+                store.as_ref(),
+                &planned_read.dst_blocks(),
+                &planned_read.hash,
+                planned_read.piece_size,
+                max_buffer,
+            )
+            .unwrap_or(false)
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Restore the pieces planned for a file, with the counters of this file only, and its pieces that are on disk after
+    async fn execute_file(
+        &self,
+        src_hash: &str,
+        dst_hash: &str,
+        file_plan: &FilePlan,
+        events: &mpsc::UnboundedSender<MergeEvent>,
+    ) -> Result<(MergeReport, HashSet<usize>), Box<dyn std::error::Error>> {
+        let (options, store, file_pool) = (self.options, &self.store, &self.file_pool);
+        // nobody listening is fine
+        let emit = |event: MergeEvent| {
+            let _ = events.send(event);
+        };
+        let log_piece = |planned_read: &PlannedRead,
+                         hash_match: Option<bool>,
+                         outcome: Result<(), SkipReason>,
+                         error: Option<&dyn std::fmt::Display>| {
+            if let Some(piece_log) = &self.piece_log {
+                piece_log.log(dst_hash, planned_read, hash_match, outcome, error);
+            }
+            if let Some(checkpoint) = &self.checkpoint {
+                let idx = planned_read.dst_piece_idx;
+                match outcome {
+                    Ok(()) | Err(SkipReason::AlreadyCorrect) => {
+                        checkpoint.record(src_hash, dst_hash, idx, true)
+                    }
+                    Err(SkipReason::HashMismatch) => {
+                        checkpoint.record(src_hash, dst_hash, idx, false)
+                    }
+                    _ => (),
+                }
+            }
+        };
+
+        let mut report = MergeReport::default();
+        let mut restored = HashSet::new();
+        // files that were not started yet are left alone
+        if self.is_interrupted() {
+            report.interrupted = true;
+            return Ok((report, restored));
+        }
+
+        let dst_filename = &file_plan.dst_filename;
+        emit(MergeEvent::FileStarted {
+            path: dst_filename.clone(),
+            missing_pieces: file_plan.missing_pieces,
+        });
+        for &(idx, reason) in &file_plan.skipped {
+            let resumed = self
+                .checkpoint
+                .as_ref()
+                .and_then(|checkpoint| checkpoint.processed(src_hash, dst_hash, idx));
+            if reason == SkipReason::Resumed && resumed == Some(true) {
+                restored.insert(idx);
+            }
+            emit(MergeEvent::PieceSkipped {
+                path: dst_filename.clone(),
+                idx,
+                reason,
+            });
+        }
+
+        // Double buffering: read batch N+1 while N is hashed (on the hash pool) and written
+        let batches = batches(&file_plan.planned_reads, options.max_buffer);
+        let mut next_read = match batches.first() {
+            Some(batch) => {
+                Some(PlannedRead::spawn_batch(batch, file_pool, store, options.max_buffer).await)
+            }
+            None => None,
+        };
+        for (i, batch) in batches.iter().enumerate() {
+            let batch_data = next_read.take().unwrap().await?;
+            next_read = match batches.get(i + 1) {
+                Some(batch) => Some(
+                    PlannedRead::spawn_batch(batch, file_pool, store, options.max_buffer).await,
+                ),
+                None => None,
+            };
+            let batch_data = self.hash_pool.hash(batch, batch_data).await;
+
+            // pieces are checked first, those to write are written together (a store may submit them at once), then
+            // the outcomes are reported in piece order
+            let (mut steps, mut to_write) = (Vec::new(), Vec::new());
+            for (planned_read, source_data) in batch.iter().zip(batch_data) {
+                // pieces are never left half written
+                if self.is_interrupted() {
+                    warn!("{}: interrupted", dst_hash);
+                    report.interrupted = true;
+                    break;
+                }
+                let step = match source_data {
+                    Err(e) => PieceStep::ReadFailed(e),
+                    Ok((source_data, computed_hash)) => {
+                        if computed_hash != planned_read.hash {
+                            PieceStep::HashMismatch
+                        } else if options.skip_correct_pieces
+                            && self.dst_is_correct(planned_read).await
+                        {
+                            PieceStep::AlreadyCorrect
+                        } else if options.dry_run {
+                            PieceStep::DryRun
+                        } else {
+                            to_write.push((planned_read.clone(), source_data));
+                            PieceStep::Write
+                        }
+                    }
+                };
+                steps.push((planned_read, step));
+            }
+
+            debug!("Writing {} pieces to {}", to_write.len(), dst_filename);
+            let mut written = match to_write.is_empty() {
+                true => Vec::new(),
+                false => {
+                    PlannedRead::spawn_write_batch(to_write, file_pool, store, options.max_buffer)
+                        .await
+                        .await??
+                }
+            }
+            .into_iter();
+
+            for (planned_read, step) in steps {
+                match step {
+                    // the piece stays missing, and is tried again with the next donor of the destination
+                    PieceStep::ReadFailed(e) => {
+                        warn!("Can't read {:?}: {}", planned_read.src_blocks(), e);
+                        report.donor_read_errors += 1;
+                        log_piece(planned_read, None, Err(SkipReason::ReadFailed), Some(&e));
+                        emit(MergeEvent::PieceSkipped {
+                            path: dst_filename.clone(),
+                            idx: planned_read.dst_piece_idx,
+                            reason: SkipReason::ReadFailed,
+                        });
+                    }
+                    PieceStep::HashMismatch => {
+                        warn!("hashes don't match");
+                        log_piece(
+                            planned_read,
+                            Some(false),
+                            Err(SkipReason::HashMismatch),
+                            None,
+                        );
+                        emit(MergeEvent::PieceSkipped {
+                            path: dst_filename.clone(),
+                            idx: planned_read.dst_piece_idx,
+                            reason: SkipReason::HashMismatch,
+                        });
+                    }
+                    PieceStep::AlreadyCorrect => {
+                        debug!("Already correct: {:?}", planned_read.dst_blocks());
+                        report.already_correct_pieces += 1;
+                        restored.insert(planned_read.dst_piece_idx);
+                        log_piece(
+                            planned_read,
+                            Some(true),
+                            Err(SkipReason::AlreadyCorrect),
+                            None,
+                        );
+                        emit(MergeEvent::PieceSkipped {
+                            path: dst_filename.clone(),
+                            idx: planned_read.dst_piece_idx,
+                            reason: SkipReason::AlreadyCorrect,
+                        });
+                    }
+                    PieceStep::DryRun => {
+                        for s in &planned_read.segments {
+                            report.patch.push(PatchEntry {
+                                dst_hash: dst_hash.to_owned(),
+                                path: s.segment.dst_filename.clone(),
+                                file_block: s.segment.file_block,
+                                hash: planned_read.hash,
+                            });
+                        }
+                        report.restored_pieces += 1;
+                        report.restored_bytes += planned_read.size();
+                        restored.insert(planned_read.dst_piece_idx);
+                        log_piece(planned_read, Some(true), Ok(()), None);
+                        emit(MergeEvent::PieceRestored {
+                            path: dst_filename.clone(),
+                            idx: planned_read.dst_piece_idx,
+                            size: planned_read.size(),
+                        });
+                    }
+                    PieceStep::Write => {
+                        let has_hash = match written.next().unwrap() {
+                            Ok(has_hash) => has_hash,
+                            Err(e) => {
+                                error!("Can't write to {}: {}", dst_filename, e);
+                                report.failed_writes += 1;
+                                log_piece(
+                                    planned_read,
+                                    Some(true),
+                                    Err(SkipReason::WriteFailed),
+                                    Some(&e),
+                                );
+                                emit(MergeEvent::PieceSkipped {
+                                    path: dst_filename.clone(),
+                                    idx: planned_read.dst_piece_idx,
+                                    reason: SkipReason::WriteFailed,
+                                });
+                                continue;
+                            }
+                        };
+
+                        if has_hash {
+                            report.restored_pieces += 1;
+                            report.restored_bytes += planned_read.size();
+                            restored.insert(planned_read.dst_piece_idx);
+                            log_piece(planned_read, Some(true), Ok(()), None);
+                            emit(MergeEvent::PieceRestored {
+                                path: dst_filename.clone(),
+                                idx: planned_read.dst_piece_idx,
+                                size: planned_read.size(),
+                            });
+                        } else {
+                            error!(
+                                "Written data doesn't match at {:?}",
+                                planned_read.dst_blocks()
+                            );
+                            report.failed_writes += 1;
+                            log_piece(
+                                planned_read,
+                                Some(true),
+                                Err(SkipReason::WriteFailed),
+                                Some(&"written data doesn't match"),
+                            );
+                            emit(MergeEvent::PieceSkipped {
+                                path: dst_filename.clone(),
+                                idx: planned_read.dst_piece_idx,
+                                reason: SkipReason::WriteFailed,
+                            });
+                        }
+                    }
+                }
+            }
+            if report.interrupted {
+                return Ok((report, restored));
+            }
+        }
+
+        let (pieces, bytes) = (report.restored_pieces, report.restored_bytes);
+        if options.dry_run {
+            info!(
+                "{}: {} pieces would be restored ({} bytes)",
+                dst_filename, pieces, bytes
+            );
+        } else {
+            info!(
+                "{}: {} pieces restored ({} bytes)",
+                dst_filename, pieces, bytes
+            );
+        }
+
+        emit(MergeEvent::FileFinished {
+            path: dst_filename.clone(),
+        });
+
+        Ok((report, restored))
+    }
+}
+
+/// Print name, size and completion of each torrent, and ask the user to go on when running interactively
+///
+/// Returns `false` if the user declined
+async fn confirm_hashes(
+    api: &dyn TorrentClient,
+    hashes: &[String],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let torrents = api.torrents(Some(hashes)).await?;
+
+    for hash in hashes {
+        match torrents
+            .iter()
+            .find(|t| t.hash.as_deref() == Some(hash.as_str()))
+        {
+            Some(t) => info!(
+                "{} {:12} {:5.1}% {}",
+                hash,
+                t.size.unwrap_or_default(),
+                t.progress.unwrap_or_default() * 100.,
+                t.name.as_deref().unwrap_or_default()
+            ),
+            None => return Err(format!("Torrent not found: {}", hash).into()),
+        }
+    }
+
+    Ok(ask_yes_no("Proceed?")?)
+}
+
+/// Ask a question when running interactively, `true` otherwise
+fn ask_yes_no(question: &str) -> std::io::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// WebUI user. The password is not handled yet, auth bypass for localhost must be enabled
+pub const USERNAME: &str = "admin";
+
+/// Store reading and writing the files of the torrents, as selected by `options`
+fn open_store(options: &Options) -> Result<Arc<dyn PieceStore>, Box<dyn std::error::Error>> {
+    let store: Arc<dyn PieceStore> = match &options.sftp {
+        #[cfg(feature = "sftp")]
+        Some(destination) => Arc::new(SftpStore::connect(destination, options.create_missing)?),
+        #[cfg(not(feature = "sftp"))]
+        Some(_) => return Err("SFTP support is not enabled, build with --features sftp".into()),
+        #[cfg(feature = "io-uring")]
+        None if options.io_uring => Arc::new(UringStore::new(
+            options.create_missing,
+            options.preallocate,
+            FileHandles::new(options.max_open_files.min(CACHED_FILE_HANDLES)),
+        )?),
+        #[cfg(not(feature = "io-uring"))]
+        None if options.io_uring => {
+            return Err("io_uring support is not enabled, build with --features io-uring".into())
+        }
+        None => Arc::new(LocalStore {
+            create_missing: options.create_missing,
+            preallocate: options.preallocate,
+            mmap: options.mmap,
+            handles: FileHandles::new(options.max_open_files.min(CACHED_FILE_HANDLES)),
+        }),
+    };
+    #[cfg(feature = "compressed")]
+    let store: Arc<dyn PieceStore> = match options.compressed_donors {
+        true => Arc::new(DecompressingStore { inner: store }),
+        false => store,
+    };
+    #[cfg(not(feature = "compressed"))]
+    if options.compressed_donors {
+        return Err(
+            "Compressed donors support is not enabled, build with --features compressed".into(),
+        );
+    }
+
+    let store: Arc<dyn PieceStore> = match (options.max_read_rate, options.max_write_rate) {
+        (None, None) => store,
+        (read, write) => Arc::new(ThrottledStore {
+            inner: store,
+            read: read.map(TokenBucket::new),
+            write: write.map(TokenBucket::new),
+        }),
+    };
+    let store: Arc<dyn PieceStore> = match options.read_cache {
+        0 => store,
+        capacity => Arc::new(CachingStore::new(store, capacity)),
+    };
+
+    Ok(Arc::new(PaddingStore { inner: store }))
+}
+
+/// Clients selected by `options`: qBittorrent, and the other clients given
+fn connect(options: &Options) -> Result<Box<dyn TorrentClient>, Box<dyn std::error::Error>> {
+    check_path_map(options)?;
+    let credential = Credential::new(USERNAME, "");
+    let qbit = QBittorrent::new(normalize_url(&options.url).as_str(), credential)?;
+    let mut clients: Vec<(&str, Box<dyn TorrentClient>)> = vec![("qbittorrent", Box::new(qbit))];
+    if let Some(url) = &options.transmission {
+        clients.push(("transmission", Box::new(Transmission::new(url)?)));
+    }
+    if let Some(url) = &options.deluge {
+        clients.push((
+            "deluge",
+            Box::new(Deluge::new(url, options.deluge_state_dir.as_deref())?),
+        ));
+    }
+    if let Some(url) = &options.rtorrent {
+        clients.push(("rtorrent", Box::new(RTorrent::new(url)?)));
+    }
+    let mut clients: Vec<Box<dyn TorrentClient>> = clients
+        .into_iter()
+        .map(|(name, client)| -> Box<dyn TorrentClient> {
+            match options.path_map.is_empty() {
+                true => client,
+                false => Box::new(MappedPaths::new(client, name, &options.path_map)),
+            }
+        })
+        .collect();
+    Ok(match clients.len() {
+        1 => clients.pop().unwrap(),
+        _ => Box::new(MultiClient::new(clients)),
+    })
+}
+
+/// Fails when the directory a path is mapped to is not there, e.g. the volume is not mounted: every file would be
+/// missing. Remote directories are not checked
+fn check_path_map(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    if options.sftp.is_some() {
+        return Ok(());
+    }
+    for mapping in &options.path_map {
+        if !std::path::Path::new(&mapping.to).is_dir() {
+            return Err(format!(
+                "Path mapping {}: {} doesn't exist or is not a directory",
+                mapping, mapping.to
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Merge the torrents selected by `options`: all the pairs of donors and destinations are merged, then modified
+/// torrents are rechecked and resumed
+pub async fn run(options: &Options) -> Result<RunOutcome, Box<dyn std::error::Error>> {
+    if options.daemon {
+        run_daemon(options).await?;
+        return Ok(RunOutcome::Interrupted);
+    }
+    if let Some(path) = &options.undo_journal {
+        return undo_journal(open_store(options)?.as_ref(), path);
+    }
+    // a .torrent file is checked without the client
+    if options.verify_torrent_file.is_some() {
+        return verify_torrents(None, options, &[]).await;
+    }
+
+    let client = connect(options)?;
+    let api = client.as_ref();
+
+    let version = api.version().await?;
+    info!("Client version: {}", version);
+
+    if options.resume_on_start {
+        let paused = read_paused_torrents()?;
+        if !paused.is_empty() {
+            info!(
+                "Resuming torrents left paused by a previous run: {:?}",
+                paused
+            );
+            api.resume(&paused).await?;
+        }
+        std::fs::remove_file(paused_torrents_path()).or_else(ignore_not_found)?;
+    }
+
+    if options.verify {
+        let hashes = match options.hashes.is_empty() {
+            true => api
+                .torrents(None)
+                .await?
+                .into_iter()
+                .map(|x| x.hash.unwrap().to_lowercase())
+                .collect(),
+            false => options.hashes.clone(),
+        };
+        return verify_torrents(Some(api), options, &hashes).await;
+    }
+
+    // merges planned by a previous run, the torrents are those of the plans
+    let saved_plans = match &options.apply_plan {
+        Some(path) => read_plan_file(path)?,
+        None => Vec::new(),
+    };
+    let saved_pairs: Vec<(String, String)> = saved_plans
+        .iter()
+        .map(|plan| {
+            let hash = |name| plan[name].as_str().unwrap_or_default().to_owned();
+            (hash("src_hash"), hash("dst_hash"))
+        })
+        .collect();
+
+    // with an offline donor, all the hashes are destinations
+    let hashes: Vec<String> = if options.apply_plan.is_some() {
+        saved_pairs
+            .iter()
+            .map(|(_, dst_hash)| dst_hash.clone())
+            .unique()
+            .collect()
+    } else if let Some(dst_hash) = &options.dst_hash {
+        options
+            .src_hashes
+            .iter()
+            .chain([dst_hash])
+            .cloned()
+            .collect()
+    } else if options.hashes.is_empty()
+        || (options.hashes.len() < 2 && options.offline_donor().is_none())
+    {
+        api.torrents(None)
+            .await?
+            .into_iter()
+            .map(|x| x.hash.unwrap().to_lowercase())
+            .collect()
+    } else {
+        options.hashes.clone()
+    };
+    let hashes = hashes.as_slice();
+
+    info!("hashes: {:?}", hashes);
+
+    if options.confirm_hashes && !confirm_hashes(api, hashes).await? {
+        info!("Aborted");
+        return Ok(RunOutcome::Complete);
+    }
+
+    if options.only_missing_in_both {
+        let mut torrents = Vec::new();
+        for hash in hashes {
+            match Torrent::new(api, hash).await {
+                Ok(torrent) => torrents.push(torrent),
+                Err(e) => error!("{}: {}", hash, e),
+            }
+        }
+        report_missing_everywhere(&torrents);
+        return Ok(RunOutcome::Complete);
+    }
+
+    if options.scan {
+        scan_torrents(api, options, hashes).await?;
+        return Ok(RunOutcome::Complete);
+    }
+
+    if options.compare_only {
+        let store = open_store(options)?;
+        for (src_hash, dst_hash) in hashes.iter().tuple_combinations() {
+            if let Err(e) = compare_torrents(api, options, store.as_ref(), src_hash, dst_hash).await
+            {
+                error!("{} / {}: {}", src_hash, dst_hash, e);
+            }
+        }
+        return Ok(RunOutcome::Complete);
+    }
+
+    let engine = MergeEngine::new(api, options)?;
+    engine.interrupt_on_ctrl_c();
+
+    // the daemon backend can't write yet, fail before pausing anything
+    let must_check_writable =
+        options.check_writable || options.write_backend == WriteBackend::Daemon;
+    if must_check_writable && !options.is_read_only() {
+        for hash in hashes {
+            check_writable(api, engine.store.as_ref(), hash).await?;
+        }
+    }
+
+    // Nothing is written in read only modes, torrents can keep running
+    if !options.is_read_only() {
+        let paused = match options.offline_donor() {
+            Some(_) => hashes.to_vec(),
+            None if options.apply_plan.is_some() => hashes.to_vec(),
+            None if options.dst_hash.is_some() => options.dst_hash.iter().cloned().collect(),
+            None => vec![hashes[1].clone()],
+        };
+        // remember what we paused, in case we crash before resuming
+        write_paused_torrents(&paused)?;
+        api.pause(&paused).await?;
+        //api.pause(hashes).await?;
+        info!("plop");
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    let mut batch_report = BatchReport::default();
+    // torrents that got data, the only ones that need a recheck
+    let mut modified = BTreeSet::new();
+
+    // pieces that each torrent had before merging, and pieces that were restored since
+    let mut pieces_have: HashMap<&str, (i64, u64)> = HashMap::new();
+    if options.verify_after_recheck && !options.is_read_only() {
+        for hash in hashes {
+            let properties = api.properties(hash).await?;
+            pieces_have.insert(hash, (properties.pieces_have.unwrap_or_default(), 0));
+        }
+    }
+
+    let groups = match options.group_by {
+        Some(GroupBy::Category) => group_by_category(api, hashes).await?,
+        None => vec![hashes.to_vec()],
+    };
+
+    #[allow(unused_mut)]
+    let mut pairs = match options.offline_donor() {
+        _ if options.apply_plan.is_some() => saved_pairs,
+        // one destination, the donors in the given order
+        _ if options.dst_hash.is_some() => options
+            .dst_hash
+            .iter()
+            .flat_map(|dst_hash| {
+                let pair = |src_hash: &String| (src_hash.clone(), dst_hash.clone());
+                options.src_hashes.iter().map(pair)
+            })
+            .collect(),
+        Some(donor) => hashes
+            .iter()
+            .map(|hash| (donor.clone(), hash.clone()))
+            .collect(),
+        None => donor_pairs(&groups),
+    };
+    // saved plans are applied in the order they were reviewed
+    #[cfg(feature = "script")]
+    if let (Some(script), None) = (&options.script, &options.apply_plan) {
+        // stable, donors with the same rank keep the default order
+        pairs.sort_by_cached_key(|(src_hash, dst_hash)| script.donor_rank(src_hash, dst_hash));
+    }
+
+    let mut planned = Vec::new();
+    let mut pair_reports = Vec::new();
+    // Loop over all couple of hashes, (src, dst) and (dst, src)
+    for (i, (src_hash, dst_hash)) in pairs.iter().enumerate() {
+        // pieces of the source, to know if it got new data since the last merge
+        let src_pieces_have = match &options.marker_dir {
+            Some(dir)
+                if !options.is_read_only()
+                    && options.offline_donor().is_none()
+                    && options.apply_plan.is_none() =>
+            {
+                let src_pieces_have = api
+                    .properties(src_hash)
+                    .await?
+                    .pieces_have
+                    .unwrap_or_default();
+                let marker = read_marker(&marker_path(dir, dst_hash))?;
+                if !options.force && marker_is_up_to_date(&marker, src_hash, src_pieces_have) {
+                    info!(
+                        "{} was already merged into {}, skipping (use --force to merge again)",
+                        src_hash, dst_hash
+                    );
+                    continue;
+                }
+                Some(src_pieces_have)
+            }
+            _ => None,
+        };
+
+        if options.estimate_only {
+            match engine.plan(src_hash, dst_hash).await {
+                Ok(plan) => info!(
+                    "{} -> {}: {} pieces to read, {} bytes",
+                    src_hash,
+                    dst_hash,
+                    plan.planned_pieces(),
+                    plan.planned_bytes()
+                ),
+                Err(e) => error!("{}", e),
+            }
+            continue;
+        }
+
+        if options.plan_file.is_some() {
+            match engine.plan(src_hash, dst_hash).await {
+                Ok(plan) if plan.planned_pieces() > 0 => planned.push(plan.to_json()),
+                Ok(_) => info!("{} -> {}: nothing to restore", src_hash, dst_hash),
+                Err(e) => error!("{}", e),
+            }
+            continue;
+        }
+
+        let result = match options.apply_plan {
+            Some(_) => match engine.load_plan(&saved_plans[i]).await {
+                Ok(plan) => engine.execute(plan).await,
+                Err(e) => Err(e),
+            },
+            None => engine.merge(src_hash, dst_hash).await,
+        };
+        match &result {
+            Ok(report) => {
+                // pieces restored by an interrupted run were not rechecked yet
+                if report.restored_pieces > 0 || report.resumed_pieces > 0 {
+                    modified.insert(dst_hash.clone());
+                }
+                if let Some((_, restored)) = pieces_have.get_mut(dst_hash.as_str()) {
+                    *restored += report.restored_pieces;
+                }
+                // an interrupted pair must be merged again
+                if let (Some(dir), Some(src_pieces_have), false) =
+                    (&options.marker_dir, src_pieces_have, report.interrupted)
+                {
+                    update_marker(dir, dst_hash, src_hash, src_pieces_have, report)?;
+                }
+            }
+            Err(e) => error!("{}", e),
+        }
+        if options.output == OutputFormat::Json {
+            pair_reports.push(pair_json(src_hash, dst_hash, &result));
+        }
+        batch_report.add(result.as_ref().ok());
+        if engine.is_interrupted() {
+            warn!("Interrupted, the other pairs are left alone");
+            break;
+        }
+    }
+    batch_report.interrupted |= engine.is_interrupted();
+
+    if let Some(path) = &options.plan_file {
+        let mut f = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut f, &planned)?;
+        writeln!(f)?;
+        f.flush()?;
+        info!("Wrote {} merge plans to {}", planned.len(), path);
+        return Ok(RunOutcome::Complete);
+    }
+
+    batch_report.log();
+
+    if options.output == OutputFormat::Json {
+        write_json_report(
+            options.report_file.as_deref(),
+            &batch_report.to_json(pair_reports),
+        )?;
+        if let Some(path) = &options.report_file {
+            info!("Wrote the report to {}", path);
+        }
+    }
+
+    if let Some(path) = &options.emit_patch {
+        write_patch(path, &batch_report.patch)?;
+        info!(
+            "Wrote {} planned writes to {}",
+            batch_report.patch.len(),
+            path
+        );
+    }
+
+    if let Some(path) = &options.report_unavailable_files {
+        write_coverage_gaps(path, &batch_report.coverage_gaps)?;
+        info!(
+            "Wrote {} files with donor coverage gaps to {}",
+            batch_report.coverage_gaps.len(),
+            path
+        );
+    }
+
+    if let Some(path) = &options.export_missing {
+        write_missing(path, batch_report.still_missing())?;
+        info!(
+            "Wrote {} files with missing pieces to {}",
+            batch_report.still_missing().count(),
+            path
+        );
+    }
+
+    let outcome = batch_report.outcome();
+    if options.is_read_only() {
+        return Ok(outcome);
+    }
+
+    // nothing is rechecked: the next run with the same --state-file does it
+    if outcome == RunOutcome::Interrupted {
+        if options.resume_on_interrupt {
+            let paused = read_paused_torrents()?;
+            info!("Resuming the paused torrents: {:?}", paused);
+            api.resume(&paused).await?;
+            std::fs::remove_file(paused_torrents_path()).or_else(ignore_not_found)?;
+        } else {
+            info!("The torrents are left paused, resume them with --resume-on-start");
+        }
+        return Ok(outcome);
+    }
+
+    if options.prioritize_missing {
+        for (dst_hash, files) in &batch_report
+            .still_missing()
+            .group_by(|f| f.dst_hash.as_str())
+        {
+            let indexes: Vec<i64> = files.map(|f| f.index as i64).collect();
+            info!("{}: raising priority of {} files", dst_hash, indexes.len());
+            api.set_file_priority(dst_hash, &indexes, Priority::Maximal)
+                .await?;
+        }
+    }
+
+    // a single request for all the torrents, rather than one per pair
+    let modified: Vec<String> = modified.into_iter().collect();
+    let recheck = !modified.is_empty()
+        && (options.assume_yes_recheck
+            || ask_yes_no(&format!("Recheck {} modified torrents?", modified.len()))?);
+    if !recheck {
+        info!("No recheck, qBittorrent won't know about restored pieces until the torrents are rechecked");
+    } else {
+        api.recheck(&modified).await?;
+        info!("Rechecking {} torrents...", modified.len());
+    }
+
+    let mut damaged = false;
+    if recheck && options.verify_after_recheck {
+        for hash in &modified {
+            wait_for_check(api, hash).await?;
+            let (before, restored) = pieces_have[hash.as_str()];
+            let after = api.properties(hash).await?.pieces_have.unwrap_or_default();
+
+            if after < before {
+                damaged = true;
+                error!(
+                    "{}: {} pieces before merging, only {} after recheck. Existing data was damaged!",
+                    hash, before, after
+                );
+            } else if after < before + restored as i64 {
+                warn!(
+                    "{}: expected {} pieces after recheck ({} + {} restored), got {}",
+                    hash,
+                    before + restored as i64,
+                    before,
+                    restored,
+                    after
+                );
+            } else {
+                info!(
+                    "{}: {} pieces before merging, {} after recheck ({:+})",
+                    hash,
+                    before,
+                    after,
+                    after - before
+                );
+            }
+        }
+    } else if recheck {
+        std::thread::sleep(Duration::from_secs(10));
+    }
+    if options.keep_paused {
+        info!("Leaving the torrents paused");
+    } else {
+        api.resume(hashes).await?;
+    }
+    std::fs::remove_file(paused_torrents_path()).or_else(ignore_not_found)?;
+
+    // the client now knows about the restored pieces, there is nothing to resume
+    if let Some(path) = &options.state_file {
+        if recheck || modified.is_empty() {
+            drop(engine);
+            std::fs::remove_file(path).or_else(ignore_not_found)?;
+        }
+    }
+
+    Ok(if damaged {
+        RunOutcome::Damaged
+    } else {
+        outcome
+    })
+}
+
+/// How torrents are grouped before looking for pairs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GroupBy {
+    Category,
+}
+
+impl std::str::FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "category" => Ok(GroupBy::Category),
+            _ => Err(format!("unknown grouping {:?}", s)),
+        }
+    }
+}
+
+/// Order in which destination files are restored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PriorityOrder {
+    /// Files with the highest priority in qBittorrent first
+    HighPriorityFilesFirst,
+    /// Files in torrent order, e.g. to get the beginning of media first
+    Sequential,
+    /// Biggest files first
+    LargestFileFirst,
+}
+
+impl std::str::FromStr for PriorityOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "high-priority-files-first" => Ok(PriorityOrder::HighPriorityFilesFirst),
+            "sequential" => Ok(PriorityOrder::Sequential),
+            "largest-file-first" => Ok(PriorityOrder::LargestFileFirst),
+            _ => Err(format!("unknown priority order {:?}", s)),
+        }
+    }
+}
+
+/// Pattern selecting destination files by name, for `Options::include` and `Options::exclude`
+///
+/// A glob (`*.mkv`, `Season 1/**`), or a regex after `re:` (`re:(?i)\.(mkv|mp4)$`). Globs without `/` match the file
+/// name, others the whole name in the torrent: `*` and `?` stay in a folder, `**` crosses folders. Regexes search the
+/// whole name
+#[derive(Debug, Clone)]
+pub struct FileFilter {
+    pattern: String,
+    regex: regex::Regex,
+}
+
+impl FileFilter {
+    /// The file `name` of a torrent matches the pattern
+    pub fn is_match(&self, name: &str) -> bool {
+        match self.pattern.starts_with("re:") || self.pattern.contains('/') {
+            true => self.regex.is_match(name),
+            false => self
+                .regex
+                .is_match(name.rsplit_once('/').map_or(name, |(_, file)| file)),
+        }
+    }
+}
+
+/// Regex of a glob, anchored on both ends
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `a/**/b` also matches `a/b`
+                match chars.peek() {
+                    Some('/') => {
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    }
+                    _ => regex.push_str(".*"),
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == '\\' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+impl std::str::FromStr for FileFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let regex = match s.strip_prefix("re:") {
+            Some(regex) => regex.to_owned(),
+            None => glob_to_regex(s),
+        };
+        Ok(FileFilter {
+            pattern: s.to_owned(),
+            regex: regex::Regex::new(&regex)
+                .map_err(|e| format!("invalid pattern {:?}: {}", s, e))?,
+        })
+    }
+}
+
+impl std::fmt::Display for FileFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// Torrents that may take a role, donor or destination, by qBittorrent category and tag
+///
+/// Empty lists allow everything. Torrents of clients without categories or tags only pass empty lists
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoleFilter {
+    /// The torrent is in one of these categories
+    pub categories: Vec<String>,
+    /// The torrent has one of these tags
+    pub tags: Vec<String>,
+}
+
+impl RoleFilter {
+    fn matches(&self, torrent: &TorrentInfo) -> bool {
+        let category = torrent.category.as_deref().unwrap_or_default();
+        // tags are comma separated, e.g. "cross-seed, archive"
+        let mut tags = torrent
+            .tags
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim);
+
+        (self.categories.is_empty() || self.categories.iter().any(|c| c == category))
+            && (self.tags.is_empty() || tags.any(|tag| self.tags.iter().any(|t| t == tag)))
+    }
+}
+
+/// Format of the report at the end of a run
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Only the logs
+    Text,
+    /// A JSON report with the outcome of each piece, on stdout or to `Options::report_file`
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+/// Where recovered data is written
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WriteBackend {
+    /// Directly into the files, through the selected `PieceStore`
+    Disk,
+    /// Through the torrent client
+    Daemon,
+}
+
+impl std::str::FromStr for WriteBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disk" => Ok(WriteBackend::Disk),
+            "daemon" => Ok(WriteBackend::Daemon),
+            _ => Err(format!("unknown write backend {:?}", s)),
+        }
+    }
+}
+
+/// Options of a merge, see the usage of the `merge` tool
+#[derive(Debug)]
+pub struct Options {
+    /// Config file the options were read from
+    pub config: Option<String>,
+    /// Print the effective configuration, and exit
+    pub print_config: bool,
+    /// Don't ask before rechecking modified torrents
+    pub assume_yes_recheck: bool,
+    /// Show torrent names before doing anything, and ask for confirmation
+    pub confirm_hashes: bool,
+    /// Only report pieces that can be recovered by hash lookup, without reading or writing data
+    pub hash_only: bool,
+    /// Only plan the merges, and report how many pieces and bytes would be read
+    pub estimate_only: bool,
+    /// Only list the pairs of torrents that could be merged, ranked by recoverable bytes
+    pub scan: bool,
+    /// With `scan`, compare a sample of the data of matched files, and drop files that differ
+    pub sample_hash: bool,
+    /// Keep running, and merge new torrents from the complete ones, see `run_daemon`
+    pub daemon: bool,
+    /// With `daemon`, seconds between 2 looks at the client
+    pub poll_interval: u64,
+    /// With `daemon`, new torrents merged at the same time
+    pub concurrent_merges: usize,
+    /// Files of a merge restored at the same time
+    pub jobs: usize,
+    /// Threads hashing the data read, 0 for one per CPU
+    pub hash_threads: usize,
+    /// Format of the report at the end of the run
+    pub output: OutputFormat,
+    /// Where to write the JSON report, stdout when not set
+    pub report_file: Option<String>,
+    /// Record the pieces processed in this file, and skip those that an interrupted run processed
+    pub state_file: Option<String>,
+    /// Save the original data of the blocks that are written to this journal, see `undo_journal`
+    pub journal: Option<String>,
+    /// Write back the original data saved in this journal, instead of merging
+    pub undo_journal: Option<String>,
+    /// Hash-check the data of the torrents on disk, and log which pieces are intact. Nothing is written
+    pub verify: bool,
+    /// With `verify`, check this .torrent file instead of the torrents of the client
+    pub verify_torrent_file: Option<String>,
+    /// Directory the data of `verify_torrent_file` was downloaded to, its save path
+    pub verify_data: Option<String>,
+    /// Only plan the merges, and save the plans as JSON to this file
+    pub plan_file: Option<String>,
+    /// Execute the plans saved to this file, instead of planning the merges
+    pub apply_plan: Option<String>,
+    /// With `scan` and `daemon`, torrents that can be donors
+    pub src_filter: RoleFilter,
+    /// With `scan` and `daemon`, torrents that can be destinations
+    pub dst_filter: RoleFilter,
+    /// Only report pieces that no torrent has
+    pub only_missing_in_both: bool,
+    /// Compare shared files, nothing is written
+    pub compare_only: bool,
+    /// Read and check data, but don't write anything
+    pub dry_run: bool,
+    /// Where to save the writes of a dry run
+    pub emit_patch: Option<String>,
+    /// Resume torrents that a previous run paused and did not resume
+    pub resume_on_start: bool,
+    /// Recheck the source before reading from it, so that pieces are really on disk
+    pub force_recheck_source: bool,
+    /// Only restore pieces when the donor has the very same piece (same size and hash), never rebuild a piece from several donor pieces
+    pub pieces_from_donor_only: bool,
+    /// With `pieces_from_donor_only`, don't fetch the donor pieces hashes: donor pieces are not checked to be identical
+    /// before being read, they are only checked against the destination hash once read
+    pub trust_donor_state: bool,
+    /// Copy the pieces that are identical in a source client (same size, place and hash, and downloaded) without
+    /// hashing them before writing: they are still checked once written. Offline donors are never trusted
+    pub trust_identical_pieces: bool,
+    /// Read destination pieces before writing them, and don't write those that are already correct
+    pub skip_correct_pieces: bool,
+    /// Check that destination files can be written before pausing torrents
+    pub check_writable: bool,
+    /// Follow symlinks in save paths
+    pub resolve_symlinks: bool,
+    /// Wait for the recheck, and make sure that no torrent lost pieces
+    pub verify_after_recheck: bool,
+    /// Leave the merged torrents paused at the end, instead of resuming them
+    pub keep_paused: bool,
+    /// Resume the paused torrents when the run is interrupted by Ctrl-C, instead of leaving them paused
+    pub resume_on_interrupt: bool,
+    /// Also restore destination files that are not wanted ("do not download")
+    pub include_unwanted: bool,
+    /// Only restore destination files that match one of these, all of them when empty
+    pub include: Vec<FileFilter>,
+    /// Don't restore destination files that match one of these
+    pub exclude: Vec<FileFilter>,
+    /// Ignore matched files smaller than this
+    pub min_file_size: Option<u64>,
+    /// Ignore matched files bigger than this
+    pub max_file_size: Option<u64>,
+    /// Ignore destination files missing fewer pieces than this, 0 restores every file
+    pub min_missing_pieces: usize,
+    /// Only merge torrents that are in the same group
+    pub group_by: Option<GroupBy>,
+    /// Missing pieces in these states are not restored
+    pub exclude_piece_states: Vec<PieceState>,
+    /// Order of the destination files, by size when not set
+    pub priority_order: Option<PriorityOrder>,
+    /// Skip destination files when a bigger part of them would be overwritten
+    pub max_overwrite_fraction: Option<f64>,
+    /// Where to save the pieces that are still missing after merging
+    pub export_missing: Option<String>,
+    /// Where to log, as CSV, each destination piece read from a source
+    pub piece_log: Option<String>,
+    /// Where to save, as JSON, the ranges of each file that donors don't have
+    pub report_unavailable_files: Option<String>,
+    /// Raise the priority of files that are still missing pieces
+    pub prioritize_missing: bool,
+    /// Directory of the `.merged.json` markers
+    pub marker_dir: Option<String>,
+    /// Merge pairs again even if their marker is up to date
+    pub force: bool,
+    /// Maximum number of files open at the same time
+    pub max_open_files: usize,
+    /// Bytes of recently read blocks kept in memory, 0 to disable
+    pub read_cache: u64,
+    /// Pieces bigger than this are hashed and copied in chunks of this size instead of being read at once
+    pub max_buffer: u64,
+    /// Bytes read per second, at most
+    pub max_read_rate: Option<u64>,
+    /// Bytes written per second, at most
+    pub max_write_rate: Option<u64>,
+    /// Log a progress summary every this many seconds, 0 to disable
+    pub stats_interval: u64,
+    /// Draw progress bars of the merges there, logs should go through it too so that they don't break the bars
+    pub progress: Option<MultiProgress>,
+    /// qBittorrent WebUI URL
+    pub url: String,
+    /// Transmission RPC URL, its torrents are merged with those of qBittorrent
+    pub transmission: Option<String>,
+    /// Deluge web UI JSON-RPC URL, its torrents are merged with those of qBittorrent
+    pub deluge: Option<String>,
+    /// State directory of deluged, where the .torrent files giving piece hashes are
+    pub deluge_state_dir: Option<String>,
+    /// Directories that the clients see under another path than this tool, e.g. in a container
+    pub path_map: Vec<PathMapping>,
+    /// rTorrent XML-RPC URL, over HTTP or SCGI, its torrents are merged with those of qBittorrent
+    pub rtorrent: Option<String>,
+    /// Access torrent data on this `user@host[:port]` over SFTP, instead of the local filesystem
+    pub sftp: Option<String>,
+    /// Read donor files from `<file>.zst` or `<file>.gz` when they don't exist
+    pub compressed_donors: bool,
+    /// Read local files through memory mappings, pieces are hashed without being copied
+    pub mmap: bool,
+    /// Read and write local files through io_uring, requires the io-uring feature
+    pub io_uring: bool,
+    /// Directory of loose files used as the only donor, all the hashes are destinations
+    pub donor_dir: Option<String>,
+    /// .torrent file of a donor that is in no client, all the hashes are destinations
+    pub src_torrent: Option<String>,
+    /// Save path of the data of `src_torrent`
+    pub src_data: Option<String>,
+    /// Create destination files that don't exist, with their size, instead of failing to write them
+    pub create_missing: bool,
+    /// Allocate the blocks of the files created by `create_missing`, instead of leaving them sparse
+    pub preallocate: bool,
+    /// Where recovered data is written
+    pub write_backend: WriteBackend,
+    /// Script choosing pieces and donors
+    #[cfg(feature = "script")]
+    pub script: Option<PieceScript>,
+    /// Torrents to merge. If less than 2 are given, all torrents are used
+    pub hashes: Vec<String>,
+    /// Only destination, restored from `src_hashes` instead of merging `hashes` with each other
+    pub dst_hash: Option<String>,
+    /// Donors of `dst_hash`, in order: each missing piece comes from the first one that has it
+    pub src_hashes: Vec<String>,
+}
+
+impl Options {
+    /// Nothing will be written to torrents
+    fn is_read_only(&self) -> bool {
+        self.hash_only
+            || self.estimate_only
+            || self.scan
+            || self.plan_file.is_some()
+            || self.verify
+            || self.dry_run
+            || self.compare_only
+    }
+
+    /// Donor that is not in a client, loose files or a .torrent file, given instead of a source hash
+    fn offline_donor(&self) -> Option<&String> {
+        self.donor_dir.as_ref().or(self.src_torrent.as_ref())
+    }
+
+    /// Donor pieces hashes are not needed: only identical pieces are restored, and donors are trusted.
+    /// `--hash-only` works on hashes alone, they are always fetched
+    fn skips_donor_hashes(&self) -> bool {
+        self.pieces_from_donor_only && self.trust_donor_state && !self.hash_only
+    }
+
+    /// Path used to read and write a file: symlinks are resolved, unless disabled or files are remote
+    fn resolve_path(&self, path: String) -> String {
+        if self.resolve_symlinks && self.sftp.is_none() {
+            resolve_symlinks(path)
+        } else {
+            path
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            config: None,
+            print_config: false,
+            assume_yes_recheck: false,
+            confirm_hashes: false,
+            hash_only: false,
+            estimate_only: false,
+            scan: false,
+            sample_hash: false,
+            daemon: false,
+            poll_interval: 60,
+            concurrent_merges: 1,
+            jobs: 1,
+            hash_threads: 0,
+            output: OutputFormat::Text,
+            report_file: None,
+            state_file: None,
+            journal: None,
+            undo_journal: None,
+            verify: false,
+            verify_torrent_file: None,
+            verify_data: None,
+            plan_file: None,
+            apply_plan: None,
+            src_filter: RoleFilter::default(),
+            dst_filter: RoleFilter::default(),
+            only_missing_in_both: false,
+            compare_only: false,
+            dry_run: false,
+            emit_patch: None,
+            resume_on_start: false,
+            force_recheck_source: false,
+            pieces_from_donor_only: false,
+            trust_donor_state: false,
+            trust_identical_pieces: false,
+            skip_correct_pieces: false,
+            check_writable: false,
+            resolve_symlinks: true,
+            verify_after_recheck: false,
+            keep_paused: false,
+            resume_on_interrupt: false,
+            include_unwanted: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            min_file_size: None,
+            max_file_size: None,
+            min_missing_pieces: 0,
+            group_by: None,
+            exclude_piece_states: Vec::new(),
+            priority_order: None,
+            max_overwrite_fraction: None,
+            export_missing: None,
+            piece_log: None,
+            report_unavailable_files: None,
+            prioritize_missing: false,
+            marker_dir: None,
+            force: false,
+            max_open_files: 64,
+            read_cache: 32 * 1024 * 1024,
+            max_buffer: CHUNK_SIZE,
+            max_read_rate: None,
+            max_write_rate: None,
+            stats_interval: 0,
+            progress: None,
+            url: "http://localhost:8080".to_owned(),
+            transmission: None,
+            deluge: None,
+            deluge_state_dir: None,
+            path_map: Vec::new(),
+            rtorrent: None,
+            sftp: None,
+            compressed_donors: false,
+            mmap: false,
+            io_uring: false,
+            donor_dir: None,
+            src_torrent: None,
+            src_data: None,
+            create_missing: false,
+            preallocate: false,
+            write_backend: WriteBackend::Disk,
+            #[cfg(feature = "script")]
+            script: None,
+            hashes: Vec::new(),
+            dst_hash: None,
+            src_hashes: Vec::new(),
+        }
+    }
+}
+
+/// Make sure the WebUI URL ends with a single `/`
+///
+/// API paths are joined to it, and without the trailing `/` the last path segment would be replaced
+pub fn normalize_url(url: &str) -> String {
+    format!("{}/", url.trim_end_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_sha1(data: &[u8]) -> PieceHash {
+        PieceHash::V1(Sha1::digest(data).into())
+    }
+
+    fn test_torrent(piece_size: u64, files: &[(&str, u64)]) -> Torrent {
+        let total_size: u64 = files.iter().map(|(_, size)| size).sum();
+        let pieces_num = total_size.div_ceil(piece_size) as usize;
+        let properties = serde_json::from_value(serde_json::json!({
+            "piece_size": piece_size,
+            "pieces_num": pieces_num,
+            "pieces_have": 0,
+            "total_size": total_size,
+        }))
+        .unwrap();
+        let content = files
+            .iter()
+            .enumerate()
+            .map(|(index, (name, size))| TorrentContent {
+                index: index as u64,
+                name: name.to_string(),
+                size: *size,
+                progress: 0.,
+                priority: Priority::Normal,
+                is_seed: None,
+                piece_range: vec![],
+                availability: 0.,
+            })
+            .collect();
+
+        Torrent::from_parts(
+            &"0".repeat(40),
+            properties,
+            content,
+            vec![PieceState::NotDownloaded; pieces_num],
+            vec![PieceHash::V1([0xff; 20]); pieces_num],
+        )
+        .unwrap()
+    }
+
+    fn preferences(temp_path_enabled: bool) -> Preferences {
+        Preferences {
+            temp_path_enabled: Some(temp_path_enabled),
+            temp_path: Some("/temp".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn torrent(files: &[(&str, f64, Priority)]) -> (TorrentProperty, Vec<TorrentContent>) {
+        let content: Vec<TorrentContent> = files
+            .iter()
+            .enumerate()
+            .map(|(index, (name, progress, priority))| TorrentContent {
+                index: index as u64,
+                name: name.to_string(),
+                size: 100,
+                progress: *progress,
+                priority: *priority,
+                is_seed: None,
+                piece_range: vec![],
+                availability: 0.,
+            })
+            .collect();
+        let pieces_num = content.len() * 10;
+        let pieces_have: f64 = content.iter().map(|f| f.progress * 10.).sum();
+        let properties = serde_json::from_value(serde_json::json!({
+            "save_path": "/save",
+            "pieces_num": pieces_num,
+            "pieces_have": pieces_have as u64,
+        }))
+        .unwrap();
+
+        (properties, content)
+    }
+
+    #[test]
+    fn complete_torrent() {
+        let (properties, content) =
+            torrent(&[("a", 1., Priority::Normal), ("b", 1., Priority::Normal)]);
+
+        assert_eq!(
+            resolve_file_path(&preferences(true), &properties, &content, "b"),
+            PathBuf::from("/save/b")
+        );
+    }
+
+    #[test]
+    fn incomplete_torrent() {
+        let (properties, content) =
+            torrent(&[("a", 0.5, Priority::Normal), ("b", 0., Priority::Normal)]);
+
+        assert_eq!(
+            resolve_file_path(&preferences(true), &properties, &content, "a"),
+            PathBuf::from("/temp/a")
+        );
+        // temp path disabled in qBittorrent
+        assert_eq!(
+            resolve_file_path(&preferences(false), &properties, &content, "a"),
+            PathBuf::from("/save/a")
+        );
+    }
+
+    #[test]
+    fn partially_complete_torrent() {
+        // complete files stay with the others until the torrent is finished
+        let (properties, content) =
+            torrent(&[("a", 1., Priority::Normal), ("b", 0.5, Priority::Normal)]);
+        assert_eq!(
+            resolve_file_path(&preferences(true), &properties, &content, "a"),
+            PathBuf::from("/temp/a")
+        );
+
+        // missing files are not wanted, the torrent is finished
+        let (properties, content) = torrent(&[
+            ("a", 1., Priority::Normal),
+            ("b", 0.5, Priority::DoNotDownload),
+        ]);
+        assert_eq!(
+            resolve_file_path(&preferences(true), &properties, &content, "a"),
+            PathBuf::from("/save/a")
+        );
+    }
+
+    #[test]
+    fn nested_file() {
+        let (properties, content) = torrent(&[("folder/a", 1., Priority::Normal)]);
+
+        let path = resolve_file_path(&preferences(true), &properties, &content, "folder/a");
+        assert_eq!(path, PathBuf::from("/save").join("folder").join("a"));
+    }
+
+    #[test]
+    fn content_layout() {
+        let names = |content: Vec<TorrentContent>| -> Vec<String> {
+            content.into_iter().map(|f| f.name).collect()
+        };
+        let (_, content) = torrent(&[
+            ("root/a", 1., Priority::Normal),
+            ("root/sub/b", 1., Priority::Normal),
+        ]);
+
+        // as expected
+        let layout = with_content_layout("t", content.clone(), "/save", "/save/root");
+        assert_eq!(names(layout), ["root/a", "root/sub/b"]);
+        // renamed root folder
+        let layout = with_content_layout("t", content.clone(), "/save/", "/save/renamed");
+        assert_eq!(names(layout), ["renamed/a", "renamed/sub/b"]);
+        // no subfolder
+        let layout = with_content_layout("t", content.clone(), "/save", "/save");
+        assert_eq!(names(layout), ["a", "sub/b"]);
+        // somewhere else
+        let layout = with_content_layout("t", content, "/save", "/other/root");
+        assert_eq!(names(layout), ["root/a", "root/sub/b"]);
+
+        let (_, content) = torrent(&[("a.mkv", 0.5, Priority::Normal)]);
+        let layout = with_content_layout("t", content, "/temp", "/temp/b.mkv.!qB");
+        assert_eq!(names(layout), ["b.mkv"]);
+    }
+
+    #[test]
+    fn incomplete_files_extension() {
+        let preferences = Preferences {
+            incomplete_files_ext: Some(".!qB".to_owned()),
+            ..preferences(false)
+        };
+        let (properties, content) =
+            torrent(&[("a", 1., Priority::Normal), ("b", 0.5, Priority::Normal)]);
+
+        // only incomplete files are renamed
+        assert_eq!(
+            resolve_file_path(&preferences, &properties, &content, "a"),
+            PathBuf::from("/save/a")
+        );
+        assert_eq!(
+            resolve_file_path(&preferences, &properties, &content, "b"),
+            PathBuf::from("/save/b.!qB")
+        );
+        assert_eq!(
+            resolve_file_path(&Default::default(), &properties, &content, "b"),
+            PathBuf::from("/save/b")
+        );
+
+        // Transmission
+        let preferences = Preferences {
+            incomplete_files_ext: Some(".part".to_owned()),
+            ..preferences
+        };
+        assert_eq!(
+            resolve_file_path(&preferences, &properties, &content, "b"),
+            PathBuf::from("/save/b.part")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_save_paths() {
+        let (mut properties, content) = torrent(&[("folder/a", 1., Priority::Normal)]);
+
+        properties.save_path = Some(r"D:\Downloads".to_owned());
+        let path = resolve_file_path(&preferences(true), &properties, &content, "folder/a");
+        assert_eq!(path.to_str(), Some(r"D:\Downloads\folder\a"));
+
+        properties.save_path = Some(r"\\nas\share\".to_owned());
+        let path = resolve_file_path(&preferences(true), &properties, &content, "folder/a");
+        assert_eq!(path.to_str(), Some(r"\\nas\share\folder\a"));
+
+        // forward slashes, as qBittorrent shows them
+        properties.save_path = Some("D:/Downloads".to_owned());
+        let path = resolve_file_path(&preferences(true), &properties, &content, "folder/a");
+        assert_eq!(path, PathBuf::from(r"D:\Downloads\folder\a"));
+    }
+
+    #[test]
+    fn verbatim_prefix() {
+        assert_eq!(strip_verbatim_prefix(r"\\?\D:\Downloads"), r"D:\Downloads");
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\UNC\nas\share\a"),
+            r"\\nas\share\a"
+        );
+        // no usual form
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\Volume{01234567}\a"),
+            r"\\?\Volume{01234567}\a"
+        );
+        assert_eq!(strip_verbatim_prefix("/save/a"), "/save/a");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn symlinks_resolved_without_verbatim_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_owned();
+        let resolved = resolve_symlinks(path);
+        assert!(!resolved.starts_with(r"\\?\"), "{}", resolved);
+    }
+
+    #[test]
+    fn content_in_reverse_order() {
+        let mut torrent = test_torrent(16, &[("a", 20), ("b", 40), ("c", 4)]);
+        torrent.content.reverse();
+        let torrent = Torrent::from_parts(
+            &torrent.hash,
+            torrent.properties,
+            torrent.content,
+            torrent.pieces_states,
+            torrent.pieces_hashes,
+        )
+        .unwrap();
+
+        assert_eq!(get_file_offset(&torrent.content, "a").unwrap(), 0);
+        assert_eq!(get_file_offset(&torrent.content, "b").unwrap(), 20);
+        assert_eq!(get_file_offset(&torrent.content, "c").unwrap(), 60);
+        assert_eq!(torrent.piece_range_for_file("c").unwrap(), 3..4);
+    }
+
+    #[test]
+    fn verify_piece_in_sparse_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sparse");
+        let path = path.to_str().unwrap();
+
+        // 3 pieces of 16 bytes, nothing written yet
+        File::create(path).unwrap().set_len(48).unwrap();
+
+        let data = [0xab; 16];
+        let file_block = FileBlock {
+            offset: 16,
+            size: 16,
+        };
+        LocalStore::default()
+            .write_block(path, file_block, &data)
+            .unwrap();
+
+        assert!(blocks_have_hash(
+            &LocalStore::default(),
+            &[(path, file_block)],
+            &get_sha1(&data),
+            0,
+            CHUNK_SIZE,
+        )
+        .unwrap());
+
+        // a block overlapping a hole reads zeros there, and does not match
+        let overlapping_block = FileBlock {
+            offset: 8,
+            size: 16,
+        };
+        let mut expected = [0; 16];
+        expected[8..].copy_from_slice(&data[..8]);
+        assert!(!blocks_have_hash(
+            &LocalStore::default(),
+            &[(path, overlapping_block)],
+            &get_sha1(&data),
+            0,
+            CHUNK_SIZE,
+        )
+        .unwrap());
+        assert!(blocks_have_hash(
+            &LocalStore::default(),
+            &[(path, overlapping_block)],
+            &get_sha1(&expected),
+            0,
+            CHUNK_SIZE,
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn missing_piece_size() {
+        let properties: TorrentProperty = serde_json::from_value(serde_json::json!({
+            "total_size": 40,
+            "pieces_num": 3,
+        }))
+        .unwrap();
+        assert_eq!(resolve_piece_size("hash", &properties).unwrap(), 16);
+
+        let properties: TorrentProperty = serde_json::from_value(serde_json::json!({
+            "total_size": 16384 * 100,
+            "pieces_num": 100,
+        }))
+        .unwrap();
+        assert_eq!(resolve_piece_size("hash", &properties).unwrap(), 16384);
+
+        let properties: TorrentProperty = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert!(matches!(
+            resolve_piece_size("hash", &properties),
+            Err(MergeError::NoPieceSize { hash }) if hash == "hash"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_save_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("save");
+        let link = dir.path().join("link");
+        std::fs::create_dir(&save_path).unwrap();
+        std::os::unix::fs::symlink(&save_path, &link).unwrap();
+        File::create(save_path.join("a")).unwrap();
+        File::create(save_path.join("b")).unwrap();
+
+        let real = format!("{}/a", save_path.to_str().unwrap());
+        let linked = format!("{}/a", link.to_str().unwrap());
+        assert_eq!(
+            resolve_symlinks(linked.clone()),
+            resolve_symlinks(real.clone())
+        );
+        assert!(is_same_file(&real, &linked));
+        assert!(!is_same_file(
+            &real,
+            &format!("{}/b", link.to_str().unwrap())
+        ));
+
+        let hardlink = format!("{}/c", save_path.to_str().unwrap());
+        std::fs::hard_link(&real, &hardlink).unwrap();
+        assert!(is_same_file(&hardlink, &linked));
+    }
+
+    #[test]
+    fn chunked_hash_and_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        let (src, dst) = (src.to_str().unwrap(), dst.to_str().unwrap());
+
+        let size = 2 * CHUNK_SIZE + 1000;
+        let data: Vec<u8> = (0..size + 2000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(src, &data).unwrap();
+        File::create(dst)
+            .unwrap()
+            .set_len(data.len() as u64)
+            .unwrap();
+
+        let file_block = FileBlock { offset: 1000, size };
+        assert_eq!(chunks(file_block, CHUNK_SIZE).count(), 3);
+        let expected = get_sha1(&data[1000..1000 + size as usize]);
+        assert_eq!(
+            hash_blocks_chunked(
+                &LocalStore::default(),
+                &[(src, file_block)],
+                expected.hasher(0),
+                CHUNK_SIZE,
+            )
+            .unwrap(),
+            expected
+        );
+
+        copy_block_chunked(&LocalStore::default(), src, dst, file_block, CHUNK_SIZE).unwrap();
+        let copied = std::fs::read(dst).unwrap();
+        assert_eq!(
+            &copied[1000..1000 + size as usize],
+            &data[1000..1000 + size as usize]
+        );
+        assert!(copied[..1000].iter().all(|&b| b == 0));
+        assert!(copied[1000 + size as usize..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn pieces_bigger_than_max_buffer_are_streamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        let (src, dst) = (src.to_str().unwrap(), dst.to_str().unwrap());
+        let data: Vec<u8> = (0..64).collect();
+        std::fs::write(src, &data).unwrap();
+        File::create(dst).unwrap().set_len(64).unwrap();
+
+        let file_block = FileBlock {
+            offset: 16,
+            size: 32,
+        };
+        let planned_read = PlannedRead {
+            dst_piece_idx: 0,
+            segments: vec![PlannedSegment {
+                src_path: src.to_owned(),
+                dst_path: dst.to_owned(),
+                segment: Segment {
+                    src_filename: "src".to_owned(),
+                    dst_filename: "dst".to_owned(),
+                    file_block,
+                },
+            }],
+            hash: get_sha1(&data[16..48]),
+            piece_size: 32,
+            identical: false,
+        };
+
+        // only hashed, 8 bytes at a time
+        let store = CountingStore::default();
+        let read = PlannedRead::read_batch(std::slice::from_ref(&planned_read), &store, 8);
+        let source_data = match read.into_iter().next().unwrap().unwrap() {
+            SourceData::Hashed(hash) => SourceData::Hashed(hash),
+            SourceData::InMemory(_) => panic!("piece read at once"),
+        };
+        assert_eq!(store.reads.load(Ordering::SeqCst), 4);
+        assert_eq!(store.bytes.load(Ordering::SeqCst), 32);
+
+        let written = PlannedRead::write_batch(&[(&planned_read, &source_data)], &store, 8);
+        assert!(written[0].is_ok());
+        assert_eq!(store.reads.load(Ordering::SeqCst), 8);
+        assert_eq!(std::fs::read(dst).unwrap()[16..48], data[16..48]);
+    }
+
+    #[tokio::test]
+    async fn batches_are_hashed_on_the_pool() {
+        let data: Vec<u8> = (0..32).collect();
+        let planned_read = |idx: usize| PlannedRead {
+            dst_piece_idx: idx,
+            segments: Vec::new(),
+            hash: get_sha1(&data[idx * 16..][..16]),
+            piece_size: 16,
+            identical: false,
+        };
+        let batch = [planned_read(0), planned_read(1), planned_read(1)];
+        let batch_data = vec![
+            Ok(SourceData::InMemory(data[..16].to_vec())),
+            Err(std::io::ErrorKind::NotFound.into()),
+            Ok(SourceData::Hashed(batch[1].hash)),
+        ];
+
+        let hashed = HashPool::new(2).unwrap().hash(&batch, batch_data).await;
+        assert_eq!(hashed[0].as_ref().unwrap().1, batch[0].hash);
+        assert!(hashed[1].is_err());
+        assert_eq!(hashed[2].as_ref().unwrap().1, batch[1].hash);
+    }
+
+    #[test]
+    fn token_bucket_limits_the_rate() {
+        let bucket = TokenBucket::new(1000);
+        let started = std::time::Instant::now();
+        // a second of burst
+        bucket.take(1000);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        bucket.take(200);
+        bucket.take(200);
+        assert!(started.elapsed() >= Duration::from_millis(350));
+    }
+
+    /// Local files, counting the reads and the bytes read
+    #[derive(Default)]
+    struct CountingStore {
+        inner: LocalStore,
+        reads: std::sync::atomic::AtomicUsize,
+        bytes: std::sync::atomic::AtomicU64,
+    }
+
+    impl PieceStore for CountingStore {
+        fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.bytes.fetch_add(file_block.size, Ordering::SeqCst);
+            self.inner.read_block(path, file_block)
+        }
+
+        fn write_block(
+            &self,
+            path: &str,
+            file_block: FileBlock,
+            data: &[u8],
+        ) -> std::io::Result<()> {
+            self.inner.write_block(path, file_block, data)
+        }
+
+        fn check_writable(&self, path: &str) -> std::io::Result<()> {
+            self.inner.check_writable(path)
+        }
+    }
+
+    #[test]
+    fn small_pieces_are_read_by_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let src = src.to_str().unwrap();
+        let piece_size = 16 * 1024;
+        let data: Vec<u8> = (0..1000 * piece_size).map(|i| (i % 251) as u8).collect();
+        std::fs::write(src, &data).unwrap();
+
+        let planned_read = |idx: usize, path: &str| {
+            let file_block = FileBlock {
+                offset: (idx * piece_size) as u64,
+                size: piece_size as u64,
+            };
+            PlannedRead {
+                dst_piece_idx: idx,
+                segments: vec![PlannedSegment {
+                    src_path: path.to_owned(),
+                    dst_path: path.to_owned(),
+                    segment: Segment {
+                        src_filename: "src".to_owned(),
+                        dst_filename: "dst".to_owned(),
+                        file_block,
+                    },
+                }],
+                hash: get_sha1(&data[file_block.offset as usize..][..piece_size]),
+                piece_size: piece_size as u64,
+                identical: false,
+            }
+        };
+
+        // 256 pieces of 16 KiB per batch
+        let planned_reads: Vec<PlannedRead> = (0..1000).map(|i| planned_read(i, src)).collect();
+        let batches = batches(&planned_reads, CHUNK_SIZE);
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<_>>(),
+            vec![256, 256, 256, 232]
+        );
+
+        // consecutive pieces are read at once
+        let store = CountingStore::default();
+        let read = PlannedRead::read_batch(batches[1], &store, CHUNK_SIZE);
+        assert_eq!(store.reads.load(Ordering::SeqCst), 1);
+        assert_eq!(read.len(), 256);
+        for (planned_read, source_data) in batches[1].iter().zip(read) {
+            match source_data.unwrap() {
+                SourceData::InMemory(data) => assert_eq!(get_sha1(&data), planned_read.hash),
+                SourceData::Hashed(_) => panic!("small pieces are read"),
+            }
+        }
+
+        // a read for each range
+        let store = CountingStore::default();
+        let gap = [
+            planned_read(0, src),
+            planned_read(2, src),
+            planned_read(3, src),
+        ];
+        let read = PlannedRead::read_batch(&gap, &store, CHUNK_SIZE);
+        assert!(read.iter().all(Result::is_ok));
+        assert_eq!(store.reads.load(Ordering::SeqCst), 2);
+        // missing file: pieces are read again one by one
+        let store = CountingStore::default();
+        let missing = dir.path().join("missing");
+        let missing = [
+            planned_read(0, src),
+            planned_read(0, missing.to_str().unwrap()),
+            planned_read(1, missing.to_str().unwrap()),
+        ];
+        let read = PlannedRead::read_batch(&missing, &store, CHUNK_SIZE);
+        assert!(read[0].is_ok() && read[1].is_err() && read[2].is_err());
+        assert_eq!(store.reads.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn cached_blocks_are_not_read_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let path = path.to_str().unwrap();
+        let data: Vec<u8> = (0..64).collect();
+        std::fs::write(path, &data).unwrap();
+        let block = |offset, size| FileBlock { offset, size };
+
+        let inner = Arc::new(CountingStore::default());
+        let store = CachingStore::new(inner.clone(), 64);
+        assert_eq!(store.read_block(path, block(0, 16)).unwrap(), &data[..16]);
+        // same block, and a part of it
+        assert_eq!(store.read_block(path, block(0, 16)).unwrap(), &data[..16]);
+        assert_eq!(store.read_block(path, block(4, 8)).unwrap(), &data[4..12]);
+        assert_eq!(inner.reads.load(Ordering::SeqCst), 1);
+
+        // only the blocks that are not cached are read
+        let read = store.read_blocks(&[(path, block(0, 16)), (path, block(16, 16))]);
+        assert_eq!(read[1].as_ref().unwrap(), &data[16..32]);
+        assert_eq!(inner.reads.load(Ordering::SeqCst), 2);
+
+        // written blocks are read again
+        store.write_block(path, block(8, 4), &[0; 4]).unwrap();
+        assert_eq!(store.read_block(path, block(0, 16)).unwrap()[8..12], [0; 4]);
+        assert_eq!(
+            store.read_block(path, block(16, 16)).unwrap(),
+            &data[16..32]
+        );
+        assert_eq!(inner.reads.load(Ordering::SeqCst), 3);
+
+        // too big for the cache
+        store.read_block(path, block(0, 32)).unwrap();
+        store.read_block(path, block(0, 32)).unwrap();
+        assert_eq!(inner.reads.load(Ordering::SeqCst), 5);
+
+        store.close_files();
+        store.read_block(path, block(16, 16)).unwrap();
+        assert_eq!(inner.reads.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn mapped_files_are_hashed_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let path = path.to_str().unwrap();
+        let data: Vec<u8> = (0..40).collect();
+        std::fs::write(path, &data).unwrap();
+
+        let store = LocalStore {
+            mmap: true,
+            ..LocalStore::default()
+        };
+        let block = FileBlock {
+            offset: 16,
+            size: 16,
+        };
+        assert_eq!(store.read_block(path, block).unwrap(), &data[16..32]);
+        let hash = get_sha1(&data[16..32]);
+        assert_eq!(
+            hash_blocks_chunked(&store, &[(path, block)], hash.hasher(16), CHUNK_SIZE).unwrap(),
+            hash
+        );
+        // past the end of the file
+        let block = FileBlock {
+            offset: 32,
+            size: 16,
+        };
+        assert_eq!(
+            store.read_block(path, block).unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        assert!(LocalStore::default()
+            .hash_in_place(path, block, &mut hash.hasher(16))
+            .is_none());
+
+        // small pieces are only hashed too, and copied when they match
+        let mut src_data = data.clone();
+        src_data[20] = 0;
+        let (dir, client) = src_dst_fixture(&data, &[0; 40]);
+        std::fs::write(dir.path().join("src/a"), &src_data).unwrap();
+        let options = Options {
+            mmap: true,
+            ..Options::default()
+        };
+        let report = MergeEngine::new(&client, &options)
+            .unwrap()
+            .merge("src", "dst")
+            .await
+            .unwrap();
+        assert_eq!(report.restored_pieces, 2);
+        assert_eq!(report.still_missing[0].pieces, [1]);
+        assert_eq!(
+            std::fs::read(dir.path().join("dst/a")).unwrap()[32..],
+            data[32..]
+        );
+    }
+
+    #[test]
+    fn marker_records_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().join("markers");
+        let dir = dir.to_str().unwrap();
+        let report = MergeReport {
+            restored_pieces: 3,
+            ..Default::default()
+        };
+
+        let marker = read_marker(&marker_path(dir, "dst")).unwrap();
+        assert!(!marker_is_up_to_date(&marker, "src1", 10));
+
+        update_marker(dir, "dst", "src1", 10, &report).unwrap();
+        update_marker(dir, "dst", "src2", 20, &report).unwrap();
+        let marker = read_marker(&marker_path(dir, "dst")).unwrap();
+        assert!(marker_is_up_to_date(&marker, "src1", 10));
+        assert!(marker_is_up_to_date(&marker, "src2", 20));
+        // the source got new pieces since
+        assert!(!marker_is_up_to_date(&marker, "src1", 11));
+        assert_eq!(marker["sources"]["src1"]["restored_pieces"], 3);
+    }
+
+    #[test]
+    fn run_outcomes() {
+        let outcome = |reports: &[Option<MergeReport>]| {
+            let mut batch_report = BatchReport::default();
+            for report in reports {
+                batch_report.add(report.as_ref());
+            }
+            batch_report.outcome()
+        };
+        let restored = MergeReport {
+            restored_pieces: 2,
+            ..Default::default()
+        };
+        let missing = MergeReport {
+            still_missing: vec![MissingFile {
+                dst_hash: "dst".to_owned(),
+                index: 0,
+                path: "a".to_owned(),
+                pieces: vec![1],
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(outcome(&[]), RunOutcome::Complete);
+        assert_eq!(outcome(&[Some(restored.clone())]), RunOutcome::Complete);
+        // no source has the missing pieces
+        assert_eq!(
+            outcome(&[Some(restored.clone()), Some(missing.clone())]),
+            RunOutcome::Complete
+        );
+        assert_eq!(outcome(&[Some(restored), None]), RunOutcome::Partial);
+        assert_eq!(outcome(&[Some(missing)]), RunOutcome::NothingRestored);
+        assert_eq!(outcome(&[None]), RunOutcome::NothingRestored);
+        assert_eq!(RunOutcome::NothingRestored.exit_code(), 3);
+    }
+
+    #[test]
+    fn still_missing_pieces_of_all_sources() {
+        let missing = |pieces: Vec<usize>| MergeReport {
+            still_missing: vec![MissingFile {
+                dst_hash: "dst".to_owned(),
+                index: 0,
+                path: "a".to_owned(),
+                pieces,
+            }],
+            ..Default::default()
+        };
+
+        let mut batch_report = BatchReport::default();
+        batch_report.add(Some(&missing(vec![1, 2, 3])));
+        batch_report.add(None);
+        batch_report.add(Some(&missing(vec![2, 3, 4])));
+        let still_missing: Vec<_> = batch_report.still_missing().collect();
+        assert_eq!(still_missing.len(), 1);
+        assert_eq!(still_missing[0].pieces, vec![2, 3]);
+
+        batch_report.add(Some(&missing(vec![1])));
+        assert_eq!(batch_report.still_missing().count(), 0);
+    }
+
+    #[test]
+    fn overwrite_fraction_inside_file() {
+        let planned_read = |dst_filename: &str, offset, size| PlannedRead {
+            dst_piece_idx: 0,
+            segments: vec![PlannedSegment {
+                src_path: String::new(),
+                dst_path: String::new(),
+                segment: Segment {
+                    src_filename: dst_filename.to_owned(),
+                    dst_filename: dst_filename.to_owned(),
+                    file_block: FileBlock { offset, size },
+                },
+            }],
+            hash: PieceHash::V1([0; 20]),
+            piece_size: 16,
+            identical: false,
+        };
+        assert_eq!(overwrite_fraction(&[], "a", 100), 0.);
+        assert_eq!(
+            overwrite_fraction(
+                &[planned_read("a", 0, 25), planned_read("a", 50, 25)],
+                "a",
+                100
+            ),
+            0.5
+        );
+        // only the part inside the file counts
+        assert_eq!(
+            overwrite_fraction(
+                &[planned_read("a", 80, 20), planned_read("b", 0, 20)],
+                "a",
+                100
+            ),
+            0.2
+        );
+    }
+
+    #[cfg(feature = "compressed")]
+    #[test]
+    fn compressed_donor() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let zst = dir.path().join("a.mkv");
+        let gz = dir.path().join("b.mkv");
+        std::fs::write(
+            dir.path().join("a.mkv.zst"),
+            zstd::encode_all(&data[..], 0).unwrap(),
+        )
+        .unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        std::fs::write(dir.path().join("b.mkv.gz"), encoder.finish().unwrap()).unwrap();
+
+        let store = DecompressingStore {
+            inner: Arc::new(LocalStore::default()),
+        };
+        let file_block = FileBlock {
+            offset: 70_000,
+            size: 20_000,
+        };
+        for path in [zst, gz] {
+            let path = path.to_str().unwrap();
+            assert_eq!(
+                store.read_block(path, file_block).unwrap(),
+                &data[70_000..90_000]
+            );
+            assert!(store.check_size(path, 100_000).is_ok());
+            assert!(store.check_size(path, 100_001).is_err());
+        }
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn uring_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let path = path.to_str().unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        std::fs::write(path, &data).unwrap();
+        let missing = dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+
+        let store = UringStore::new(false, false, FileHandles::default()).unwrap();
+        let block = |offset, size| FileBlock { offset, size };
+        let read = store.read_blocks(&[
+            (path, block(10, 20)),
+            (missing, block(0, 10)),
+            (path, block(90, 20)),
+            (path, block(0, 10)),
+        ]);
+        assert_eq!(read[0].as_ref().unwrap(), &data[10..30]);
+        assert_eq!(
+            read[1].as_ref().unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            read[2].as_ref().unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(read[3].as_ref().unwrap(), &data[..10]);
+
+        let written = store.write_blocks(&[
+            (path, block(0, 2), &[0xff; 2]),
+            (missing, block(0, 2), &[0xff; 2]),
+            (path, block(98, 2), &[0xff; 2]),
+        ]);
+        assert!(written[0].is_ok() && written[1].is_err() && written[2].is_ok());
+        let on_disk = std::fs::read(path).unwrap();
+        assert_eq!(
+            (&on_disk[..2], &on_disk[2..98]),
+            (&[0xff; 2][..], &data[2..98])
+        );
+        assert_eq!(&on_disk[98..], &[0xff; 2]);
+    }
+
+    #[test]
+    fn data_of_unwanted_files_is_in_part_file() {
+        let mut torrent = test_torrent(16, &[("a", 20), ("b", 20)]);
+        torrent.content[1].priority = Priority::DoNotDownload;
+        assert!(torrent.file_data_is_in_file("a"));
+        assert!(!torrent.file_data_is_in_file("b"));
+        assert!(!torrent.file_data_is_in_file("unknown"));
+    }
+
+    #[test]
+    fn unwanted_destination_files_are_skipped() {
+        let src = test_torrent(16, &[("a", 20), ("b", 40)]);
+        let mut dst = test_torrent(16, &[("a", 20), ("a2", 20), ("b", 40)]);
+        dst.content[0].priority = Priority::DoNotDownload;
+        dst.content[2].priority = Priority::DoNotDownload;
+
+        let mut same_files = find_same_size_files(&src, &dst);
+        retain_wanted_files(&mut same_files, &dst);
+        let dst_files: Vec<&[String]> = same_files.iter().map(|f| &f.dst_files[..]).collect();
+        assert_eq!(dst_files, [["a2".to_owned()]]);
+    }
+
+    #[test]
+    fn file_filters() {
+        let filter = |pattern: &str| pattern.parse::<FileFilter>().unwrap();
+        assert!(filter("*.mkv").is_match("Show/S01E01.mkv"));
+        assert!(!filter("*.mkv").is_match("Show/S01E01.mkv.nfo"));
+        assert!(filter("S01E0?.*").is_match("Show/S01E01.srt"));
+        assert!(filter("Show/*.nfo").is_match("Show/a.nfo"));
+        assert!(!filter("Show/*.nfo").is_match("Show/Extras/a.nfo"));
+        assert!(filter("Show/**/*.nfo").is_match("Show/Extras/a.nfo"));
+        assert!(filter("Show/**/*.nfo").is_match("Show/a.nfo"));
+        assert!(filter("**/Subs/**").is_match("Show/Subs/en.srt"));
+        assert!(filter("[!a]*").is_match("b.mkv"));
+        assert!(!filter("[!a]*").is_match("a.mkv"));
+        assert!(filter("re:(?i)sample").is_match("Show/Sample/s.mkv"));
+        assert!("[a".parse::<FileFilter>().is_err());
+
+        let src = test_torrent(16, &[("a.mkv", 20), ("a.nfo", 40), ("sample.mkv", 8)]);
+        let dst = test_torrent(16, &[("b.mkv", 20), ("b.nfo", 40), ("sample.mkv", 8)]);
+        let options = Options {
+            include: vec![filter("*.mkv")],
+            exclude: vec![filter("sample*")],
+            ..Default::default()
+        };
+        let mut same_files = find_same_size_files(&src, &dst);
+        retain_selected_files(&mut same_files, &options);
+        let dst_files: Vec<&[String]> = same_files.iter().map(|f| &f.dst_files[..]).collect();
+        assert_eq!(dst_files, [["b.mkv".to_owned()]]);
+    }
+
+    #[test]
+    fn piece_range_single_file() {
+        let torrent = test_torrent(16, &[("a", 40)]);
+        assert_eq!(torrent.piece_range_for_file("a").unwrap(), 0..3);
+    }
+
+    #[test]
+    fn piece_range_aligned() {
+        let torrent = test_torrent(16, &[("a", 32), ("b", 48)]);
+        assert_eq!(torrent.piece_range_for_file("a").unwrap(), 0..2);
+        assert_eq!(torrent.piece_range_for_file("b").unwrap(), 2..5);
+    }
+
+    #[test]
+    fn piece_range_unaligned() {
+        let torrent = test_torrent(16, &[("a", 20), ("b", 40), ("c", 4)]);
+        // piece 1 is shared by a and b, piece 3 by b and c
+        assert_eq!(torrent.piece_range_for_file("a").unwrap(), 0..2);
+        assert_eq!(torrent.piece_range_for_file("b").unwrap(), 1..4);
+        assert_eq!(torrent.piece_range_for_file("c").unwrap(), 3..4);
+    }
+
+    #[test]
+    fn file_block_pieces_stay_in_file() {
+        let torrent = test_torrent(16, &[("a", 32), ("empty", 0), ("b", 20)]);
+        let pieces = |path: &str, offset: u64, size: u64| -> Vec<usize> {
+            file_block_to_pieces(&torrent, path, &FileBlock { offset, size })
+                .unwrap()
+                .iter()
+                .map(|piece| piece.idx)
+                .collect()
+        };
+        assert_eq!(pieces("a", 8, 16), [0, 1]);
+        assert_eq!(pieces("b", 16, 4), [3]);
+        // the end of a file on a piece boundary is the start of the next piece, which is not in the file
+        assert!(pieces("a", 32, 0).is_empty());
+        assert!(pieces("a", 32, 16).is_empty());
+        assert!(pieces("empty", 0, 0).is_empty());
+        assert!(file_block_to_pieces(
+            &torrent,
+            "a",
+            &FileBlock {
+                offset: 33,
+                size: 0
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn short_last_piece() {
+        let torrent = test_torrent(16, &[("a", 20), ("b", 20)]);
+        let file_block = FileBlock {
+            offset: 12,
+            size: 8,
+        };
+        assert_eq!(piece_segments(&torrent, 2), [("b".to_owned(), file_block)]);
+
+        // only the real bytes of the last piece are hashed
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("b");
+        let data: Vec<u8> = (0..20).collect();
+        std::fs::write(&path, &data).unwrap();
+        assert!(blocks_have_hash(
+            &LocalStore::default(),
+            &[(path.to_str().unwrap(), file_block)],
+            &get_sha1(&data[12..]),
+            0,
+            CHUNK_SIZE,
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn piece_spanning_files_with_other_boundaries_in_donor() {
+        // same files, in another order in the donor
+        let mut src = test_torrent(16, &[("b", 40), ("a", 20)]);
+        src.pieces_states = vec![PieceState::Downloaded; 4];
+        let dst = test_torrent(16, &[("a", 20), ("b", 40)]);
+        let same_files = find_same_size_files(&src, &dst);
+
+        // piece 1 is the end of a and the beginning of b
+        let segments = plan_piece(&src, &dst, &same_files, 1, false, false).unwrap();
+        let segment = |filename: &str, offset, size| Segment {
+            src_filename: filename.to_owned(),
+            dst_filename: filename.to_owned(),
+            file_block: FileBlock { offset, size },
+        };
+        assert_eq!(segments, [segment("a", 16, 4), segment("b", 0, 12)]);
+
+        // a is at the end of the donor, b at the beginning
+        let dir = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..60).collect();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::write(&a, &content[..20]).unwrap();
+        std::fs::write(&b, &content[20..]).unwrap();
+        let planned_read = PlannedRead {
+            dst_piece_idx: 1,
+            segments: segments
+                .into_iter()
+                .map(|segment| {
+                    let path = dir.path().join(&segment.src_filename);
+                    PlannedSegment {
+                        src_path: path.to_str().unwrap().to_owned(),
+                        dst_path: path.to_str().unwrap().to_owned(),
+                        segment,
+                    }
+                })
+                .collect(),
+            hash: get_sha1(&content[16..32]),
+            piece_size: 16,
+            identical: false,
+        };
+        assert_eq!(planned_read.size(), 16);
+        assert!(blocks_have_hash(
+            &LocalStore::default(),
+            &planned_read.src_blocks(),
+            &planned_read.hash,
+            planned_read.piece_size,
+            CHUNK_SIZE,
+        )
+        .unwrap());
+
+        // not available when a donor piece of one of the segments is missing
+        src.pieces_states[0] = PieceState::NotDownloaded;
+        assert_eq!(
+            plan_piece(&src, &dst, &same_files, 1, false, false),
+            Err(SkipReason::Unavailable)
+        );
+    }
+
+    #[test]
+    fn only_the_destination_piece_is_read_from_bigger_donor_pieces() {
+        // a donor piece holds 16 destination pieces
+        let mut src = test_torrent(256, &[("a", 512)]);
+        src.pieces_states = vec![PieceState::Downloaded; 2];
+        let dst = test_torrent(16, &[("a", 512)]);
+        let same_files = find_same_size_files(&src, &dst);
+
+        let segments = plan_piece(&src, &dst, &same_files, 5, false, false).unwrap();
+        assert_eq!(
+            segments.iter().map(|s| s.file_block).collect::<Vec<_>>(),
+            [FileBlock {
+                offset: 80,
+                size: 16
+            }]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let content: Vec<u8> = (0..512).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let planned_read = PlannedRead {
+            dst_piece_idx: 5,
+            segments: segments
+                .into_iter()
+                .map(|segment| PlannedSegment {
+                    src_path: path.to_str().unwrap().to_owned(),
+                    dst_path: path.to_str().unwrap().to_owned(),
+                    segment,
+                })
+                .collect(),
+            hash: get_sha1(&content[80..96]),
+            piece_size: 16,
+            identical: false,
+        };
+        let store = CountingStore::default();
+        let read = PlannedRead::read_batch(std::slice::from_ref(&planned_read), &store, CHUNK_SIZE);
+        match &read[..] {
+            [Ok(SourceData::InMemory(data))] => assert_eq!(data, &content[80..96]),
+            _ => panic!("piece not read"),
+        }
+        assert_eq!(store.bytes.load(Ordering::SeqCst), 16);
+    }
+
+    #[test]
+    fn identical_donor_piece_without_donor_hashes() {
+        let mut src = test_torrent(16, &[("a", 32)]);
+        src.pieces_states = vec![PieceState::Downloaded; 2];
+        src.pieces_hashes.clear();
+        let dst = test_torrent(16, &[("a", 32)]);
+        let same_files = find_same_size_files(&src, &dst);
+
+        assert_eq!(
+            plan_piece(&src, &dst, &same_files, 1, true, false),
+            Err(SkipReason::NotIdentical)
+        );
+        let segments = plan_piece(&src, &dst, &same_files, 1, true, true).unwrap();
+        assert_eq!(segments.len(), 1);
+        // identical only when the hashes are known to match
+        assert!(!is_identical_piece(&src, &dst, &segments, 1));
+        src.pieces_hashes = dst.pieces_hashes.clone();
+        assert!(is_identical_piece(&src, &dst, &segments, 1));
+
+        // pieces of another size are still not identical
+        let mut src = test_torrent(32, &[("a", 32)]);
+        src.pieces_states = vec![PieceState::Downloaded];
+        let same_files = find_same_size_files(&src, &dst);
+        assert_eq!(
+            plan_piece(&src, &dst, &same_files, 1, true, true),
+            Err(SkipReason::NotIdentical)
+        );
+        let segments = plan_piece(&src, &dst, &same_files, 1, false, false).unwrap();
+        assert!(!is_identical_piece(&src, &dst, &segments, 1));
+    }
+
+    #[test]
+    fn stats_from_events() {
+        let mut stats = MergeStats::new("dst");
+        stats.record(&MergeEvent::FileStarted {
+            path: "a".to_owned(),
+            missing_pieces: 3,
+        });
+        stats.record(&MergeEvent::PieceRestored {
+            path: "a".to_owned(),
+            idx: 0,
+            size: 16,
+        });
+        stats.record(&MergeEvent::PieceSkipped {
+            path: "a".to_owned(),
+            idx: 1,
+            reason: SkipReason::HashMismatch,
+        });
+        stats.record(&MergeEvent::PieceRestored {
+            path: "a".to_owned(),
+            idx: 2,
+            size: 4,
+        });
+        assert_eq!(stats.current_files, ["a"]);
+        assert_eq!(
+            (
+                stats.processed_pieces,
+                stats.restored_pieces,
+                stats.restored_bytes
+            ),
+            (3, 2, 20)
+        );
+
+        stats.record(&MergeEvent::FileFinished {
+            path: "a".to_owned(),
+        });
+        assert!(stats.current_files.is_empty());
+    }
+
+    #[test]
+    fn loose_files_as_donor() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/b"), [1; 40]).unwrap();
+        std::fs::write(dir.path().join("a"), [2; 20]).unwrap();
+        let src = Torrent::from_dir(dir.path().to_str().unwrap(), 16).unwrap();
+
+        let names: Vec<(&str, u64)> = src
+            .content
+            .iter()
+            .map(|f| (f.name.as_str(), f.size))
+            .collect();
+        assert_eq!(names, [("a", 20), ("sub/b", 40)]);
+        assert_eq!(src.properties.save_path.as_deref(), dir.path().to_str());
+
+        // all the data is available, and checked against the destination hashes only
+        let dst = test_torrent(16, &[("other/b", 40)]);
+        let same_files = find_same_size_files(&src, &dst);
+        let segments = plan_piece(&src, &dst, &same_files, 2, false, false).unwrap();
+        assert_eq!(segments[0].src_filename, "sub/b");
+        assert_eq!(
+            segments[0].file_block,
+            FileBlock {
+                offset: 32,
+                size: 8
+            }
+        );
+    }
+
+    #[test]
+    fn torrent_file_as_donor() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("donor.torrent");
+        let mut torrent_file =
+            b"d4:infod5:filesld6:lengthi20e4:pathl1:aeed6:lengthi40e4:pathl3:sub1:beee\
+              4:name4:root12:piece lengthi16e6:pieces80:"
+                .to_vec();
+        torrent_file.extend([7; 80]);
+        torrent_file.extend(b"ee");
+        std::fs::write(&path, &torrent_file).unwrap();
+        let src = Torrent::from_torrent_file(path.to_str().unwrap(), "/data").unwrap();
+
+        let names: Vec<(&str, u64)> = src
+            .content
+            .iter()
+            .map(|f| (f.name.as_str(), f.size))
+            .collect();
+        assert_eq!(names, [("root/a", 20), ("root/sub/b", 40)]);
+        assert_eq!(src.properties.save_path.as_deref(), Some("/data"));
+        assert_eq!(src.piece_size, 16);
+        assert_eq!(src.pieces_hashes.len(), 4);
+        assert_eq!(src.pieces_states.len(), 4);
+    }
+
+    #[test]
+    fn padding_files() {
+        assert!(is_padding_file("root/.pad/12"));
+        assert!(is_padding_file("/save/root/_____padding_file_0_"));
+        assert!(!is_padding_file("root/pad/12"));
+
+        // qBittorrent leaves padding out of the content, the .torrent file puts it back
+        let mut dst = test_torrent(16, &[("r/a", 20), ("r/b", 16)]);
+        let meta_file = |path: &str, size, padding| bencode::MetaFile {
+            path: path.to_owned(),
+            size,
+            pieces_root: None,
+            padding,
+        };
+        let files = [
+            meta_file("r/a", 20, false),
+            meta_file("r/.pad/12", 12, true),
+            meta_file("r/b", 16, false),
+        ];
+        dst.content = with_padding_files(dst.content, &files);
+        dst.pieces_hashes = vec![PieceHash::V1([0xff; 20]); 3];
+        dst.pieces_states = vec![PieceState::NotDownloaded; 3];
+        assert_eq!(dst.piece_range_for_file("r/b").unwrap(), 2..3);
+
+        // padding files all have the same size, they are never matched
+        let mut src = test_torrent(16, &[("s/a", 20), ("s/.pad/12", 12), ("s/b", 16)]);
+        src.pieces_states = vec![PieceState::Downloaded; 3];
+        let same_files = find_same_size_files(&src, &dst);
+        assert_eq!(same_files.len(), 2);
+
+        let segments = plan_piece(&src, &dst, &same_files, 1, false, false).unwrap();
+        assert_eq!(
+            segments[1],
+            Segment {
+                src_filename: "r/.pad/12".to_owned(),
+                dst_filename: "r/.pad/12".to_owned(),
+                file_block: FileBlock {
+                    offset: 0,
+                    size: 12
+                },
+            }
+        );
+        let store = PaddingStore {
+            inner: Arc::new(LocalStore::default()),
+        };
+        assert_eq!(
+            store
+                .read_block("/missing/r/.pad/12", segments[1].file_block)
+                .unwrap(),
+            [0; 12]
+        );
+        assert!(store
+            .write_block("/missing/r/.pad/12", segments[1].file_block, &[1; 12])
+            .is_ok());
+    }
+
+    #[test]
+    fn scan_candidates() {
+        let mut donor = test_torrent(16, &[("a", 32), ("b", 8)]);
+        donor.pieces_states = vec![PieceState::Downloaded; 3];
+        let incomplete = test_torrent(16, &[("x", 32)]);
+        let unrelated = test_torrent(16, &[("z", 40)]);
+        let mut torrents = vec![donor, incomplete, unrelated];
+
+        // complete torrents are never destinations, torrents without a common size are never paired
+        assert_eq!(
+            scan_pairs(&torrents, &Options::default()),
+            BTreeSet::from([(0, 1)])
+        );
+        let options = Options {
+            min_file_size: Some(33),
+            ..Default::default()
+        };
+        assert!(scan_pairs(&torrents, &options).is_empty());
+
+        let same_files = find_same_size_files(&torrents[0], &torrents[1]);
+        assert_eq!(
+            recoverable_bytes(&torrents[0], &torrents[1], &same_files),
+            32
+        );
+        torrents[0].pieces_states[1] = PieceState::NotDownloaded;
+        assert_eq!(
+            recoverable_bytes(&torrents[0], &torrents[1], &same_files),
+            16
+        );
+    }
+
+    #[test]
+    fn role_filters() {
+        let torrent: TorrentInfo = serde_json::from_value(serde_json::json!({
+            "category": "archive",
+            "tags": "old, cross-seed",
+        }))
+        .unwrap();
+        let filter = |categories: &[&str], tags: &[&str]| RoleFilter {
+            categories: categories.iter().map(|c| c.to_string()).collect(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        };
+
+        assert!(RoleFilter::default().matches(&torrent));
+        assert!(filter(&["movies", "archive"], &[]).matches(&torrent));
+        assert!(filter(&["archive"], &["cross-seed"]).matches(&torrent));
+        assert!(!filter(&["archive"], &["cross"]).matches(&torrent));
+        assert!(!filter(&["movies"], &[]).matches(&torrent));
+
+        let untagged: TorrentInfo = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert!(!filter(&[], &["cross-seed"]).matches(&untagged));
+    }
+
+    #[test]
+    fn same_size_files_with_merkle_roots() {
+        let mut src = test_torrent(16, &[("a", 32), ("b", 32), ("c", 8)]);
+        let mut dst = test_torrent(16, &[("x", 32), ("y", 32), ("z", 8)]);
+        src.pieces_roots = HashMap::from([("a".to_owned(), [1; 32]), ("b".to_owned(), [2; 32])]);
+        dst.pieces_roots = HashMap::from([("x".to_owned(), [2; 32]), ("y".to_owned(), [3; 32])]);
+
+        // "y" has no identical file, "z" only has a root on one side and is matched by size
+        let same_files = find_same_size_files(&src, &dst);
+        let matches: Vec<(&[String], &[String], MatchSource)> = same_files
+            .iter()
+            .map(|m| (&m.src_files[..], &m.dst_files[..], m.match_source))
+            .collect();
+        assert_eq!(
+            matches,
+            [
+                (
+                    &["c".to_owned()][..],
+                    &["z".to_owned()][..],
+                    MatchSource::Size
+                ),
+                (
+                    &["b".to_owned()][..],
+                    &["x".to_owned()][..],
+                    MatchSource::MerkleRoot
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn zeroed_expected_hash() {
+        let mut src = test_torrent(16, &[("a", 32)]);
+        src.pieces_states = vec![PieceState::Downloaded; 2];
+        src.pieces_hashes[1] = PieceHash::V1([0; 20]);
+        let mut dst = test_torrent(16, &[("a", 32)]);
+        dst.pieces_hashes[1] = PieceHash::V1([0; 20]);
+        let same_files = find_same_size_files(&src, &dst);
+
+        assert!(plan_piece(&src, &dst, &same_files, 0, false, false).is_ok());
+        assert_eq!(
+            plan_piece(&src, &dst, &same_files, 1, false, false),
+            Err(SkipReason::NoExpectedHash)
+        );
+        // missing hash
+        dst.pieces_hashes.pop();
+        assert_eq!(
+            plan_piece(&src, &dst, &same_files, 1, false, false),
+            Err(SkipReason::NoExpectedHash)
+        );
+        // zero hashes don't match each other
+        dst.pieces_hashes.push(PieceHash::V1([0; 20]));
+        assert_eq!(find_pieces_by_hash(&src, &dst), vec![0]);
+    }
+
+    #[test]
+    fn write_leaves_other_bytes_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let path = path.to_str().unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        std::fs::write(path, &data).unwrap();
+
+        let file_block = FileBlock {
+            offset: 40,
+            size: 10,
+        };
+        LocalStore::default()
+            .write_block(path, file_block, &[0xff; 10])
+            .unwrap();
+        let written = std::fs::read(path).unwrap();
+        assert_eq!(written.len(), 100);
+        assert_eq!(&written[..40], &data[..40]);
+        assert_eq!(&written[40..50], &[0xff; 10]);
+        assert_eq!(&written[50..], &data[50..]);
+
+        // missing files are only created when asked
+        let missing = dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+        let store = LocalStore::default();
+        assert_eq!(
+            store
+                .write_block(missing, file_block, &[0xff; 10])
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::NotFound
+        );
+        store.check_writable(missing).unwrap();
+        assert!(!std::path::Path::new(missing).exists());
+        let store = LocalStore {
+            create_missing: true,
+            ..LocalStore::default()
+        };
+        store.check_writable(missing).unwrap();
+        assert!(!std::path::Path::new(missing).exists());
+        store.write_block(missing, file_block, &[0xff; 10]).unwrap();
+        assert_eq!(std::fs::read(missing).unwrap().len(), 50);
+    }
+
+    #[test]
+    fn missing_files_are_created_with_their_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sub/a");
+        let path = path.to_str().unwrap();
+
+        LocalStore::default().create_file(path, 1 << 20).unwrap();
+        assert!(!std::path::Path::new(path).exists());
+
+        let store = LocalStore {
+            create_missing: true,
+            ..LocalStore::default()
+        };
+        store.create_file(path, 1 << 20).unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 1 << 20);
+        // existing files are left alone
+        std::fs::write(path, [1; 10]).unwrap();
+        store.create_file(path, 1 << 20).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), [1; 10]);
+
+        let path = dir.path().join("b");
+        let path = path.to_str().unwrap();
+        let store = LocalStore {
+            create_missing: true,
+            preallocate: true,
+            ..LocalStore::default()
+        };
+        store.create_file(path, 1 << 20).unwrap();
+        let metadata = std::fs::metadata(path).unwrap();
+        assert_eq!(metadata.len(), 1 << 20);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(metadata.blocks() * 512 >= 1 << 20);
+        }
+    }
+
+    #[test]
+    fn file_handles_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let path = dir.path().join(name);
+                std::fs::write(&path, [0; 10]).unwrap();
+                path.to_str().unwrap().to_owned()
+            })
+            .collect();
+
+        let handles = FileHandles::new(2);
+        let a = handles.get(&paths[0], false, false).unwrap();
+        assert!(Arc::ptr_eq(
+            &a,
+            &handles.get(&paths[0], false, false).unwrap()
+        ));
+        // opened for writing, it is another handle
+        assert!(!Arc::ptr_eq(
+            &a,
+            &handles.get(&paths[0], true, false).unwrap()
+        ));
+        handles.get(&paths[1], false, false).unwrap();
+        // the least recently used is closed
+        handles.get(&paths[2], false, false).unwrap();
+        let cached: Vec<(String, bool)> = handles
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, write, _)| (path.clone(), *write))
+            .collect();
+        assert_eq!(
+            cached,
+            [(paths[1].clone(), false), (paths[2].clone(), false)]
+        );
+        assert!(handles.get("missing", false, false).is_err());
+        assert_eq!(handles.files.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn v2_piece_hash() {
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let expected =
+            PieceHash::from_hex("ab671631a9fa97a1fdac651fff6c68773b9acf0735b9c7f6ecdd54cbf1bf5dc2")
+                .unwrap();
+
+        // 3 blocks of the last piece of a file, padded to the 4 blocks of a 64 KiB piece
+        assert_eq!(expected.hasher(64 * 1024).digest(&data), expected);
+        let mut hasher = expected.hasher(64 * 1024);
+        for part in data.chunks(7000) {
+            hasher.update(part);
+        }
+        assert_eq!(hasher.finalize(), expected);
+        assert_ne!(expected.hasher(128 * 1024).digest(&data), expected);
+
+        // a v1 destination checks v2 donor data with SHA-1, and the other way around
+        let v1 = get_sha1(&data);
+        assert_eq!(v1.hasher(64 * 1024).digest(&data), v1);
+        assert_ne!(v1, expected);
+        assert!(PieceHash::from_hex("abcd").is_err());
+    }
+
+    #[test]
+    fn pure_v2_files_start_a_piece() {
+        let kib = 1024;
+        let block = V2_BLOCK_SIZE;
+        let a: Vec<u8> = (0..10 * kib).map(|i| (i % 251) as u8).collect();
+        let b: Vec<u8> = (0..40 * kib).map(|i| (i % 241) as u8).collect();
+        let leaf = |data: &[u8]| -> [u8; 32] { Sha256::digest(data).into() };
+        let node = |left: [u8; 32], right: [u8; 32]| -> [u8; 32] {
+            Sha256::digest([left, right].concat()).into()
+        };
+        // a fits in a piece, its hash is its root. The last piece of b is padded with zero leaves
+        let hashes = vec![
+            PieceHash::V2(leaf(&a)),
+            PieceHash::V2(node(leaf(&b[..block]), leaf(&b[block..2 * block]))),
+            PieceHash::V2(node(leaf(&b[2 * block..]), [0; 32])),
+        ];
+
+        // what the client reports: 3 pieces of 32 KiB, for 50 KiB of files
+        let mut torrent = test_torrent(
+            32 * kib as u64,
+            &[("a", a.len() as u64), ("b", b.len() as u64)],
+        );
+        torrent.pieces_states = vec![PieceState::Downloaded; 3];
+        torrent.pieces_hashes = hashes;
+        assert_eq!(torrent.pieces_hashes.len(), 3);
+        assert!(torrent.has_unaligned_files());
+
+        // the .torrent file aligns b, without the padding being hashed
+        let meta_file = |path: &str, size, padding| bencode::MetaFile {
+            path: path.to_owned(),
+            size,
+            pieces_root: None,
+            padding,
+        };
+        let files = [
+            meta_file("a", a.len() as u64, false),
+            meta_file(".pad/22528", 22 * kib as u64, true),
+            meta_file("b", b.len() as u64, false),
+        ];
+        torrent.content = with_padding_files(torrent.content, &files);
+        assert!(!torrent.has_unaligned_files());
+        assert_eq!(torrent.piece_hash_size(0), a.len() as u64);
+        assert_eq!(torrent.piece_hash_size(2), 32 * kib as u64);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = HashMap::new();
+        for (name, data) in [("a", &a), ("b", &b)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            paths.insert(name.to_owned(), path.to_str().unwrap().to_owned());
+        }
+        let checks = verify_pieces(&LocalStore::default(), &torrent, &paths, None, CHUNK_SIZE);
+        assert_eq!(checks, [PieceCheck::Intact; 3]);
+    }
+
+    #[test]
+    fn coverage_gaps_by_file() {
+        let block = |offset, size| FileBlock { offset, size };
+        let gaps = coverage_gaps(
+            "src",
+            "dst",
+            [
+                ("b".to_owned(), block(32, 16)),
+                ("a".to_owned(), block(16, 4)),
+                ("b".to_owned(), block(0, 16)),
+                ("b".to_owned(), block(64, 16)),
+            ],
+        );
+
+        assert_eq!(gaps.len(), 2);
+        assert_eq!(
+            (gaps[0].path.as_str(), gaps[0].ranges.as_slice()),
+            ("a", &[block(16, 4)][..])
+        );
+        assert_eq!(gaps[1].ranges, [block(0, 16), block(32, 16), block(64, 16)]);
+        assert_eq!(gaps[1].size(), 48);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gaps.json");
+        write_coverage_gaps(path.to_str().unwrap(), &gaps).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json[1]["file"], "b");
+        assert_eq!(json[1]["ranges"][2]["offset"], 64);
+    }
+
+    #[test]
+    fn piece_range_empty_and_unknown_files() {
+        let torrent = test_torrent(16, &[("a", 16), ("empty", 0), ("b", 16)]);
+        assert!(torrent.piece_range_for_file("empty").unwrap().is_empty());
+        assert!(torrent.piece_range_for_file("unknown").is_err());
+    }
+
+    #[test]
+    fn same_donor_on_every_run() {
+        let groups = vec![vec!["c".to_owned(), "a".to_owned(), "b".to_owned()]];
+        let shuffled = vec![vec!["b".to_owned(), "c".to_owned(), "a".to_owned()]];
+        let pairs = donor_pairs(&groups);
+        assert_eq!(pairs, donor_pairs(&shuffled));
+
+        // donors of c by order of hash
+        let donors: Vec<&str> = pairs
+            .iter()
+            .filter(|(_, dst)| dst == "c")
+            .map(|(src, _)| src.as_str())
+            .collect();
+        assert_eq!(donors, ["a", "b"]);
+
+        // the first file by name is used when several files have the same size
+        let t1 = test_torrent(16, &[("z", 32), ("y", 32), ("x", 16)]);
+        let t2 = test_torrent(16, &[("b", 16), ("a", 32)]);
+        for _ in 0..10 {
+            let matches = find_same_size_files(&t1, &t2);
+            assert_eq!(matches.len(), 2);
+            assert_eq!(matches[0].src_files, ["x"]);
+            assert_eq!(matches[1].src_files, ["y", "z"]);
+            assert_eq!(convert_filename(&matches, "a").unwrap(), "y");
+        }
+    }
+
+    #[test]
+    fn divergence_between_differently_aligned_torrents() {
+        let mut src = test_torrent(16, &[("a", 64)]);
+        src.pieces_states = vec![PieceState::Downloaded; 4];
+        let mut dst = test_torrent(32, &[("a", 64)]);
+        dst.pieces_states = vec![PieceState::Downloaded; 2];
+
+        let dir = tempfile::tempdir().unwrap();
+        let (src_path, dst_path) = (dir.path().join("src"), dir.path().join("dst"));
+        let (src_path, dst_path) = (src_path.to_str().unwrap(), dst_path.to_str().unwrap());
+        let data = vec![1; 64];
+        let mut other = data.clone();
+        other[40] = 2;
+        std::fs::write(src_path, &data).unwrap();
+        std::fs::write(dst_path, &other).unwrap();
+
+        let map = |src: &Torrent, dst: &Torrent| {
+            divergence_map(
+                &LocalStore::default(),
+                src,
+                "a",
+                src_path,
+                dst,
+                "a",
+                dst_path,
+            )
+        };
+        let block = |offset, size| FileBlock { offset, size };
+        assert_eq!(
+            map(&src, &dst),
+            [
+                (block(0, 32), Divergence::Identical),
+                (block(32, 32), Divergence::Divergent)
+            ]
+        );
+
+        dst.pieces_states[0] = PieceState::NotDownloaded;
+        assert_eq!(
+            map(&src, &dst),
+            [
+                (block(0, 32), Divergence::Unknown),
+                (block(32, 32), Divergence::Divergent)
+            ]
+        );
+
+        // same pieces on both sides are compared by hash, without reading
+        let mut dst = test_torrent(16, &[("a", 64)]);
+        dst.pieces_states = vec![PieceState::Downloaded; 4];
+        dst.pieces_hashes[2] = PieceHash::V1([1; 20]);
+        assert_eq!(
+            map(&src, &dst),
+            [
+                (block(0, 32), Divergence::Identical),
+                (block(32, 16), Divergence::Divergent),
+                (block(48, 16), Divergence::Identical)
+            ]
+        );
+    }
+
+    #[test]
+    fn divergent_pieces_are_split_by_blocks() {
+        let block_size = DIVERGENCE_BLOCK_SIZE;
+        let mut src = test_torrent(4 * block_size, &[("a", 8 * block_size)]);
+        src.pieces_states = vec![PieceState::Downloaded; 2];
+        let mut dst = test_torrent(4 * block_size, &[("a", 8 * block_size)]);
+        dst.pieces_states = vec![PieceState::Downloaded; 2];
+        dst.pieces_hashes[0] = PieceHash::V1([1; 20]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let (src_path, dst_path) = (dir.path().join("src"), dir.path().join("dst"));
+        let (src_path, dst_path) = (src_path.to_str().unwrap(), dst_path.to_str().unwrap());
+        let data = vec![1; 8 * block_size as usize];
+        let mut other = data.clone();
+        other[block_size as usize + 10] = 2;
+        std::fs::write(src_path, &data).unwrap();
+        std::fs::write(dst_path, &other).unwrap();
+
+        let map = |dst_path| {
+            divergence_map(
+                &LocalStore::default(),
+                &src,
+                "a",
+                src_path,
+                &dst,
+                "a",
+                dst_path,
+            )
+        };
+        let block = |offset, size| FileBlock { offset, size };
+        assert_eq!(
+            map(dst_path),
+            [
+                (block(0, block_size), Divergence::Identical),
+                (block(block_size, block_size), Divergence::Divergent),
+                (block(2 * block_size, 6 * block_size), Divergence::Identical)
+            ]
+        );
+
+        // a range that can't be read doesn't hide the others
+        let missing = dir.path().join("missing");
+        assert_eq!(
+            map(missing.to_str().unwrap()),
+            [
+                (block(0, 4 * block_size), Divergence::Unknown),
+                (block(4 * block_size, 4 * block_size), Divergence::Identical)
+            ]
+        );
+    }
+
+    #[cfg(feature = "script")]
+    #[test]
+    fn script_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.rhai");
+        std::fs::write(
+            &path,
+            r#"
+            fn restore(piece) { !piece.dst_files[0].ends_with(".nfo") && piece.size > 8 }
+            fn donor_rank(src_hash, dst_hash) { if src_hash == "preferred" { -1 } else { 0 } }
+            "#,
+        )
+        .unwrap();
+        let script = PieceScript::load(path.to_str().unwrap()).unwrap();
+
+        let segment = |filename: &str, size| Segment {
+            src_filename: filename.to_owned(),
+            dst_filename: filename.to_owned(),
+            file_block: FileBlock { offset: 0, size },
+        };
+        assert!(script.restore("src", "dst", 0, &[segment("movie.mkv", 16)]));
+        assert!(!script.restore("src", "dst", 0, &[segment("movie.mkv", 8)]));
+        assert!(!script.restore("src", "dst", 0, &[segment("release.nfo", 16)]));
+        assert_eq!(script.donor_rank("preferred", "dst"), -1);
+        assert_eq!(script.donor_rank("other", "dst"), 0);
+    }
+
+    #[test]
+    fn stale_mapping() {
+        let mut src = test_torrent(16, &[("a", 32), ("b", 32)]);
+        src.pieces_states = vec![PieceState::Downloaded; 4];
+        let dst = test_torrent(16, &[("c", 32)]);
+        let mut same_files = find_same_size_files(&src, &dst);
+        assert_eq!(same_files[0].src_files, ["a", "b"]);
+
+        // a was renamed in the source since files were matched: b is used
+        same_files[0].src_files[0] = "renamed".to_owned();
+        let segments = plan_piece(&src, &dst, &same_files, 0, false, false).unwrap();
+        assert_eq!(segments[0].src_filename, "b");
+
+        same_files[0].src_files = vec!["renamed".to_owned()];
+        assert_eq!(
+            plan_piece(&src, &dst, &same_files, 0, false, false),
+            Err(SkipReason::MappingStale)
+        );
+    }
+
+    #[test]
+    fn files_by_priority() {
+        let mut dst = test_torrent(16, &[("a", 16), ("b", 48), ("c", 32)]);
+        dst.content[2].priority = Priority::Maximal;
+        let src = test_torrent(16, &[("a", 16), ("b", 48), ("c", 32)]);
+        let names = |files: &[FileMatch]| -> Vec<String> {
+            files.iter().map(|f| f.dst_files[0].clone()).collect()
+        };
+
+        let mut same_files = find_same_size_files(&src, &dst);
+        sort_by_priority(&mut same_files, &dst, PriorityOrder::HighPriorityFilesFirst);
+        assert_eq!(names(&same_files), ["c", "a", "b"]);
+        sort_by_priority(&mut same_files, &dst, PriorityOrder::Sequential);
+        assert_eq!(names(&same_files), ["a", "b", "c"]);
+        sort_by_priority(&mut same_files, &dst, PriorityOrder::LargestFileFirst);
+        assert_eq!(names(&same_files), ["b", "c", "a"]);
+    }
+
+    #[test]
+    fn url_with_or_without_trailing_slash() {
+        assert_eq!(
+            normalize_url("http://localhost:8080"),
+            normalize_url("http://localhost:8080/")
+        );
+        assert_eq!(
+            normalize_url("https://seedbox.example/qbittorrent"),
+            "https://seedbox.example/qbittorrent/"
+        );
+        assert_eq!(
+            normalize_url("https://seedbox.example/qbittorrent//"),
+            "https://seedbox.example/qbittorrent/"
+        );
+
+        // API paths are joined under the WebUI, whatever the URL given
+        let api_url = |url: &str| {
+            reqwest::Url::parse(&normalize_url(url))
+                .unwrap()
+                .join("api/v2/app/version")
+                .unwrap()
+                .to_string()
+        };
+        for url in ["http://localhost:8080", "http://localhost:8080/"] {
+            assert_eq!(api_url(url), "http://localhost:8080/api/v2/app/version");
+        }
+        for url in [
+            "https://seedbox.example/qbittorrent",
+            "https://seedbox.example/qbittorrent/",
+            "https://seedbox.example/qbittorrent//",
+        ] {
+            assert_eq!(
+                api_url(url),
+                "https://seedbox.example/qbittorrent/api/v2/app/version"
+            );
+        }
+    }
+
+    /// Torrent of a `MemoryClient`
+    struct MemoryTorrent {
+        properties: TorrentProperty,
+        content: Vec<TorrentContent>,
+        pieces_states: Vec<PieceState>,
+        pieces_hashes: Vec<String>,
+    }
+
+    /// Client keeping torrents in memory
+    #[derive(Default)]
+    struct MemoryClient {
+        torrents: HashMap<String, MemoryTorrent>,
+    }
+
+    impl MemoryClient {
+        /// A single file torrent in `save_path`, with the pieces of `data`
+        fn add(&mut self, hash: &str, save_path: &std::path::Path, data: &[u8], have: bool) {
+            let pieces_hashes: Vec<String> = data
+                .chunks(16)
+                .map(|piece| hex::encode(Sha1::digest(piece)))
+                .collect();
+            let pieces_num = pieces_hashes.len();
+            let properties = serde_json::from_value(serde_json::json!({
+                "save_path": save_path,
+                "piece_size": 16,
+                "pieces_num": pieces_num,
+                "pieces_have": if have { pieces_num } else { 0 },
+                "total_size": data.len(),
+            }))
+            .unwrap();
+            let content = vec![TorrentContent {
+                index: 0,
+                name: "a".to_owned(),
+                size: data.len() as u64,
+                progress: if have { 1. } else { 0. },
+                priority: Priority::Normal,
+                is_seed: None,
+                piece_range: vec![],
+                availability: 0.,
+            }];
+            let state = if have {
+                PieceState::Downloaded
+            } else {
+                PieceState::NotDownloaded
+            };
+            self.torrents.insert(
+                hash.to_owned(),
+                MemoryTorrent {
+                    properties,
+                    content,
+                    pieces_states: vec![state; pieces_num],
+                    pieces_hashes,
+                },
+            );
+        }
+
+        fn get(&self, hash: &str) -> Result<&MemoryTorrent, Box<dyn std::error::Error>> {
+            Ok(self
+                .torrents
+                .get(hash)
+                .ok_or_else(|| format!("Torrent not found: {}", hash))?)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl TorrentClient for MemoryClient {
+        async fn version(&self) -> Result<String, Box<dyn std::error::Error>> {
+            Ok("memory".to_owned())
+        }
+
+        async fn torrents(
+            &self,
+            _hashes: Option<&[String]>,
+        ) -> Result<Vec<qbit_rs::model::Torrent>, Box<dyn std::error::Error>> {
+            Ok(Vec::new())
+        }
+
+        async fn properties(
+            &self,
+            hash: &str,
+        ) -> Result<TorrentProperty, Box<dyn std::error::Error>> {
+            Ok(self.get(hash)?.properties.clone())
+        }
+
+        async fn contents(
+            &self,
+            hash: &str,
+        ) -> Result<Vec<TorrentContent>, Box<dyn std::error::Error>> {
+            Ok(self.get(hash)?.content.clone())
+        }
+
+        async fn pieces_states(
+            &self,
+            hash: &str,
+        ) -> Result<Vec<PieceState>, Box<dyn std::error::Error>> {
+            Ok(self.get(hash)?.pieces_states.clone())
+        }
+
+        async fn pieces_hashes(
+            &self,
+            hash: &str,
+        ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            Ok(self.get(hash)?.pieces_hashes.clone())
+        }
+
+        async fn preferences(
+            &self,
+            _hash: &str,
+        ) -> Result<Preferences, Box<dyn std::error::Error>> {
+            Ok(Preferences::default())
+        }
+
+        async fn pause(&self, _hashes: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn resume(&self, _hashes: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn recheck(&self, _hashes: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn set_file_priority(
+            &self,
+            _hash: &str,
+            _indexes: &[i64],
+            _priority: Priority,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    /// Torrents `src`, complete, and `dst`, missing all its pieces, of a file `a` with `data`, in the directories
+    /// `src` and `dst` of a temporary directory. The destination file holds `dst_data`
+    fn src_dst_fixture(data: &[u8], dst_data: &[u8]) -> (tempfile::TempDir, MemoryClient) {
+        let dir = tempfile::tempdir().unwrap();
+        let (src_dir, dst_dir) = (dir.path().join("src"), dir.path().join("dst"));
+        std::fs::create_dir(&src_dir).unwrap();
+        std::fs::create_dir(&dst_dir).unwrap();
+        std::fs::write(src_dir.join("a"), data).unwrap();
+        std::fs::write(dst_dir.join("a"), dst_data).unwrap();
+
+        let mut client = MemoryClient::default();
+        client.add("src", &src_dir, data, true);
+        client.add("dst", &dst_dir, data, false);
+        (dir, client)
+    }
+
+    /// Reading a multi-gigabyte torrent of 16 KiB pieces, in dry run:
+    /// `cargo test --release --lib -- --ignored --nocapture small_pieces_throughput`
+    ///
+    /// The size is `BENCH_SIZE_GIB`, 4 by default. Files are sparse, this measures the cost of each piece (tasks, file
+    /// openings, reads and hashing) rather than the disk
+    #[tokio::test]
+    #[ignore]
+    async fn small_pieces_throughput() {
+        let size_gib: u64 = std::env::var("BENCH_SIZE_GIB")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(4);
+        let (size, piece_size) = (size_gib << 30, 16 * 1024);
+        let pieces_num = (size / piece_size) as usize;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = MemoryClient::default();
+        let piece_hash = hex::encode(Sha1::digest(vec![0; piece_size as usize]));
+        for (hash, have) in [("src", true), ("dst", false)] {
+            let save_path = dir.path().join(hash);
+            std::fs::create_dir(&save_path).unwrap();
+            std::fs::File::create(save_path.join("a"))
+                .unwrap()
+                .set_len(size)
+                .unwrap();
+            let properties = serde_json::from_value(serde_json::json!({
+                "save_path": save_path,
+                "piece_size": piece_size,
+                "pieces_num": pieces_num,
+                "pieces_have": if have { pieces_num } else { 0 },
+                "total_size": size,
+            }))
+            .unwrap();
+            let content = vec![TorrentContent {
+                index: 0,
+                name: "a".to_owned(),
+                size,
+                progress: if have { 1. } else { 0. },
+                priority: Priority::Normal,
+                is_seed: None,
+                piece_range: vec![],
+                availability: 0.,
+            }];
+            let state = match have {
+                true => PieceState::Downloaded,
+                false => PieceState::NotDownloaded,
+            };
+            client.torrents.insert(
+                hash.to_owned(),
+                MemoryTorrent {
+                    properties,
+                    content,
+                    pieces_states: vec![state; pieces_num],
+                    pieces_hashes: vec![piece_hash.clone(); pieces_num],
+                },
+            );
+        }
+
+        let options = Options {
+            dry_run: true,
+            ..Options::default()
+        };
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let plan = engine.plan("src", "dst").await.unwrap();
+        let start = std::time::Instant::now();
+        let report = engine.execute(plan).await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(report.restored_pieces, pieces_num as u64);
+        println!(
+            "{} GiB, {} pieces of 16 KiB: {:.2?}, {:.0} MiB/s",
+            size_gib,
+            pieces_num,
+            elapsed,
+            (size >> 20) as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    #[tokio::test]
+    async fn plan_and_execute_with_memory_client() {
+        let data: Vec<u8> = (0..40).collect();
+        let (dir, client) = src_dst_fixture(&data, &[0; 40]);
+        let options = Options::default();
+        let engine = MergeEngine::new(&client, &options).unwrap();
+
+        let plan = engine.plan("src", "dst").await.unwrap();
+        assert_eq!(plan.planned_pieces(), 3);
+        assert_eq!(plan.planned_bytes(), 40);
+
+        let report = engine.execute(plan).await.unwrap();
+        assert_eq!(report.restored_pieces, 3);
+        assert_eq!(std::fs::read(dir.path().join("dst/a")).unwrap(), data);
+
+        let json = report.to_json();
+        assert_eq!(json["restored_bytes"], 40);
+        let file = &json["files"][0];
+        assert_eq!(file["restored_pieces"], 3);
+        assert_eq!(file["pieces"][0]["outcome"], "restored");
+    }
+
+    #[tokio::test]
+    async fn paths_are_mapped() {
+        let mapping: PathMapping = "/downloads/=/mnt/tank".parse().unwrap();
+        assert_eq!(mapping.from, "/downloads");
+        assert_eq!(
+            mapping.apply("/downloads/tv/a").as_deref(),
+            Some("/mnt/tank/tv/a")
+        );
+        assert_eq!(mapping.apply("/downloads").as_deref(), Some("/mnt/tank"));
+        assert_eq!(mapping.apply("/downloads2/a"), None);
+        let mapping: PathMapping = "transmission:/=/mnt/nas".parse().unwrap();
+        assert_eq!(mapping.client.as_deref(), Some("transmission"));
+        assert_eq!(mapping.apply("/data/a").as_deref(), Some("/mnt/nas/data/a"));
+        // not a client name
+        let mapping: PathMapping = "C:\\Downloads=/mnt/c".parse().unwrap();
+        assert_eq!(mapping.client, None);
+        #[cfg(unix)]
+        assert_eq!(
+            mapping.apply("C:/Downloads/tv\\a").as_deref(),
+            Some("/mnt/c/tv/a")
+        );
+        assert!("/downloads".parse::<PathMapping>().is_err());
+
+        // qBittorrent in a container sees the directories of the test as /downloads
+        let data: Vec<u8> = (0..40).collect();
+        let (dir, mut client) = src_dst_fixture(&data, &[0; 40]);
+        client.add("src", "/downloads/src".as_ref(), &data, true);
+        client.add("dst", "/downloads/dst".as_ref(), &data, false);
+        let options = Options {
+            path_map: vec![format!("/downloads={}", dir.path().display())
+                .parse()
+                .unwrap()],
+            ..Options::default()
+        };
+        check_path_map(&options).unwrap();
+        let client = MappedPaths::new(Box::new(client), "qbittorrent", &options.path_map);
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let report = engine.merge("src", "dst").await.unwrap();
+        assert_eq!(report.restored_pieces, 3);
+        assert_eq!(std::fs::read(dir.path().join("dst/a")).unwrap(), data);
+
+        // mappings of other clients don't apply
+        let mut client = MemoryClient::default();
+        client.add("src", "/downloads/src".as_ref(), &data, true);
+        let client = MappedPaths::new(
+            Box::new(client),
+            "qbittorrent",
+            &["deluge:/downloads=/mnt".parse().unwrap()],
+        );
+        let properties = client.properties("src").await.unwrap();
+        assert_eq!(properties.save_path.as_deref(), Some("/downloads/src"));
+
+        let options = Options {
+            path_map: vec!["/downloads=/nonexistent/tank".parse().unwrap()],
+            ..Options::default()
+        };
+        assert!(check_path_map(&options).is_err());
+    }
+
+    #[tokio::test]
+    async fn progress_bars_follow_events() {
+        let data: Vec<u8> = (0..40).collect();
+        let (_dir, client) = src_dst_fixture(&data, &[0; 40]);
+        let options = Options::default();
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let plan = engine.plan("src", "dst").await.unwrap();
+
+        let bars = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let mut progress = MergeProgress::new(&bars, &plan);
+        assert_eq!(progress.total.length(), Some(40));
+        progress.record(&MergeEvent::FileStarted {
+            path: "a".to_owned(),
+            missing_pieces: 3,
+        });
+        assert_eq!(progress.files["a"].length(), Some(3));
+        progress.record(&MergeEvent::PieceRestored {
+            path: "a".to_owned(),
+            idx: 0,
+            size: 16,
+        });
+        progress.record(&MergeEvent::PieceSkipped {
+            path: "a".to_owned(),
+            idx: 2,
+            reason: SkipReason::HashMismatch,
+        });
+        assert_eq!(progress.files["a"].position(), 2);
+        // skipped pieces were read too
+        assert_eq!(progress.total.position(), 24);
+        assert_eq!(progress.total.message(), "16 B restored");
+    }
+
+    #[tokio::test]
+    async fn pieces_are_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..40).collect();
+        let mut on_disk = data.clone();
+        on_disk[20] = 0;
+        std::fs::write(dir.path().join("a"), &on_disk[..36]).unwrap();
+        let mut client = MemoryClient::default();
+        client.add("t", dir.path(), &data, true);
+        client.torrents.get_mut("t").unwrap().pieces_states[0] = PieceState::NotDownloaded;
+
+        let torrent = Torrent::new(&client, "t").await.unwrap();
+        let paths = HashMap::from([(
+            "a".to_owned(),
+            dir.path().join("a").to_str().unwrap().to_owned(),
+        )]);
+        let checks = verify_pieces(&LocalStore::default(), &torrent, &paths, None, CHUNK_SIZE);
+        // the last piece is cut short on disk
+        assert_eq!(
+            checks,
+            [PieceCheck::Intact, PieceCheck::Bad, PieceCheck::Bad]
+        );
+        assert_eq!(log_verification(&torrent, &checks), 2);
+
+        assert_eq!(format_pieces([1, 2, 3, 5, 7, 8]), "1-3,5,7-8");
+        assert_eq!(format_pieces([]), "");
+    }
+
+    #[tokio::test]
+    async fn merge_is_undone_from_journal() {
+        let data: Vec<u8> = (0..40).collect();
+        let original: Vec<u8> = (100..140).collect();
+        let (dir, client) = src_dst_fixture(&data, &original);
+        let journal = dir.path().join("journal.jsonl");
+        let journal = journal.to_str().unwrap();
+        let options = Options {
+            journal: Some(journal.to_owned()),
+            ..Options::default()
+        };
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let report = engine.merge("src", "dst").await.unwrap();
+        assert_eq!(report.restored_pieces, 3);
+        assert_eq!(std::fs::read(dir.path().join("dst/a")).unwrap(), data);
+
+        let outcome = undo_journal(&LocalStore::default(), journal).unwrap();
+        assert_eq!(outcome, RunOutcome::Complete);
+        assert_eq!(std::fs::read(dir.path().join("dst/a")).unwrap(), original);
+    }
+
+    #[test]
+    fn journal_never_writes_without_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        let inner = LocalStore {
+            create_missing: true,
+            ..LocalStore::default()
+        };
+        let store = JournalStore::open(Arc::new(inner), &path("journal.jsonl")).unwrap();
+        let block = FileBlock { offset: 0, size: 8 };
+
+        // no original data in files created by this run
+        store.create_file(&path("created"), 0).unwrap();
+        store.write_block(&path("created"), block, &[1; 8]).unwrap();
+        store.write_block(&path("missing"), block, &[1; 8]).unwrap();
+
+        // the end of a file that already existed can't be saved, the other blocks are written
+        std::fs::write(path("short"), [2; 12]).unwrap();
+        let second = FileBlock { offset: 8, size: 8 };
+        let written = store.write_blocks(&[
+            (&path("short"), block, &[3; 8]),
+            (&path("short"), second, &[3; 8]),
+        ]);
+        assert!(written[0].is_ok());
+        assert!(written[1].is_err());
+        assert!(store.write_block(&path("short"), second, &[3; 8]).is_err());
+        assert_eq!(
+            std::fs::read(path("short")).unwrap(),
+            [[3; 8], [2; 8]].concat()[..12]
+        );
+
+        let journal = std::fs::read_to_string(path("journal.jsonl")).unwrap();
+        let data: Vec<_> = journal
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["data"].clone())
+            .collect();
+        assert_eq!(
+            data,
+            [
+                serde_json::Value::Null,
+                serde_json::Value::Null,
+                "AgICAgICAgI=".into()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn processed_pieces_are_skipped_on_resume() {
+        let data: Vec<u8> = (0..40).collect();
+        let mut src_data = data.clone();
+        src_data[20] = 0;
+        let (dir, client) = src_dst_fixture(&data, &[0; 40]);
+        std::fs::write(dir.path().join("src/a"), &src_data).unwrap();
+        let state_file = dir.path().join("state.jsonl");
+        let options = Options {
+            state_file: Some(state_file.to_str().unwrap().to_owned()),
+            ..Options::default()
+        };
+        let report = MergeEngine::new(&client, &options)
+            .unwrap()
+            .merge("src", "dst")
+            .await
+            .unwrap();
+        assert_eq!(report.restored_pieces, 2);
+        // an interrupted write leaves half a line
+        let mut f = OpenOptions::new().append(true).open(&state_file).unwrap();
+        write!(f, "{{\"src_hash\":").unwrap();
+
+        // the client doesn't know yet about the restored pieces
+        let report = MergeEngine::new(&client, &options)
+            .unwrap()
+            .merge("src", "dst")
+            .await
+            .unwrap();
+        assert_eq!(report.restored_pieces, 0);
+        assert_eq!(report.resumed_pieces, 3);
+        assert_eq!(report.still_missing[0].pieces, [1]);
+    }
+
+    /// Local files, where writes to "a" wait for a write to "b": both files must be written at the same time for
+    /// `met` to be set. The wait is bounded, so that writing one file after the other is only slow
+    #[derive(Default)]
+    struct RendezvousStore {
+        inner: LocalStore,
+        b_written: (std::sync::Mutex<bool>, std::sync::Condvar),
+        met: AtomicBool,
+    }
+
+    impl PieceStore for RendezvousStore {
+        fn read_block(&self, path: &str, file_block: FileBlock) -> std::io::Result<Vec<u8>> {
+            self.inner.read_block(path, file_block)
+        }
+
+        fn write_block(
+            &self,
+            path: &str,
+            file_block: FileBlock,
+            data: &[u8],
+        ) -> std::io::Result<()> {
+            let (b_written, written) = &self.b_written;
+            if path.ends_with("b") {
+                *b_written.lock().unwrap() = true;
+                written.notify_all();
+            } else {
+                let (b_written, _) = written
+                    .wait_timeout_while(b_written.lock().unwrap(), Duration::from_secs(5), |b| !*b)
+                    .unwrap();
+                if *b_written {
+                    self.met.store(true, Ordering::SeqCst);
+                }
+            }
+            self.inner.write_block(path, file_block, data)
+        }
+
+        fn check_writable(&self, path: &str) -> std::io::Result<()> {
+            self.inner.check_writable(path)
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_merged_in_parallel() {
+        let data: Vec<u8> = (0..80).collect();
+        let (dir, mut client) = src_dst_fixture(&data, &[0; 32]);
+        std::fs::write(dir.path().join("src/a"), &data[..32]).unwrap();
+        std::fs::write(dir.path().join("src/b"), &data[32..]).unwrap();
+        std::fs::write(dir.path().join("dst/b"), [0; 48]).unwrap();
+        for torrent in client.torrents.values_mut() {
+            let mut b = torrent.content[0].clone();
+            (b.index, b.name, b.size) = (1, "b".to_owned(), 48);
+            torrent.content[0].size = 32;
+            torrent.content.push(b);
+        }
+        let options = Options {
+            jobs: 2,
+            ..Options::default()
+        };
+        let mut engine = MergeEngine::new(&client, &options).unwrap();
+        let store = Arc::new(RendezvousStore::default());
+        engine.store = store.clone();
+        let report = engine.merge("src", "dst").await.unwrap();
+        assert!(store.met.load(Ordering::SeqCst));
+        assert_eq!(report.restored_pieces, 5);
+        assert_eq!(std::fs::read(dir.path().join("dst/a")).unwrap(), data[..32]);
+        assert_eq!(std::fs::read(dir.path().join("dst/b")).unwrap(), data[32..]);
+        // files of different sizes, they can only match one way
+        // pieces are reported with their file, whatever the order they were restored in
+        let json = report.to_json();
+        for (file, pieces) in [("a", [0, 1]), ("b", [2, 3])] {
+            let file = json["files"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|f| f["file"] == file)
+                .unwrap();
+            assert_eq!(file["pieces"][0]["piece"], pieces[0]);
+            assert_eq!(file["pieces"][1]["piece"], pieces[1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupted_merge_stops() {
+        let data: Vec<u8> = (0..40).collect();
+        let (dir, client) = src_dst_fixture(&data, &[0; 40]);
+        let options = Options::default();
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        engine.interrupt();
+        let report = engine.merge("src", "dst").await.unwrap();
+        assert!(report.interrupted);
+        assert_eq!(report.restored_pieces, 0);
+        assert_eq!(std::fs::read(dir.path().join("dst/a")).unwrap(), [0; 40]);
+    }
+
+    #[tokio::test]
+    async fn identical_pieces_are_copied_without_hashing() {
+        let data: Vec<u8> = (0..40).collect();
+        // the donor went bad after being downloaded, which is only found once written
+        let mut src_data = data.clone();
+        src_data[20] = 0;
+        let (dir, client) = src_dst_fixture(&data, &[0; 40]);
+        std::fs::write(dir.path().join("src/a"), &src_data).unwrap();
+        let options = Options {
+            trust_identical_pieces: true,
+            ..Options::default()
+        };
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let plan = engine.plan("src", "dst").await.unwrap();
+        assert!(plan.files[0]
+            .planned_reads
+            .iter()
+            .all(|read| read.identical));
+        assert_eq!(plan.to_json()["files"][0]["pieces"][0]["identical"], true);
+        let report = engine.execute(plan).await.unwrap();
+        assert_eq!(report.restored_pieces, 2);
+        assert_eq!(report.failed_writes, 1);
+        assert_eq!(report.donor_read_errors, 0);
+
+        // nothing is read in dry run
+        std::fs::remove_file(dir.path().join("src/a")).unwrap();
+        let options = Options {
+            trust_identical_pieces: true,
+            dry_run: true,
+            ..Options::default()
+        };
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let report = engine.merge("src", "dst").await.unwrap();
+        assert_eq!(report.donor_read_errors, 0);
+        assert_eq!(report.restored_pieces, 3);
+    }
+
+    #[tokio::test]
+    async fn offline_donor_pieces_are_never_identical() {
+        let data: Vec<u8> = (0..40).collect();
+        let (dir, client) = src_dst_fixture(&data, &[0; 40]);
+        // a .torrent file has the hashes, but says nothing of the data on disk
+        let path = dir.path().join("donor.torrent");
+        let mut torrent_file =
+            b"d4:infod6:lengthi40e4:name1:a12:piece lengthi16e6:pieces60:".to_vec();
+        for piece in data.chunks(16) {
+            torrent_file.extend(Sha1::digest(piece));
+        }
+        torrent_file.extend(b"ee");
+        std::fs::write(&path, &torrent_file).unwrap();
+        let options = Options {
+            trust_identical_pieces: true,
+            src_torrent: Some(path.to_string_lossy().into_owned()),
+            src_data: Some(dir.path().join("src").to_string_lossy().into_owned()),
+            ..Options::default()
+        };
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let plan = engine.plan("src", "dst").await.unwrap();
+        assert_eq!(plan.planned_pieces(), 3);
+        assert!(plan.files[0]
+            .planned_reads
+            .iter()
+            .all(|read| !read.identical));
+    }
+
+    #[tokio::test]
+    async fn saved_plan_identical_pieces_are_checked_again() {
+        let data: Vec<u8> = (0..40).collect();
+        let (_dir, mut client) = src_dst_fixture(&data, &[0; 40]);
+        let options = Options {
+            trust_identical_pieces: true,
+            ..Options::default()
+        };
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let json = engine.plan("src", "dst").await.unwrap().to_json();
+        assert_eq!(json["files"][0]["pieces"][1]["identical"], true);
+
+        // the source lost a piece since the plan was made
+        client.torrents.get_mut("src").unwrap().pieces_states[1] = PieceState::NotDownloaded;
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let plan = engine.load_plan(&json).await.unwrap();
+        let identical: Vec<_> = plan.files[0]
+            .planned_reads
+            .iter()
+            .map(|read| read.identical)
+            .collect();
+        assert_eq!(identical, [true, false, true]);
+    }
+
+    #[tokio::test]
+    async fn pieces_come_from_the_first_donor_that_has_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..48).collect();
+        let mut client = MemoryClient::default();
+        for (hash, have) in [("src1", true), ("src2", true), ("dst", false)] {
+            let save_path = dir.path().join(hash);
+            std::fs::create_dir(&save_path).unwrap();
+            let file_data = if have { data.clone() } else { vec![0; 48] };
+            std::fs::write(save_path.join("a"), file_data).unwrap();
+            client.add(hash, &save_path, &data, have);
+        }
+        // the first donor misses the last piece
+        let src1 = client.torrents.get_mut("src1").unwrap();
+        src1.pieces_states[2] = PieceState::NotDownloaded;
+        src1.properties.pieces_have = Some(2);
+
+        let options = Options::default();
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let report = engine.merge("src1", "dst").await.unwrap();
+        assert_eq!(report.restored_pieces, 2);
+        // the client doesn't know yet, the next donor only gives what is still missing
+        let plan = engine.plan("src2", "dst").await.unwrap();
+        assert_eq!(plan.planned_pieces(), 1);
+        let report = engine.execute(plan).await.unwrap();
+        assert_eq!(report.restored_pieces, 1);
+        assert!(report.still_missing.iter().all(|f| f.pieces.is_empty()));
+        assert_eq!(std::fs::read(dir.path().join("dst/a")).unwrap(), data);
+
+        engine.forget_restored("dst");
+        let plan = engine.plan("src2", "dst").await.unwrap();
+        assert_eq!(plan.planned_pieces(), 3);
+    }
+
+    #[tokio::test]
+    async fn piece_shared_with_a_file_over_the_overwrite_limit() {
+        let data: Vec<u8> = (0..64).collect();
+        let (dir, mut client) = src_dst_fixture(&data, &[0; 24]);
+        std::fs::write(dir.path().join("src/a"), &data[..24]).unwrap();
+        std::fs::write(dir.path().join("src/b"), &data[24..]).unwrap();
+        // piece 1 is the end of a and the start of b, the destination only has piece 3
+        for torrent in client.torrents.values_mut() {
+            let file = torrent.content.remove(0);
+            torrent.content = vec![
+                TorrentContent {
+                    size: 24,
+                    ..file.clone()
+                },
+                TorrentContent {
+                    index: 1,
+                    name: "b".to_owned(),
+                    size: 40,
+                    ..file
+                },
+            ];
+        }
+        client.torrents.get_mut("dst").unwrap().pieces_states[3] = PieceState::Downloaded;
+
+        // all of a would be overwritten, 60% of b
+        let options = Options {
+            max_overwrite_fraction: Some(0.9),
+            ..Options::default()
+        };
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        let plan = engine.plan("src", "dst").await.unwrap();
+        let planned = |name: &str| -> Vec<usize> {
+            plan.files
+                .iter()
+                .filter(|f| f.dst_filename == name)
+                .flat_map(|f| f.planned_reads.iter().map(|read| read.dst_piece_idx))
+                .collect()
+        };
+        assert!(planned("a").is_empty());
+        assert_eq!(planned("b"), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn files_missing_few_pieces_are_skipped() {
+        let data: Vec<u8> = (0..40).collect();
+        let (_dir, client) = src_dst_fixture(&data, &[0; 40]);
+        for (min_missing_pieces, planned) in [(3, 3), (4, 0)] {
+            let options = Options {
+                min_missing_pieces,
+                ..Options::default()
+            };
+            let engine = MergeEngine::new(&client, &options).unwrap();
+            let plan = engine.plan("src", "dst").await.unwrap();
+            let planned_reads: usize = plan.files.iter().map(|f| f.planned_reads.len()).sum();
+            assert_eq!(planned_reads, planned);
+        }
+    }
+
+    #[tokio::test]
+    async fn pieces_are_logged() {
+        let data: Vec<u8> = (0..40).collect();
+        let mut src_data = data.clone();
+        src_data[20] = 0;
+        let (dir, client) = src_dst_fixture(&data, &[0; 40]);
+        std::fs::write(dir.path().join("src/a"), &src_data).unwrap();
+        let piece_log = dir.path().join("pieces.csv");
+        let options = Options {
+            piece_log: Some(piece_log.to_str().unwrap().to_owned()),
+            ..Options::default()
+        };
+        let engine = MergeEngine::new(&client, &options).unwrap();
+        engine.merge("src", "dst").await.unwrap();
+
+        let log = std::fs::read_to_string(&piece_log).unwrap();
+        let src_path = dir.path().join("src/a");
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(
+            lines[0],
+            "dst_hash,piece,src_files,src_ranges,hash,outcome,error"
+        );
+        assert_eq!(
+            lines[1],
+            format!("dst,0,{},0-16,match,restored,", src_path.display())
+        );
+        assert_eq!(
+            lines[2],
+            format!("dst,1,{},16-32,mismatch,hash-mismatch,", src_path.display())
+        );
+        assert_eq!(lines.len(), 4);
+
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[tokio::test]
+    async fn saved_plan_is_applied() {
+        let data: Vec<u8> = (0..40).collect();
+        let (dir, client) = src_dst_fixture(&data, &[0; 40]);
+        let options = Options::default();
+        let engine = MergeEngine::new(&client, &options).unwrap();
+
+        let json = engine.plan("src", "dst").await.unwrap().to_json();
+        assert_eq!(json["dst_hash"], "dst");
+        assert_eq!(json["files"][0]["pieces"][2]["segments"][0]["size"], 8);
+
+        let plan = engine.load_plan(&json).await.unwrap();
+        assert_eq!(plan.planned_pieces(), 3);
+        let report = engine.execute(plan).await.unwrap();
+        assert_eq!(report.restored_pieces, 3);
+        assert_eq!(std::fs::read(dir.path().join("dst/a")).unwrap(), data);
+
+        // a plan for another torrent is refused
+        let mut json = json;
+        json["files"][0]["pieces"][0]["hash"] = hex::encode([1; 20]).into();
+        assert!(engine.load_plan(&json).await.is_err());
+    }
+}
@@ -0,0 +1,424 @@
+//
+// rTorrent client, through XML-RPC over HTTP (e.g. ruTorrent's RPC2) or SCGI
+//
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use async_trait::async_trait;
+use qbit_rs::model::{
+    PieceState, Priority, Torrent as TorrentInfo, TorrentContent, TorrentProperty,
+};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::bencode;
+use crate::client::{bitfield_states, Preferences, TorrentClient};
+use crate::xmlrpc::{self, Value};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Torrents of rTorrent, through its XML-RPC
+///
+/// rTorrent only reports whether pieces are complete. Piece hashes are not in the RPC, they are read from the
+/// .torrent file of its session directory, which must be readable locally
+pub struct RTorrent {
+    http: reqwest::Client,
+    url: reqwest::Url,
+}
+
+impl RTorrent {
+    /// `url` of the RPC: `http[s]://user:password@seedbox/RPC2` through a web server, `scgi://host:port` or
+    /// `scgi:///path/to/socket` to rTorrent directly
+    pub fn new(url: &str) -> Result<Self> {
+        let url = reqwest::Url::parse(url)?;
+        if !matches!(url.scheme(), "http" | "https" | "scgi") {
+            return Err(format!("Unsupported rTorrent URL scheme: {}", url.scheme()).into());
+        }
+        Ok(RTorrent {
+            http: reqwest::Client::new(),
+            url,
+        })
+    }
+
+    async fn call(&self, method: &str, params: &[Value]) -> Result<Value> {
+        let body = xmlrpc::request(method, params);
+        let response = match self.url.scheme() {
+            "scgi" => self.scgi_call(body).await?,
+            _ => {
+                let mut request = self
+                    .http
+                    .post(self.url.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "text/xml")
+                    .body(body);
+                if !self.url.username().is_empty() {
+                    request = request.basic_auth(self.url.username(), self.url.password());
+                }
+                request.send().await?.error_for_status()?.text().await?
+            }
+        };
+
+        Ok(xmlrpc::parse_response(&response)
+            .map_err(|e| format!("rTorrent {} failed: {}", method, e))?)
+    }
+
+    async fn scgi_call(&self, body: String) -> Result<String> {
+        let request = scgi_request(&body);
+        let response = match self.url.host_str() {
+            Some(host) if !host.is_empty() => {
+                let port = self.url.port().ok_or("SCGI URL without port")?;
+                let stream = tokio::net::TcpStream::connect((host, port)).await?;
+                scgi_exchange(stream, &request).await?
+            }
+            #[cfg(unix)]
+            _ => {
+                let stream = tokio::net::UnixStream::connect(self.url.path()).await?;
+                scgi_exchange(stream, &request).await?
+            }
+            #[cfg(not(unix))]
+            _ => return Err("SCGI over a local socket needs a Unix system".into()),
+        };
+
+        // CGI headers, then the XML
+        let response = String::from_utf8(response)?;
+        match response.split_once("\r\n\r\n") {
+            Some((_, xml)) => Ok(xml.to_owned()),
+            None => Err("Invalid SCGI response from rTorrent".into()),
+        }
+    }
+
+    /// Call each of `methods` on the torrent `hash`, in one request
+    async fn fields(&self, hash: &str, methods: &[&str]) -> Result<Vec<Value>> {
+        let calls = methods
+            .iter()
+            .map(|method| {
+                Value::Struct(BTreeMap::from([
+                    ("methodName".to_owned(), (*method).into()),
+                    (
+                        "params".to_owned(),
+                        Value::Array(vec![rtorrent_hash(hash).as_str().into()]),
+                    ),
+                ]))
+            })
+            .collect();
+        let results = self
+            .call("system.multicall", &[Value::Array(calls)])
+            .await?;
+
+        // each result is wrapped in an array, or is a fault
+        results
+            .as_array()
+            .unwrap_or_default()
+            .iter()
+            .map(|result| match result.as_array() {
+                Some([value]) => Ok(value.clone()),
+                _ => Err(format!(
+                    "Torrent {}: {}",
+                    hash,
+                    result
+                        .get("faultString")
+                        .and_then(Value::as_str)
+                        .unwrap_or("invalid answer")
+                )
+                .into()),
+            })
+            .collect()
+    }
+
+    /// The .torrent file of the session directory, piece hashes are not in the RPC
+    async fn read_torrent_file(&self, hash: &str) -> Result<Vec<u8>> {
+        let fields = self.fields(hash, &["d.session_file"]).await?;
+        let path = fields[0].as_str().unwrap_or_default();
+        Ok(std::fs::read(path).map_err(|e| {
+            format!(
+                "{}: rTorrent doesn't report piece hashes, and its session file {:?} can't be read: {}",
+                hash, path, e
+            )
+        })?)
+    }
+
+    /// Call `method` on each torrent of `hashes`
+    async fn each(&self, method: &str, hashes: &[String]) -> Result<()> {
+        for hash in hashes {
+            self.call(method, &[rtorrent_hash(hash).as_str().into()])
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// rTorrent wants upper case hashes
+fn rtorrent_hash(hash: &str) -> String {
+    hash.to_uppercase()
+}
+
+/// SCGI request: netstring of the headers, then the body
+fn scgi_request(body: &str) -> Vec<u8> {
+    let headers = format!(
+        "CONTENT_LENGTH\0{}\0SCGI\x001\0REQUEST_METHOD\0POST\0",
+        body.len()
+    );
+    format!("{}:{},{}", headers.len(), headers, body).into_bytes()
+}
+
+async fn scgi_exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(response)
+}
+
+/// qBittorrent state of a torrent. Only checking states matter to the merge
+fn torrent_state(started: bool, active: bool, hashing: bool, complete: bool) -> &'static str {
+    match (hashing, started && active, complete) {
+        (true, _, true) => "checkingUP",
+        (true, _, false) => "checkingDL",
+        (false, false, true) => "pausedUP",
+        (false, false, false) => "pausedDL",
+        (false, true, true) => "uploading",
+        (false, true, false) => "downloading",
+    }
+}
+
+/// qBittorrent priority of a file, from rTorrent's 0 (off), 1 (normal) and 2 (high)
+fn file_priority(priority: i64) -> Priority {
+    match priority {
+        0 => Priority::DoNotDownload,
+        2 => Priority::High,
+        _ => Priority::Normal,
+    }
+}
+
+#[async_trait]
+impl TorrentClient for RTorrent {
+    async fn version(&self) -> Result<String> {
+        let version = self.call("system.client_version", &[]).await?;
+        Ok(format!("rTorrent {}", version.as_str().unwrap_or_default()))
+    }
+
+    async fn torrents(&self, hashes: Option<&[String]>) -> Result<Vec<TorrentInfo>> {
+        let fields = [
+            "d.hash=",
+            "d.name=",
+            "d.size_bytes=",
+            "d.completed_bytes=",
+            "d.state=",
+            "d.is_active=",
+            "d.hashing=",
+            "d.custom1=",
+        ];
+        let mut params = vec!["".into(), "main".into()];
+        params.extend(fields.iter().map(|&field| field.into()));
+        let torrents = self.call("d.multicall2", &params).await?;
+
+        torrents
+            .as_array()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|torrent| match torrent.as_array() {
+                Some(torrent) if torrent.len() == fields.len() => Some(torrent),
+                _ => None,
+            })
+            .map(|torrent| {
+                (
+                    torrent[0].as_str().unwrap_or_default().to_lowercase(),
+                    torrent,
+                )
+            })
+            .filter(|(hash, _)| hashes.is_none_or(|hashes| hashes.contains(hash)))
+            .map(|(hash, torrent)| {
+                let size = torrent[2].as_i64().unwrap_or_default();
+                let completed = torrent[3].as_i64().unwrap_or_default();
+                Ok(serde_json::from_value(json!({
+                    "hash": hash,
+                    "name": torrent[1].as_str(),
+                    "size": size,
+                    "progress": if size == 0 { 1. } else { completed as f64 / size as f64 },
+                    "state": torrent_state(
+                        torrent[4].as_i64() == Some(1),
+                        torrent[5].as_i64() == Some(1),
+                        torrent[6].as_i64().is_some_and(|hashing| hashing != 0),
+                        completed >= size,
+                    ),
+                    // label of ruTorrent
+                    "category": torrent[7].as_str(),
+                }))?)
+            })
+            .collect()
+    }
+
+    async fn properties(&self, hash: &str) -> Result<TorrentProperty> {
+        let fields = self
+            .fields(
+                hash,
+                &[
+                    "d.directory",
+                    "d.is_multi_file",
+                    "d.chunk_size",
+                    "d.size_chunks",
+                    "d.completed_chunks",
+                    "d.size_bytes",
+                ],
+            )
+            .await?;
+        // the directory of multi file torrents is their root folder, which is in the names of their files
+        let directory = fields[0].as_str().unwrap_or_default();
+        let save_path = match fields[1].as_i64() {
+            Some(1) => Path::new(directory)
+                .parent()
+                .map(|parent| parent.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            _ => directory.to_owned(),
+        };
+
+        Ok(serde_json::from_value(json!({
+            "save_path": save_path,
+            "piece_size": fields[2].as_i64(),
+            "pieces_num": fields[3].as_i64(),
+            "pieces_have": fields[4].as_i64(),
+            "total_size": fields[5].as_i64(),
+        }))?)
+    }
+
+    async fn contents(&self, hash: &str) -> Result<Vec<TorrentContent>> {
+        let fields = self
+            .fields(hash, &["d.directory", "d.is_multi_file"])
+            .await?;
+        let root = match fields[1].as_i64() {
+            Some(1) => Path::new(fields[0].as_str().unwrap_or_default())
+                .file_name()
+                .map(|root| format!("{}/", root.to_string_lossy())),
+            _ => None,
+        };
+
+        let files = self
+            .call(
+                "f.multicall",
+                &[
+                    rtorrent_hash(hash).as_str().into(),
+                    "".into(),
+                    "f.path=".into(),
+                    "f.size_bytes=".into(),
+                    "f.completed_chunks=".into(),
+                    "f.size_chunks=".into(),
+                    "f.priority=".into(),
+                ],
+            )
+            .await?;
+
+        Ok(files
+            .as_array()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(Value::as_array)
+            .enumerate()
+            .map(|(index, file)| {
+                let field = |idx: usize| file.get(idx).and_then(Value::as_i64).unwrap_or_default();
+                let path = file.first().and_then(Value::as_str).unwrap_or_default();
+                TorrentContent {
+                    index: index as u64,
+                    name: format!("{}{}", root.as_deref().unwrap_or_default(), path),
+                    size: field(1) as u64,
+                    progress: match field(3) {
+                        0 => 1.,
+                        chunks => field(2) as f64 / chunks as f64,
+                    },
+                    priority: file_priority(field(4)),
+                    is_seed: None,
+                    piece_range: vec![],
+                    availability: 0.,
+                }
+            })
+            .collect())
+    }
+
+    async fn pieces_states(&self, hash: &str) -> Result<Vec<PieceState>> {
+        let fields = self
+            .fields(hash, &["d.bitfield", "d.size_chunks", "d.completed_chunks"])
+            .await?;
+        let pieces_num = fields[1].as_i64().unwrap_or_default() as usize;
+        let bitfield = hex::decode(fields[0].as_str().unwrap_or_default())?;
+
+        // closed torrents have no bitfield
+        if bitfield.is_empty() {
+            let state = match fields[2].as_i64() == fields[1].as_i64() {
+                true => PieceState::Downloaded,
+                false => PieceState::NotDownloaded,
+            };
+            return Ok(vec![state; pieces_num]);
+        }
+
+        Ok(bitfield_states(&bitfield, pieces_num))
+    }
+
+    async fn pieces_hashes(&self, hash: &str) -> Result<Vec<String>> {
+        let torrent_file = self.read_torrent_file(hash).await?;
+        Ok(bencode::v1_pieces_hashes(&torrent_file)
+            .map_err(|e| format!("{}: invalid .torrent file: {}", hash, e))?)
+    }
+
+    async fn preferences(&self, _hash: &str) -> Result<Preferences> {
+        // rTorrent has no temp path, files are moved by scheduled commands if at all
+        Ok(Preferences {
+            temp_path_enabled: Some(false),
+            ..Default::default()
+        })
+    }
+
+    async fn pause(&self, hashes: &[String]) -> Result<()> {
+        self.each("d.stop", hashes).await
+    }
+
+    async fn resume(&self, hashes: &[String]) -> Result<()> {
+        self.each("d.start", hashes).await
+    }
+
+    async fn recheck(&self, hashes: &[String]) -> Result<()> {
+        self.each("d.check_hash", hashes).await
+    }
+
+    async fn set_file_priority(
+        &self,
+        hash: &str,
+        indexes: &[i64],
+        priority: Priority,
+    ) -> Result<()> {
+        let priority = match priority {
+            Priority::DoNotDownload => 0,
+            Priority::High | Priority::Maximal => 2,
+            _ => 1,
+        };
+        for index in indexes {
+            let target = format!("{}:f{}", rtorrent_hash(hash), index);
+            self.call("f.priority.set", &[target.as_str().into(), priority.into()])
+                .await?;
+        }
+        self.each("d.update_priorities", &[hash.to_owned()]).await
+    }
+
+    async fn torrent_file(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(Some(self.read_torrent_file(hash).await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_priorities_and_scgi() {
+        assert_eq!(torrent_state(true, true, true, false), "checkingDL");
+        assert_eq!(torrent_state(true, false, false, true), "pausedUP");
+        assert_eq!(torrent_state(true, true, false, true), "uploading");
+        assert_eq!(file_priority(0), Priority::DoNotDownload);
+        assert_eq!(file_priority(2), Priority::High);
+
+        assert_eq!(
+            scgi_request("<x/>"),
+            b"44:CONTENT_LENGTH\x004\0SCGI\x001\0REQUEST_METHOD\0POST\0,<x/>"
+        );
+    }
+}
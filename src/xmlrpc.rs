@@ -0,0 +1,320 @@
+//
+// Minimal XML-RPC encoder and decoder, for rTorrent
+//
+
+use std::collections::BTreeMap;
+
+/// XML-RPC value. Booleans are decoded as integers, rTorrent uses neither them nor doubles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    Int(i64),
+    String(String),
+    Array(Vec<Value>),
+    Struct(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// Member of a struct
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(members) => members.get(key),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Self {
+        Value::String(string.to_owned())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(number: i64) -> Self {
+        Value::Int(number)
+    }
+}
+
+/// Body of a call to `method`
+pub(crate) fn request(method: &str, params: &[Value]) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\"?><methodCall><methodName>{}</methodName><params>",
+        escape(method)
+    );
+    for param in params {
+        xml.push_str("<param>");
+        encode(param, &mut xml);
+        xml.push_str("</param>");
+    }
+    xml.push_str("</params></methodCall>");
+    xml
+}
+
+/// Result of a call, or its fault as an error
+pub(crate) fn parse_response(xml: &str) -> Result<Value, String> {
+    let mut parser = Parser { rest: xml };
+    if let Some(prolog) = parser.rest.trim_start().strip_prefix("<?") {
+        let end = prolog.find("?>").ok_or("unterminated XML declaration")?;
+        parser.rest = &prolog[end + 2..];
+    }
+
+    parser.expect("methodResponse")?;
+    match parser.take_tag()? {
+        ("params", false) => {
+            parser.expect("param")?;
+            let value = parser.value()?;
+            parser.expect("/param")?;
+            parser.expect("/params")?;
+            Ok(value)
+        }
+        ("fault", false) => {
+            let fault = parser.value()?;
+            Err(format!(
+                "fault {}: {}",
+                fault
+                    .get("faultCode")
+                    .and_then(Value::as_i64)
+                    .unwrap_or_default(),
+                fault
+                    .get("faultString")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+            ))
+        }
+        (tag, _) => Err(format!("expected <params> or <fault>, found <{}>", tag)),
+    }
+}
+
+fn encode(value: &Value, xml: &mut String) {
+    xml.push_str("<value>");
+    match value {
+        Value::Int(number) => match i32::try_from(*number) {
+            Ok(number) => xml.push_str(&format!("<i4>{}</i4>", number)),
+            Err(_) => xml.push_str(&format!("<i8>{}</i8>", number)),
+        },
+        Value::String(string) => xml.push_str(&format!("<string>{}</string>", escape(string))),
+        Value::Array(values) => {
+            xml.push_str("<array><data>");
+            for value in values {
+                encode(value, xml);
+            }
+            xml.push_str("</data></array>");
+        }
+        Value::Struct(members) => {
+            xml.push_str("<struct>");
+            for (name, value) in members {
+                xml.push_str(&format!("<member><name>{}</name>", escape(name)));
+                encode(value, xml);
+                xml.push_str("</member>");
+            }
+            xml.push_str("</struct>");
+        }
+    }
+    xml.push_str("</value>");
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(text: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or("unterminated entity")? + start;
+        let entity = &rest[start + 1..end];
+        let c = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => entity
+                .strip_prefix("#x")
+                .map(|code| u32::from_str_radix(code, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("unknown entity &{};", entity))?,
+        };
+        unescaped.push(c);
+        rest = &rest[end + 1..];
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    /// Name of the next tag, `/name` for closing tags, and whether it is self-closing
+    fn peek_tag(&self) -> Result<(&'a str, bool), String> {
+        let tag = self
+            .rest
+            .trim_start()
+            .strip_prefix('<')
+            .ok_or("expected a tag")?;
+        let tag = &tag[..tag.find('>').ok_or("unterminated tag")?];
+        Ok(match tag.strip_suffix('/') {
+            Some(tag) => (tag.trim(), true),
+            None => (tag.trim(), false),
+        })
+    }
+
+    fn take_tag(&mut self) -> Result<(&'a str, bool), String> {
+        let tag = self.peek_tag()?;
+        let rest = self.rest.trim_start();
+        self.rest = &rest[rest.find('>').unwrap_or_default() + 1..];
+        Ok(tag)
+    }
+
+    /// Consume the opening or closing tag `name`
+    fn expect(&mut self, name: &str) -> Result<(), String> {
+        match self.take_tag()? {
+            (tag, false) if tag == name => Ok(()),
+            (tag, _) => Err(format!("expected <{}>, found <{}>", name, tag)),
+        }
+    }
+
+    /// Text up to the next tag
+    fn text(&mut self) -> Result<String, String> {
+        let end = self.rest.find('<').ok_or("unexpected end of data")?;
+        let text = unescape(&self.rest[..end])?;
+        self.rest = &self.rest[end..];
+        Ok(text)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.take_tag()? {
+            ("value", true) => return Ok(Value::String(String::new())),
+            ("value", false) => {}
+            (tag, _) => return Err(format!("expected <value>, found <{}>", tag)),
+        }
+
+        // a value without a type is a string
+        let text = self.text()?;
+        if self.peek_tag()? == ("/value", false) {
+            self.expect("/value")?;
+            return Ok(Value::String(text));
+        }
+
+        let value = match self.take_tag()? {
+            ("string", true) => Value::String(String::new()),
+            ("array" | "struct", true) => {
+                return Err("self-closing array or struct".to_owned());
+            }
+            ("i4" | "int" | "i8" | "boolean", true) => {
+                return Err("empty number".to_owned());
+            }
+            ("string", false) => {
+                let string = self.text()?;
+                self.expect("/string")?;
+                Value::String(string)
+            }
+            (kind @ ("i4" | "int" | "i8" | "boolean"), false) => {
+                let number = self
+                    .text()?
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid <{}>", kind))?;
+                self.expect(&format!("/{}", kind))?;
+                Value::Int(number)
+            }
+            ("array", false) => {
+                let mut values = Vec::new();
+                if self.take_tag()? == ("data", false) {
+                    while self.peek_tag()?.0 == "value" {
+                        values.push(self.value()?);
+                    }
+                    self.expect("/data")?;
+                }
+                self.expect("/array")?;
+                Value::Array(values)
+            }
+            ("struct", false) => {
+                let mut members = BTreeMap::new();
+                while self.peek_tag()? == ("member", false) {
+                    self.expect("member")?;
+                    self.expect("name")?;
+                    let name = self.text()?;
+                    self.expect("/name")?;
+                    members.insert(name, self.value()?);
+                    self.expect("/member")?;
+                }
+                self.expect("/struct")?;
+                Value::Struct(members)
+            }
+            (tag, _) => return Err(format!("unsupported value type <{}>", tag)),
+        };
+        self.expect("/value")?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_and_responses() {
+        assert_eq!(
+            request("d.name", &["ABC".into(), Value::Int(1 << 40)]),
+            "<?xml version=\"1.0\"?><methodCall><methodName>d.name</methodName><params>\
+             <param><value><string>ABC</string></value></param>\
+             <param><value><i8>1099511627776</i8></value></param></params></methodCall>"
+        );
+
+        let response = parse_response(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<methodResponse>\n<params>\n<param><value><array><data>\n\
+             <value><string>a &amp; b</string></value>\n<value><i8>42</i8></value>\n<value>raw</value>\n\
+             <value><string/></value>\n</data></array></value></param>\n</params>\n</methodResponse>",
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            Value::Array(vec![
+                "a & b".into(),
+                Value::Int(42),
+                "raw".into(),
+                "".into()
+            ])
+        );
+
+        let fault = parse_response(
+            "<methodResponse><fault><value><struct>\
+             <member><name>faultCode</name><value><i4>-501</i4></value></member>\
+             <member><name>faultString</name><value><string>Could not find info-hash.</string></value></member>\
+             </struct></value></fault></methodResponse>",
+        );
+        assert_eq!(
+            fault,
+            Err("fault -501: Could not find info-hash.".to_owned())
+        );
+    }
+}